@@ -0,0 +1,350 @@
+use std::fmt;
+
+use anyhow::{Context, Result};
+
+use crate::command_parser;
+use crate::conversation::{is_end_command, ConversationAction, Message, User};
+use crate::model_server::{GenerationConfig, InferReq, InferenceBackend};
+use crate::nexos::Command;
+use crate::store::ConversationStore;
+use crate::token;
+
+/// Jake responding, running the commands in the response and responding again to their
+/// results, without anybody clicking through it
+#[derive(clap::Args, Clone, Debug, PartialEq)]
+pub struct AgentConfig {
+    /// How many responses the agent generates before it waits for Zack again
+    #[arg(long, default_value_t = 10)]
+    pub agent_max_steps: usize,
+    /// Ask before each command is run
+    #[arg(long)]
+    pub agent_approve: bool,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            agent_max_steps: 10,
+            agent_approve: false,
+        }
+    }
+}
+
+/// Why the agent stopped
+#[derive(Clone, Debug, PartialEq)]
+pub enum AgentStop {
+    /// The response didn't run anything, so it is for Zack
+    NoCommands,
+    /// Jake used `end`
+    Ended,
+    StepBudget(usize),
+    /// None of the commands were approved
+    Rejected,
+}
+
+impl fmt::Display for AgentStop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AgentStop::NoCommands => write!(f, "Jake answered without running anything"),
+            AgentStop::Ended => write!(f, "Jake ended the conversation"),
+            AgentStop::StepBudget(steps) => write!(f, "used up the {steps} step budget"),
+            AgentStop::Rejected => write!(f, "none of the commands were approved"),
+        }
+    }
+}
+
+/// The commands in one of Jake's responses
+#[derive(Clone, Debug, PartialEq)]
+pub struct Turn {
+    pub commands: Vec<Command>,
+    /// Commands that didn't parse, eval tells Jake about them so they count as something
+    /// to run
+    pub malformed: usize,
+}
+
+impl Turn {
+    pub fn of(msg: &str) -> Self {
+        let (commands, errors) = command_parser::parse_commands(msg);
+        Self {
+            commands: commands.into_iter().map(|c| c.command).collect(),
+            malformed: errors.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty() && self.malformed == 0
+    }
+
+    pub fn ends(&self) -> bool {
+        self.commands
+            .iter()
+            .any(|c| matches!(c, Command::System(command) if is_end_command(command)))
+    }
+
+    /// The turn without the commands at the indices in `skip`
+    pub fn without(&self, skip: &[usize]) -> Self {
+        Self {
+            commands: self
+                .commands
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| !skip.contains(i))
+                .map(|(_, c)| c.clone())
+                .collect(),
+            malformed: self.malformed,
+        }
+    }
+
+    /// Whether anything has to be approved. A malformed command means none of them run,
+    /// eval only tells Jake what was wrong
+    pub fn needs_approval(&self) -> bool {
+        !self.commands.is_empty() && self.malformed == 0
+    }
+}
+
+/// One line about the command, for asking whether to run it
+pub fn describe(command: &Command) -> String {
+    match command {
+        Command::Nexos(command) => format!("nexos: {command}"),
+        Command::System(command) => format!("system: {command}"),
+    }
+}
+
+/// Adds the empty Jake message the next response goes into, with the request that
+/// generates it
+pub fn start_response(
+    conversations: &mut dyn ConversationStore,
+    convo_id: &str,
+) -> Result<(Message, InferReq)> {
+    let conversation = conversations.apply(
+        convo_id,
+        ConversationAction::AddMessage {
+            index: None,
+            user: User::Jake,
+        },
+    )?;
+    let message = conversation
+        .active_messages()
+        .pop()
+        .context("the response wasn't added")?;
+    let config = GenerationConfig::for_prompt()?;
+    let budget = token::prompt_budget(config.max_new_tokens);
+    let prompt = conversation.msg_training_data(&message.id, budget)?;
+    if let Some(t) = prompt.truncation {
+        println!("truncated prompt {t}")
+    }
+    let req = InferReq {
+        prompt: prompt.text,
+        config,
+    };
+    Ok((message, req))
+}
+
+/// Evaluates the response without the commands at the indices in `skip`, the results go
+/// after it. Some when the response ended the conversation
+pub fn run_turn(
+    conversations: &mut dyn ConversationStore,
+    convo_id: &str,
+    message_id: &str,
+    skip: &[usize],
+) -> Result<Option<AgentStop>> {
+    let mut conversation = conversations
+        .get(convo_id)?
+        .context("the conversation is gone")?;
+    let message = conversation
+        .messages
+        .iter()
+        .find(|m| m.id == message_id)
+        .context("the response is gone")?;
+    let ends = Turn::of(&message.msg).without(skip).ends();
+    let evaluation = conversation.evaluate(message_id, skip)?;
+    conversation.add_evaluation(evaluation)?;
+    conversations.record(
+        convo_id,
+        ConversationAction::EvalMessage {
+            id: message_id.to_string(),
+        },
+        conversation,
+    )?;
+    Ok(ends.then_some(AgentStop::Ended))
+}
+
+/// Runs the agent to the end, for the terminal. `approve` is asked about every command
+/// before it runs when the config wants approval, the ones it turns down are skipped
+pub fn run(
+    conversations: &mut dyn ConversationStore,
+    convo_id: &str,
+    backend: &mut dyn InferenceBackend,
+    config: &AgentConfig,
+    mut approve: impl FnMut(&Command) -> bool,
+) -> Result<AgentStop> {
+    for _ in 0..config.agent_max_steps {
+        let (mut message, req) = start_response(conversations, convo_id)?;
+        let resp = backend.infer_and_wait(req)?;
+        message.msg = resp.completion;
+        conversations.apply(
+            convo_id,
+            ConversationAction::MutateMessage {
+                new_message: message.clone(),
+            },
+        )?;
+        println!("jake: {}", message.msg);
+
+        let turn = Turn::of(&message.msg);
+        if turn.is_empty() {
+            return Ok(AgentStop::NoCommands);
+        }
+        let mut skip = Vec::new();
+        if config.agent_approve && turn.needs_approval() {
+            for (i, command) in turn.commands.iter().enumerate() {
+                if !approve(command) {
+                    skip.push(i);
+                }
+            }
+            if skip.len() == turn.commands.len() {
+                return Ok(AgentStop::Rejected);
+            }
+        }
+        let stop = run_turn(conversations, convo_id, &message.id, &skip)?;
+        let conversation = conversations
+            .get(convo_id)?
+            .context("the conversation is gone")?;
+        let results = conversation
+            .active_messages()
+            .into_iter()
+            .skip_while(|m| m.id != message.id)
+            .skip(1);
+        for result in results {
+            println!("{}: {}", result.user, result.msg);
+        }
+        if let Some(stop) = stop {
+            return Ok(stop);
+        }
+    }
+    Ok(AgentStop::StepBudget(config.agent_max_steps))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversation::Conversation;
+    use crate::model_server::MockBackend;
+    use crate::nexos;
+    use crate::sandbox::LocalBackend;
+    use crate::store::MemoryStore;
+    use crate::templates;
+
+    /// A conversation Zack started, with a local session so nothing depends on the
+    /// configured backend
+    fn new_conversation(store: &mut MemoryStore) -> String {
+        let id = store.insert(&mut Conversation::default()).unwrap();
+        nexos::insert_session(&id, Box::new(LocalBackend::new(None).unwrap()));
+        let conversation = store
+            .apply(
+                &id,
+                ConversationAction::AddMessage {
+                    index: None,
+                    user: User::Zack,
+                },
+            )
+            .unwrap();
+        let mut msg = conversation.active_messages().pop().unwrap();
+        msg.msg = "look around".to_string();
+        store
+            .apply(&id, ConversationAction::MutateMessage { new_message: msg })
+            .unwrap();
+        id
+    }
+
+    #[test]
+    fn test_turn() {
+        let turn = Turn::of("let me look [<ls -la>] and then I'm done [(end)]");
+        assert_eq!(turn.commands.len(), 2);
+        assert!(turn.ends());
+        assert!(turn.needs_approval());
+        assert_eq!(describe(&turn.commands[0]), "nexos: ls -la");
+        assert_eq!(describe(&turn.commands[1]), "system: end");
+        assert!(!turn.without(&[1]).ends());
+
+        let turn = Turn::of("[<pwd>] ${task: \"start\", args: [\"--name\", \"end\"]}");
+        assert!(!turn.is_empty());
+        assert!(!turn.ends());
+
+        assert!(Turn::of("Woah brah, what's up").is_empty());
+        // a broken command still has to go back to Jake
+        let turn = Turn::of("[<echo \"hi>]");
+        assert!(!turn.is_empty());
+        assert_eq!(turn.malformed, 1);
+        assert!(!turn.needs_approval());
+    }
+
+    #[test]
+    fn test_run() {
+        templates::use_repo_templates();
+        // nothing here execs, the session is only there for the snapshot after each eval
+        let config = AgentConfig {
+            agent_max_steps: 2,
+            agent_approve: false,
+        };
+        let cases = [
+            (vec!["Woah brah, what's up"], AgentStop::NoCommands),
+            (vec!["[<echo \"hi>]", "all done [(end)]"], AgentStop::Ended),
+            (
+                vec!["[<echo \"hi>]", "[<echo \"hi again>]"],
+                AgentStop::StepBudget(2),
+            ),
+        ];
+        for (responses, expected) in cases {
+            let mut store = MemoryStore::default();
+            let id = new_conversation(&mut store);
+            let steps = responses.len();
+            let mut backend = MockBackend::new(responses.into_iter().map(String::from).collect());
+            let stop = run(&mut store, &id, &mut backend, &config, |_| true).unwrap();
+            nexos::close_session(&id);
+            assert_eq!(stop, expected);
+
+            let messages = store.get(&id).unwrap().unwrap().active_messages();
+            let jake = messages.iter().filter(|m| m.user == User::Jake).count();
+            assert_eq!(jake, steps);
+            // the broken commands and the end got an answer from eval
+            if expected != AgentStop::NoCommands {
+                assert_eq!(messages.last().unwrap().user, User::System);
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_approval() {
+        templates::use_repo_templates();
+        let config = AgentConfig {
+            agent_max_steps: 2,
+            agent_approve: true,
+        };
+        let not_end = |c: &Command| !matches!(c, Command::System(c) if is_end_command(c));
+
+        // the task starts but the end was turned down, so Jake gets another go
+        let mut store = MemoryStore::default();
+        let id = new_conversation(&mut store);
+        let mut backend = MockBackend::new(vec![
+            "${task: \"start\", args: [\"--name\", \"look\"]} [(end)]".to_string(),
+            "nothing to see".to_string(),
+        ]);
+        let stop = run(&mut store, &id, &mut backend, &config, not_end).unwrap();
+        nexos::close_session(&id);
+        assert_eq!(stop, AgentStop::NoCommands);
+        let messages = store.get(&id).unwrap().unwrap().active_messages();
+        let texts: Vec<&str> = messages.iter().map(|m| m.msg.as_str()).collect();
+        assert_eq!(texts[2], "Task \"look\" started");
+        assert_eq!(texts[3], "`end` wasn't run because it wasn't approved");
+
+        // nothing approved, nothing run
+        let mut store = MemoryStore::default();
+        let id = new_conversation(&mut store);
+        let mut backend = MockBackend::new(vec!["[(end)]".to_string()]);
+        let stop = run(&mut store, &id, &mut backend, &config, not_end).unwrap();
+        nexos::close_session(&id);
+        assert_eq!(stop, AgentStop::Rejected);
+        let messages = store.get(&id).unwrap().unwrap().active_messages();
+        assert_eq!(messages.last().unwrap().user, User::Jake);
+    }
+}
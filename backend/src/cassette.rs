@@ -0,0 +1,346 @@
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::nexos::{BuildResult, DockerResult, ExecBackend, NexosLimits};
+
+/// Something that went out of the process, Nexos or the inference server
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "call", rename_all = "snake_case")]
+pub enum Call {
+    Exec {
+        session: String,
+        command: String,
+    },
+    Attach {
+        session: String,
+    },
+    Rebuild {
+        session: String,
+    },
+    RestoreImage {
+        session: String,
+    },
+    Reset {
+        session: String,
+    },
+    ReadFile {
+        session: String,
+        path: String,
+    },
+    WriteFile {
+        session: String,
+        path: String,
+        contents: String,
+    },
+    /// A `runreq` to the inference server
+    Request {
+        route: String,
+        body: Value,
+    },
+}
+
+/// A call and what came back, errors are kept as their message
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Interaction {
+    #[serde(flatten)]
+    pub call: Call,
+    pub outcome: std::result::Result<Value, String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Record,
+    Replay,
+}
+
+/// A jsonl file of interactions. Recording appends every call as it happens, replaying
+/// hands back the first unused recording of the same call so things that poll (like the
+/// server status) still come back in the order they were recorded
+pub struct Cassette {
+    pub mode: Mode,
+    path: String,
+    interactions: Vec<Interaction>,
+    used: Vec<bool>,
+}
+
+impl Cassette {
+    /// Starts an empty cassette at `path`, overwriting what was there
+    pub fn record(path: &str) -> Result<Self> {
+        std::fs::File::create(path).with_context(|| format!("failed to create {path}"))?;
+        Ok(Self {
+            mode: Mode::Record,
+            path: path.to_string(),
+            interactions: Vec::new(),
+            used: Vec::new(),
+        })
+    }
+
+    pub fn replay(path: &str) -> Result<Self> {
+        let file = std::fs::File::open(path).with_context(|| format!("failed to open {path}"))?;
+        let mut interactions = Vec::new();
+        for (i, line) in std::io::BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            interactions.push(
+                serde_json::from_str(&line)
+                    .with_context(|| format!("bad interaction on line {} of {path}", i + 1))?,
+            );
+        }
+        Ok(Self {
+            mode: Mode::Replay,
+            path: path.to_string(),
+            used: vec![false; interactions.len()],
+            interactions,
+        })
+    }
+
+    pub fn save(&mut self, interaction: Interaction) -> Result<()> {
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("failed to open {}", self.path))?;
+        writeln!(file, "{}", serde_json::to_string(&interaction)?)?;
+        self.interactions.push(interaction);
+        self.used.push(true);
+        Ok(())
+    }
+
+    pub fn play(&mut self, call: &Call) -> Result<Value> {
+        let index = self
+            .interactions
+            .iter()
+            .zip(&self.used)
+            .position(|(i, used)| !used && i.call == *call)
+            .ok_or_else(|| anyhow!("{} has nothing (left) recorded for {call:?}", self.path))?;
+        self.used[index] = true;
+        self.interactions[index]
+            .outcome
+            .clone()
+            .map_err(|e| anyhow!("{e}"))
+    }
+
+    /// Replays `call` or runs `f` and records what it returned, depending on the mode
+    pub fn through<T: Serialize + DeserializeOwned>(
+        cassette: &Mutex<Self>,
+        call: Call,
+        f: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        let mode = cassette.lock().unwrap().mode;
+        match mode {
+            Mode::Replay => Ok(serde_json::from_value(
+                cassette.lock().unwrap().play(&call)?,
+            )?),
+            Mode::Record => {
+                let result = f();
+                let outcome = match result {
+                    Ok(ref value) => Ok(serde_json::to_value(value)?),
+                    Err(ref e) => Err(format!("{e:#}")),
+                };
+                cassette
+                    .lock()
+                    .unwrap()
+                    .save(Interaction { call, outcome })?;
+                result
+            }
+        }
+    }
+}
+
+static CASSETTE: Mutex<Option<Arc<Mutex<Cassette>>>> = Mutex::new(None);
+
+/// Use `cassette` for every Nexos session opened from now on and every inference request
+pub fn install(cassette: Cassette) {
+    *CASSETTE.lock().unwrap() = Some(Arc::new(Mutex::new(cassette)));
+}
+
+pub fn current() -> Option<Arc<Mutex<Cassette>>> {
+    CASSETTE.lock().unwrap().clone()
+}
+
+/// Records and replays the calls made to the backend it wraps. There is no backend
+/// when replaying, so nothing is needed from the machine the cassette came from
+pub struct CassetteBackend {
+    session: String,
+    cassette: Arc<Mutex<Cassette>>,
+    inner: Option<Box<dyn ExecBackend>>,
+}
+
+impl CassetteBackend {
+    pub fn new(
+        session: &str,
+        cassette: Arc<Mutex<Cassette>>,
+        inner: Option<Box<dyn ExecBackend>>,
+    ) -> Self {
+        Self {
+            session: session.to_string(),
+            cassette,
+            inner,
+        }
+    }
+
+    fn inner(&mut self) -> Result<&mut Box<dyn ExecBackend>> {
+        self.inner.as_mut().ok_or(anyhow!(
+            "replaying a cassette, there is no backend to run on"
+        ))
+    }
+}
+
+impl ExecBackend for CassetteBackend {
+    fn exec(&mut self, command: &str, limits: &NexosLimits) -> Result<DockerResult> {
+        let call = Call::Exec {
+            session: self.session.clone(),
+            command: command.to_string(),
+        };
+        let cassette = self.cassette.clone();
+        Cassette::through(&cassette, call, || self.inner()?.exec(command, limits))
+    }
+
+    fn attach(&mut self, limits: &NexosLimits) -> Result<String> {
+        let call = Call::Attach {
+            session: self.session.clone(),
+        };
+        let cassette = self.cassette.clone();
+        Cassette::through(&cassette, call, || self.inner()?.attach(limits))
+    }
+
+    fn rebuild(&mut self) -> Result<BuildResult> {
+        let call = Call::Rebuild {
+            session: self.session.clone(),
+        };
+        let cassette = self.cassette.clone();
+        Cassette::through(&cassette, call, || self.inner()?.rebuild())
+    }
+
+    fn restore_image(&mut self) -> Result<String> {
+        let call = Call::RestoreImage {
+            session: self.session.clone(),
+        };
+        let cassette = self.cassette.clone();
+        Cassette::through(&cassette, call, || self.inner()?.restore_image())
+    }
+
+    /// Only used for reporting, so a replay doesn't need the backend for it
+    fn image(&self) -> &str {
+        match self.inner {
+            Some(ref inner) => inner.image(),
+            None => "the recorded image",
+        }
+    }
+
+    fn home_dir(&self) -> Option<PathBuf> {
+        self.inner.as_ref().and_then(|inner| inner.home_dir())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        let call = Call::Reset {
+            session: self.session.clone(),
+        };
+        let cassette = self.cassette.clone();
+        Cassette::through(&cassette, call, || self.inner()?.reset())
+    }
+
+    fn read_file(&mut self, path: &str, max_bytes: usize) -> Result<Vec<u8>> {
+        let call = Call::ReadFile {
+            session: self.session.clone(),
+            path: path.to_string(),
+        };
+        let cassette = self.cassette.clone();
+        Cassette::through(&cassette, call, || self.inner()?.read_file(path, max_bytes))
+    }
+
+    fn write_file(&mut self, path: &str, contents: &[u8]) -> Result<()> {
+        let call = Call::WriteFile {
+            session: self.session.clone(),
+            path: path.to_string(),
+            contents: String::from_utf8_lossy(contents).to_string(),
+        };
+        let cassette = self.cassette.clone();
+        Cassette::through(&cassette, call, || self.inner()?.write_file(path, contents))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nexos::LogLine;
+
+    /// Says back what it was asked to run
+    struct Echo;
+    impl ExecBackend for Echo {
+        fn exec(&mut self, command: &str, _: &NexosLimits) -> Result<DockerResult> {
+            Ok(DockerResult {
+                output: vec![LogLine::StdOut {
+                    message: command.to_string(),
+                }],
+                ..Default::default()
+            })
+        }
+        fn attach(&mut self, _: &NexosLimits) -> Result<String> {
+            Ok("attached".into())
+        }
+        fn rebuild(&mut self) -> Result<BuildResult> {
+            anyhow::bail!("no dockerfile")
+        }
+        fn restore_image(&mut self) -> Result<String> {
+            anyhow::bail!("no image")
+        }
+        fn image(&self) -> &str {
+            "echo"
+        }
+        fn home_dir(&self) -> Option<PathBuf> {
+            None
+        }
+        fn reset(&mut self) -> Result<()> {
+            Ok(())
+        }
+        fn read_file(&mut self, path: &str, _: usize) -> Result<Vec<u8>> {
+            Ok(path.as_bytes().to_vec())
+        }
+        fn write_file(&mut self, _: &str, _: &[u8]) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn stdout(result: &DockerResult) -> &str {
+        match &result.output[..] {
+            [LogLine::StdOut { message }] => message,
+            _ => panic!("unexpected output {result:?}"),
+        }
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("episode.jsonl");
+        let path = path.to_str().unwrap();
+        let limits = NexosLimits::default();
+
+        let cassette = Arc::new(Mutex::new(Cassette::record(path).unwrap()));
+        let mut backend = CassetteBackend::new("c", cassette, Some(Box::new(Echo)));
+        backend.exec("ls", &limits).unwrap();
+        backend.exec("pwd", &limits).unwrap();
+        backend.exec("ls", &limits).unwrap();
+        assert!(backend.rebuild().is_err());
+
+        let cassette = Arc::new(Mutex::new(Cassette::replay(path).unwrap()));
+        let mut backend = CassetteBackend::new("c", cassette, None);
+        // the same call comes back in order, different calls don't have to be
+        assert_eq!(stdout(&backend.exec("pwd", &limits).unwrap()), "pwd");
+        assert_eq!(stdout(&backend.exec("ls", &limits).unwrap()), "ls");
+        assert_eq!(stdout(&backend.exec("ls", &limits).unwrap()), "ls");
+        assert!(backend.exec("ls", &limits).is_err());
+        assert_eq!(backend.rebuild().unwrap_err().to_string(), "no dockerfile");
+        // nothing was recorded for another conversation
+        let cassette = Arc::new(Mutex::new(Cassette::replay(path).unwrap()));
+        let mut other = CassetteBackend::new("d", cassette, None);
+        assert!(other.exec("pwd", &limits).is_err());
+    }
+}
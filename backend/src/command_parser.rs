@@ -0,0 +1,586 @@
+use std::ops::Range;
+
+use crate::nexos::Command;
+
+// The command syntax jake can use inside a message:
+//
+//   [<ls -la>]                      run in nexos. Quotes, `\>]` and heredocs can contain `>]`
+//   [(task start --name "x")]       system command
+//   ${sh: "pwd"}                    same as [<pwd>]
+//   ${task: "start", args: ["--name", "x"]}
+//   ${sh pwd}                       everything up to the `}` is the command
+//
+// `${...}` is only a command when the name is one of FORM_NAMES so shell variables
+// in prose stay prose.
+
+/// `docker` is what templates/command_format.txt calls nexos
+const FORM_NAMES: [&str; 9] = [
+    "sh", "nexos", "docker", "file", "memory", "task", "abort", "end", "help",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedCommand {
+    pub command: Command,
+    /// Byte range of the whole command in the message
+    pub span: Range<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    /// Byte range in the message that the error is about
+    pub span: Range<usize>,
+    /// 1 based
+    pub line: usize,
+    /// 1 based, in chars
+    pub column: usize,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+impl ParseError {
+    /// The error with the offending line and a caret under where it starts
+    pub fn report(&self, src: &str) -> String {
+        let line = src.lines().nth(self.line - 1).unwrap_or("");
+        format!(
+            "{self}\n\t{line}\n\t{}^",
+            " ".repeat(self.column.saturating_sub(1))
+        )
+    }
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    chars: Vec<(usize, char)>,
+    pos: usize,
+}
+
+type ParseResult<T> = Result<T, ParseError>;
+
+impl<'a> Parser<'a> {
+    fn new(src: &'a str) -> Self {
+        Self {
+            src,
+            chars: src.char_indices().collect(),
+            pos: 0,
+        }
+    }
+    fn offset(&self, pos: usize) -> usize {
+        self.chars
+            .get(pos)
+            .map(|(i, _)| *i)
+            .unwrap_or(self.src.len())
+    }
+    fn peek(&self) -> Option<char> {
+        self.peek_at(self.pos)
+    }
+    fn peek_at(&self, pos: usize) -> Option<char> {
+        self.chars.get(pos).map(|(_, c)| *c)
+    }
+    fn starts_with_at(&self, pos: usize, pattern: &str) -> bool {
+        self.src[self.offset(pos)..].starts_with(pattern)
+    }
+    fn starts_with(&self, pattern: &str) -> bool {
+        self.starts_with_at(self.pos, pattern)
+    }
+    fn error(&self, message: impl Into<String>, start: usize, end: usize) -> ParseError {
+        let (start, end) = (self.offset(start), self.offset(end));
+        let before = &self.src[..start];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        ParseError {
+            message: message.into(),
+            span: start..end,
+            line,
+            column: self.src[line_start..start].chars().count() + 1,
+        }
+    }
+    fn skip_whitespace(&mut self) {
+        while self.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+            self.pos += 1;
+        }
+    }
+    fn ident(&mut self) -> String {
+        let mut ident = String::new();
+        while let Some(c) = self.peek() {
+            if !(c.is_alphanumeric() || c == '_' || c == '-') {
+                break;
+            }
+            ident.push(c);
+            self.pos += 1;
+        }
+        ident
+    }
+
+    fn parse(&mut self) -> (Vec<ParsedCommand>, Vec<ParseError>) {
+        let mut commands = Vec::new();
+        let mut errors = Vec::new();
+        while self.pos < self.chars.len() {
+            let start = self.pos;
+            let result = if self.starts_with("[<") {
+                self.block(">]", true)
+                    .map(|body| Some(Command::Nexos(body)))
+            } else if self.starts_with("[(") {
+                self.block(")]", false)
+                    .map(|body| Some(Command::System(body)))
+            } else if self.starts_with("${") {
+                self.form()
+            } else {
+                self.pos += 1;
+                continue;
+            };
+            match result {
+                Ok(Some(command)) => commands.push(ParsedCommand {
+                    command,
+                    span: self.offset(start)..self.offset(self.pos),
+                }),
+                Ok(None) => self.pos = start + 1,
+                Err(e) => {
+                    errors.push(e);
+                    // carry on after the opener so later commands still get checked
+                    self.pos = start + 2;
+                }
+            }
+        }
+        (commands, errors)
+    }
+
+    /// The body of a `[<...>]` or `[(...)]` block, with `\>]` (or `\)]`) unescaped
+    fn block(&mut self, close: &str, allow_heredocs: bool) -> ParseResult<String> {
+        let start = self.pos;
+        let opener = if close == ">]" { "[<" } else { "[(" };
+        self.pos += 2;
+        let mut body = String::new();
+        let mut quote: Option<(char, usize)> = None;
+        let mut heredocs: Vec<(String, bool)> = Vec::new();
+        loop {
+            let Some(c) = self.peek() else {
+                return Err(match quote {
+                    Some((q, at)) => self.error(
+                        format!("unterminated {q} quote in {opener} block"),
+                        at,
+                        self.pos,
+                    ),
+                    None => self.error(
+                        format!("unterminated {opener} block, expected {close}"),
+                        start,
+                        self.pos,
+                    ),
+                });
+            };
+            match quote {
+                Some(('\'', _)) => {
+                    if c == '\'' {
+                        quote = None;
+                    }
+                    body.push(c);
+                    self.pos += 1;
+                }
+                Some((q, _)) => {
+                    if c == '\\' && self.peek_at(self.pos + 1).is_some() {
+                        body.push(c);
+                        body.push(self.peek_at(self.pos + 1).unwrap());
+                        self.pos += 2;
+                        continue;
+                    }
+                    if c == q {
+                        quote = None;
+                    }
+                    body.push(c);
+                    self.pos += 1;
+                }
+                None => {
+                    if self.starts_with(close) {
+                        self.pos += 2;
+                        return Ok(body);
+                    }
+                    if c == '\\' && self.starts_with_at(self.pos + 1, close) {
+                        body.push_str(close);
+                        self.pos += 3;
+                    } else if c == '\\' && self.peek_at(self.pos + 1).is_some() {
+                        // everything else is left for the shell to deal with
+                        body.push(c);
+                        body.push(self.peek_at(self.pos + 1).unwrap());
+                        self.pos += 2;
+                    } else if c == '\'' || c == '"' {
+                        quote = Some((c, self.pos));
+                        body.push(c);
+                        self.pos += 1;
+                    } else if allow_heredocs && self.starts_with("<<") {
+                        let (marker, heredoc) = self.heredoc_marker();
+                        body.push_str(&marker);
+                        heredocs.extend(heredoc);
+                    } else if c == '\n' && !heredocs.is_empty() {
+                        body.push(c);
+                        self.pos += 1;
+                        for (word, strip_tabs) in std::mem::take(&mut heredocs) {
+                            body.push_str(&self.heredoc_body(&word, strip_tabs)?);
+                        }
+                    } else {
+                        body.push(c);
+                        self.pos += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads `<<WORD`, `<<-WORD`, `<<'WORD'` or `<<"WORD"`. Returns the text it read and
+    /// the heredoc it starts, if it was one and not just `<<`
+    fn heredoc_marker(&mut self) -> (String, Option<(String, bool)>) {
+        let start = self.pos;
+        self.pos += 2;
+        let strip_tabs = self.peek() == Some('-');
+        if strip_tabs {
+            self.pos += 1;
+        }
+        while self.peek() == Some(' ') {
+            self.pos += 1;
+        }
+        let quote = match self.peek() {
+            Some(q @ ('\'' | '"')) => {
+                self.pos += 1;
+                Some(q)
+            }
+            _ => None,
+        };
+        let word = self.ident();
+        if let Some(q) = quote {
+            if self.peek() == Some(q) {
+                self.pos += 1;
+            }
+        }
+        let marker = self.src[self.offset(start)..self.offset(self.pos)].to_string();
+        if word.is_empty() {
+            return (marker, None);
+        }
+        (marker, Some((word, strip_tabs)))
+    }
+
+    /// Every line up to and including the terminator, without the newline after it
+    fn heredoc_body(&mut self, word: &str, strip_tabs: bool) -> ParseResult<String> {
+        let start = self.pos;
+        let mut body = String::new();
+        loop {
+            if self.pos >= self.chars.len() {
+                return Err(self.error(
+                    format!("unterminated heredoc, expected a line with just {word}"),
+                    start,
+                    self.pos,
+                ));
+            }
+            let line_start = self.offset(self.pos);
+            let rest = &self.src[line_start..];
+            let line = rest.split('\n').next().unwrap_or("");
+            body.push_str(line);
+            self.pos += line.chars().count();
+            let candidate = if strip_tabs {
+                line.trim_start_matches('\t')
+            } else {
+                line
+            };
+            if candidate == word {
+                return Ok(body);
+            }
+            if self.peek() == Some('\n') {
+                body.push('\n');
+                self.pos += 1;
+            }
+        }
+    }
+
+    /// A `"..."` string with json style escapes
+    fn string(&mut self) -> ParseResult<String> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut result = String::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.error("unterminated string", start, self.pos)),
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(result);
+                }
+                Some('\\') => {
+                    let escaped = match self.peek_at(self.pos + 1) {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some(c @ ('"' | '\\')) => c,
+                        _ => {
+                            return Err(self.error(
+                                "unknown escape, only \\\" \\\\ \\n and \\t are allowed",
+                                self.pos,
+                                self.pos + 2,
+                            ))
+                        }
+                    };
+                    result.push(escaped);
+                    self.pos += 2;
+                }
+                Some(c) => {
+                    result.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    /// A string or a list of strings
+    fn value(&mut self) -> ParseResult<Vec<String>> {
+        match self.peek() {
+            Some('"') => Ok(vec![self.string()?]),
+            Some('[') => {
+                let start = self.pos;
+                self.pos += 1;
+                let mut values = Vec::new();
+                loop {
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(']') => {
+                            self.pos += 1;
+                            return Ok(values);
+                        }
+                        Some('"') => values.push(self.string()?),
+                        None => return Err(self.error("unterminated list", start, self.pos)),
+                        Some(_) => {
+                            return Err(self.error(
+                                "expected a string in the list",
+                                self.pos,
+                                self.pos + 1,
+                            ))
+                        }
+                    }
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(',') => self.pos += 1,
+                        Some(']') => {}
+                        _ => {
+                            return Err(self.error(
+                                "expected , or ] in the list",
+                                self.pos,
+                                self.pos + 1,
+                            ))
+                        }
+                    }
+                }
+            }
+            _ => Err(self.error("expected a \"string\" or a [list]", self.pos, self.pos + 1)),
+        }
+    }
+
+    /// `${name}`, `${name: "arg", args: [...]}` or `${name raw text}`.
+    /// Ok(None) when the name isn't a command at all
+    fn form(&mut self) -> ParseResult<Option<Command>> {
+        let start = self.pos;
+        self.pos += 2;
+        self.skip_whitespace();
+        let name = self.ident();
+        if !FORM_NAMES.contains(&name.as_str()) {
+            return Ok(None);
+        }
+        self.skip_whitespace();
+        let mut args: Vec<String> = Vec::new();
+        let mut raw: Option<String> = None;
+        match self.peek() {
+            Some('}') => self.pos += 1,
+            Some(':') => {
+                self.pos += 1;
+                self.skip_whitespace();
+                args.extend(self.value()?);
+                loop {
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some('}') => {
+                            self.pos += 1;
+                            break;
+                        }
+                        Some(',') => self.pos += 1,
+                        None => {
+                            return Err(self.error(
+                                format!("unterminated ${{{name}: ...}}, expected }}"),
+                                start,
+                                self.pos,
+                            ))
+                        }
+                        Some(_) => {
+                            return Err(self.error("expected , or }", self.pos, self.pos + 1))
+                        }
+                    }
+                    self.skip_whitespace();
+                    let key_start = self.pos;
+                    let key = self.ident();
+                    if key != "args" {
+                        return Err(self.error(
+                            format!("unknown key \"{key}\", only args is allowed"),
+                            key_start,
+                            self.pos.max(key_start + 1),
+                        ));
+                    }
+                    self.skip_whitespace();
+                    if self.peek() != Some(':') {
+                        return Err(self.error("expected : after args", self.pos, self.pos + 1));
+                    }
+                    self.pos += 1;
+                    self.skip_whitespace();
+                    args.extend(self.value()?);
+                }
+            }
+            _ => {
+                let mut text = String::new();
+                let mut quote: Option<char> = None;
+                loop {
+                    let Some(c) = self.peek() else {
+                        return Err(self.error(
+                            format!("unterminated ${{{name} ...}}, expected }}"),
+                            start,
+                            self.pos,
+                        ));
+                    };
+                    self.pos += 1;
+                    match quote {
+                        Some(q) if c == q => quote = None,
+                        Some(_) => {}
+                        None if c == '\'' || c == '"' => quote = Some(c),
+                        None if c == '}' => break,
+                        None => {}
+                    }
+                    text.push(c);
+                }
+                raw = Some(text.trim().to_string());
+            }
+        }
+
+        let mut line = match raw {
+            Some(raw) => raw,
+            None => args
+                .iter()
+                .enumerate()
+                .map(|(i, arg)| {
+                    // the first value of sh is a whole shell line so it's left alone
+                    if i == 0 && name == "sh" {
+                        arg.clone()
+                    } else {
+                        shell_quote(arg)
+                    }
+                })
+                .collect::<Vec<String>>()
+                .join(" "),
+        };
+        Ok(Some(match name.as_str() {
+            "sh" => Command::Nexos(line),
+            _ => {
+                let name = if name == "docker" { "nexos" } else { &name };
+                if !line.is_empty() {
+                    line.insert(0, ' ');
+                }
+                Command::System(format!("{name}{line}"))
+            }
+        }))
+    }
+}
+
+fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        return arg.to_string();
+    }
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Every command in the message and everything that looked like a command but was malformed
+pub fn parse_commands(src: &str) -> (Vec<ParsedCommand>, Vec<ParseError>) {
+    Parser::new(src).parse()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commands(src: &str) -> Vec<Command> {
+        let (commands, errors) = parse_commands(src);
+        assert!(errors.is_empty(), "{errors:?}");
+        commands.into_iter().map(|c| c.command).collect()
+    }
+
+    #[test]
+    fn test_blocks() {
+        assert!(commands("").is_empty());
+        assert!(commands("[").is_empty());
+        assert_eq!(
+            commands("a [<ls>] b [(task start --name x)]"),
+            vec![
+                Command::Nexos("ls".into()),
+                Command::System("task start --name x".into())
+            ]
+        );
+        assert_eq!(
+            commands(r#"[<echo ">]" '>]' a\>]b>]"#),
+            vec![Command::Nexos(r#"echo ">]" '>]' a>]b"#.into())]
+        );
+    }
+
+    #[test]
+    fn test_heredoc() {
+        let src = "[<cat <<EOF > out.txt\nhello >]\nEOF\n>] after";
+        assert_eq!(
+            commands(src),
+            vec![Command::Nexos(
+                "cat <<EOF > out.txt\nhello >]\nEOF\n".into()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_forms() {
+        assert_eq!(
+            commands(r#"${sh: "cd ..; echo `pwd`"} ${sh pwd} ${HOME}"#),
+            vec![
+                Command::Nexos("cd ..; echo `pwd`".into()),
+                Command::Nexos("pwd".into()),
+            ]
+        );
+        assert_eq!(
+            commands(r#"${task: "start", args: ["--name", "it's done"]} ${abort} ${end}"#),
+            vec![
+                Command::System(r#"task start --name 'it'\''s done'"#.into()),
+                Command::System("abort".into()),
+                Command::System("end".into()),
+            ]
+        );
+        assert_eq!(
+            commands(r#"${docker: "rebuild"}"#),
+            vec![Command::System("nexos rebuild".into())]
+        );
+        assert_eq!(
+            commands(r#"${file: "put", args: ["notes.txt", "line one\nline two"]}"#),
+            vec![Command::System(
+                "file put notes.txt 'line one\nline two'".into()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let (commands, errors) = parse_commands("ok [<ls>]\n  [<echo \"hi>] [(abort)]");
+        // parsing picks up again after a bad block so everything wrong gets reported at once
+        assert_eq!(commands.len(), 2);
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].line, errors[0].column), (2, 10));
+        assert!(errors[0].message.contains("quote"));
+
+        let (_, errors) = parse_commands("[(abort");
+        assert_eq!(errors[0].span, 0..7);
+        assert!(errors[0].message.contains("expected )]"));
+
+        let (_, errors) = parse_commands(r#"${task: "start", name: "x"}"#);
+        assert!(errors[0].message.contains("unknown key"));
+    }
+}
@@ -1,22 +1,28 @@
 use std::collections::HashMap;
 
 use clap::{Parser, Subcommand};
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 
 use anyhow::{anyhow, bail};
 
 use anyhow::{Context, Result};
 use jammdb::{Error as JammError, DB};
-use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use strum_macros::Display;
 use uuid::Uuid;
 
-use crate::nexos::{extract_commands, Command, LogLine, NexosInstance};
+use crate::command_parser;
+use crate::files;
+use crate::history::{self, HistoryEntry};
+use crate::migrations;
+use crate::nexos::{self, Command, CommandResult, NexosLimits};
+use crate::snapshot;
+use crate::store::{ConversationStore, RevisionUpdate};
 use crate::templates::{
     self, InjectedFileTemplateData, MessagePromptTemplateEntry, MetadataPromptTemplateEntry,
     PromptTemplateData,
 };
+use crate::token::{self, Truncation};
 pub enum Programs {}
 
 pub trait Program {
@@ -31,14 +37,26 @@ pub enum User {
     System,
     TaskReport { creator: Box<User> },
 }
-impl ToString for User {
-    fn to_string(&self) -> String {
+impl User {
+    /// Lowercase name used to filter by user in searches and exports
+    pub fn key(&self) -> &'static str {
         match self {
-            Self::Jake => String::from("Me"),
-            Self::Zack => String::from("Zack"),
-            Self::Docker => String::from("Docker"),
-            Self::System => String::from("System"),
-            Self::TaskReport { creator } => format!("{} (from subtask)", creator.to_string()),
+            Self::Jake => "jake",
+            Self::Zack => "zack",
+            Self::Docker => "docker",
+            Self::System => "system",
+            Self::TaskReport { .. } => "taskreport",
+        }
+    }
+}
+impl std::fmt::Display for User {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Jake => write!(f, "Me"),
+            Self::Zack => write!(f, "Zack"),
+            Self::Docker => write!(f, "Docker"),
+            Self::System => write!(f, "System"),
+            Self::TaskReport { creator } => write!(f, "{creator} (from subtask)"),
         }
     }
 }
@@ -47,6 +65,40 @@ pub struct Metadata {
     pub task_actions: Vec<TaskAction>,
     pub omit_history_until: Option<String>,
     pub exclude_from_training: bool,
+    #[serde(default)]
+    pub feedback: Option<Feedback>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, serde::Serialize, serde::Deserialize)]
+pub enum Rating {
+    Like,
+    Dislike,
+}
+
+/// Zack's opinion of a response and why he has it
+#[derive(Clone, PartialEq, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Feedback {
+    pub rating: Rating,
+    pub reason: String,
+    pub time: SystemTime,
+}
+
+impl Feedback {
+    pub fn new(rating: Rating) -> Self {
+        Self {
+            rating,
+            reason: String::new(),
+            time: SystemTime::now(),
+        }
+    }
+}
+
+/// A DPO style preference between two alternatives for the same prompt
+#[derive(Clone, PartialEq, Debug, serde::Serialize, serde::Deserialize)]
+pub struct PreferencePair {
+    pub prompt: String,
+    pub chosen: String,
+    pub rejected: String,
 }
 
 #[derive(Clone, PartialEq, Debug, serde::Serialize, serde::Deserialize)]
@@ -63,6 +115,16 @@ pub struct Message {
     pub user: User,
     pub msg: String,
     pub id: String,
+    /// The message this one follows. None for the first message of a conversation
+    #[serde(default)]
+    pub parent: Option<String>,
+    /// Set on the Docker messages eval makes for Nexos commands, `msg` is this rendered
+    /// through tool_result.template
+    #[serde(default)]
+    pub result: Option<CommandResult>,
+    /// Snapshot of Jake's home directory after this message was evaluated
+    #[serde(default)]
+    pub snapshot: Option<String>,
 }
 
 impl Message {
@@ -73,9 +135,25 @@ impl Message {
             user,
             meta: Metadata::default(),
             msg: String::new(),
+            parent: None,
+            result: None,
+            snapshot: None,
         }
     }
 
+    pub fn new_with_result(result: CommandResult) -> anyhow::Result<Self> {
+        let mut new = Self::new(User::Docker);
+        new.msg = templates::tool_result(&result.to_template_data())?
+            .trim_end()
+            .to_string();
+        new.result = Some(result);
+        Ok(new)
+    }
+
+    pub fn rating(&self) -> Option<Rating> {
+        self.meta.feedback.as_ref().map(|f| f.rating)
+    }
+
     pub fn new_with_msg(user: User, msg: String) -> Self {
         let mut new = Self::new(user);
         new.msg = msg;
@@ -88,6 +166,7 @@ impl Message {
         Ok(MessagePromptTemplateEntry {
             author: self.user.to_string(),
             value: message,
+            id: Some(self.id.clone()),
         })
     }
     pub fn to_meta_entries(
@@ -115,19 +194,42 @@ impl Message {
         }
         Ok(res)
     }
+    /// `skip` are the indices of the commands that weren't approved, they are reported
+    /// instead of run
     pub fn eval(
         &mut self,
         conversation: &Conversation,
+        skip: &[usize],
     ) -> anyhow::Result<(Vec<Message>, Vec<InjectedFile>)> {
-        let commands = extract_commands(&self.msg);
+        let (commands, errors) = command_parser::parse_commands(&self.msg);
         dbg!(&commands);
         let mut new_msgs = Vec::new();
         let mut new_injected_files = Vec::new();
-        // clear the metadata every time. It should be generated through the eval
-        self.meta = Metadata::default();
+        // clear the metadata every time. It should be generated through the eval.
+        // Feedback comes from a human though so it is kept
+        self.meta = Metadata {
+            feedback: self.meta.feedback.take(),
+            ..Default::default()
+        };
+        // running half of what was asked for is worse than running none of it
+        if !errors.is_empty() {
+            let reports: Vec<String> = errors.iter().map(|e| e.report(&self.msg)).collect();
+            new_msgs.push(Message::new_with_msg(
+                User::System,
+                format!(
+                    "Could not parse your commands so none of them were run:\n{}",
+                    reports.join("\n")
+                ),
+            ));
+            return Ok((new_msgs, new_injected_files));
+        }
+        let commands: Vec<Command> = commands.into_iter().map(|c| c.command).collect();
         // preprocess commands to find abort an abort command if it exists
         // early-exit if it does
-        for command in &commands {
+        for (i, command) in commands.iter().enumerate() {
+            if skip.contains(&i) {
+                continue;
+            }
             if let Command::System(command) = command {
                 let mut args = shellwords::split(&command)?;
                 // add an extra empty string as the program name here
@@ -142,28 +244,28 @@ impl Message {
                 }
             }
         }
-        for command in commands {
-            let mut out = NexosInstance {};
+        // every command of the conversation runs in the same shell
+        let session_id = conversation.session_id();
+        for (i, command) in commands.into_iter().enumerate() {
+            if skip.contains(&i) {
+                let text = match command {
+                    Command::Nexos(command) | Command::System(command) => command,
+                };
+                new_msgs.push(Message::new_with_msg(
+                    User::System,
+                    format!("`{text}` wasn't run because it wasn't approved"),
+                ));
+                continue;
+            }
             match command {
                 Command::Nexos(command) => {
-                    let req = out.exec_simple(&command);
-
-                    let rt = tokio::runtime::Builder::new_current_thread()
-                        .enable_all()
-                        .build()
-                        .unwrap();
-
-                    // Call the asynchronous connect method using the runtime.
-                    let result = rt.block_on(req).context("failed to exec command")?;
-                    let mut msg = String::new();
-                    for line in &result.output {
-                        match line {
-                            LogLine::StdOut { message } => msg += message,
-                            LogLine::StdErr { message } => msg += message,
-                        }
-                    }
-
-                    new_msgs.push(Message::new_with_msg(User::Docker, msg));
+                    let start = Instant::now();
+                    let result = nexos::with_session(session_id, |session| {
+                        session.exec(&command, &conversation.nexos_limits)
+                    })
+                    .context("failed to exec command")?;
+                    let result = CommandResult::new(&command, result, start.elapsed());
+                    new_msgs.push(Message::new_with_result(result)?);
                 }
                 Command::System(command) => {
                     let mut args = shellwords::split(&command)?;
@@ -216,33 +318,56 @@ impl Message {
                             },
                             SystemSubcommand::Nexos { command } => match command {
                                 SystemNexosCommand::Rebuild {} => {
-                                    let req = out.rebuild();
-
-                                    let rt = tokio::runtime::Builder::new_current_thread()
-                                        .enable_all()
-                                        .build()
-                                        .unwrap();
-
-                                    // Call the asynchronous connect method using the runtime.
-                                    let result =
-                                        rt.block_on(req).context("failed to exec command")?;
-                                    let mut msg = String::new();
-                                    for line in &result.output {
-                                        match line {
-                                            LogLine::StdOut { message } => msg += message,
-                                            LogLine::StdErr { message } => msg += message,
-                                        }
-                                    }
-
-                                    new_msgs.push(Message::new_with_msg(User::Docker, msg));
+                                    let result = nexos::with_session(session_id, |session| {
+                                        Ok((session.rebuild()?, session.image().to_string()))
+                                    })
+                                    .context("failed to rebuild nexos")?;
+                                    let (result, image) = result;
+                                    new_msgs.push(Message::new_with_msg(
+                                        User::Docker,
+                                        result.report(
+                                            &image,
+                                            conversation.nexos_limits.max_output_bytes,
+                                        ),
+                                    ));
+                                }
+                                SystemNexosCommand::Restore {} => {
+                                    let msg = nexos::with_session(session_id, |session| {
+                                        session.restore_image()
+                                    })
+                                    .context("failed to restore the nexos image")?;
+                                    new_msgs.push(Message::new_with_msg(User::System, msg));
+                                }
+                                SystemNexosCommand::Reset {} => {
+                                    nexos::with_session(session_id, |session| session.reset())
+                                        .context("failed to reset nexos")?;
+                                    new_msgs.push(Message::new_with_msg(
+                                        User::System,
+                                        "Nexos was reset, the next command starts a new container"
+                                            .to_string(),
+                                    ));
+                                }
+                                SystemNexosCommand::Attach {} => {
+                                    let msg = nexos::with_session(session_id, |session| {
+                                        session.attach(&conversation.nexos_limits)
+                                    })
+                                    .context("failed to attach to nexos")?;
+                                    new_msgs.push(Message::new_with_msg(User::System, msg));
                                 }
                             },
+                            SystemSubcommand::File { command } => {
+                                let msg =
+                                    file_command(session_id, command, &conversation.nexos_limits)
+                                        .unwrap_or_else(|e| format!("{e:#}"));
+                                new_msgs.push(Message::new_with_msg(User::System, msg));
+                            }
                             SystemSubcommand::Memory { command } => match command {
                                 SystemMemoryCommand::Study { filename, context } => {
-                                    match std::fs::read_to_string(format!(
-                                        "/home/zack/personal/jake/nexos/persist/{}",
-                                        filename
-                                    )) {
+                                    match read_text(
+                                        session_id,
+                                        &filename,
+                                        conversation.nexos_limits.max_file_bytes,
+                                    ) {
                                         Ok(filetext) => {
                                             let uuid = uuid::Uuid::new_v4();
                                             let file = InjectedFile {
@@ -259,13 +384,19 @@ impl Message {
                                             println!("error: {e}");
                                             new_msgs.push(Message::new_with_msg(
                                                 User::System,
-                                                format!("unable to read file {}", filename),
+                                                format!("unable to read file {filename}: {e:#}"),
                                             ));
                                         }
                                     }
                                 }
                             },
                             SystemSubcommand::Abort {} => unreachable!("abort found in main loop"),
+                            SystemSubcommand::End {} => {
+                                new_msgs.push(Message::new_with_msg(
+                                    User::System,
+                                    "Conversation ended.".to_string(),
+                                ));
+                            }
                         },
                         Err(e) => {
                             new_msgs.push(Message::new_with_msg(User::Docker, e.to_string()));
@@ -313,10 +444,25 @@ impl InjectedFile {
 #[derive(Clone, PartialEq, Debug, serde::Serialize, serde::Deserialize)]
 pub struct Conversation {
     pub id: Option<String>,
+    /// Every message in the conversation tree (all branches), linked through `Message::parent`.
+    /// Use `active_messages` to get the branch that is currently selected.
     pub messages: Vec<Message>,
     #[serde(default = "field_1_default")]
     pub time: SystemTime,
     pub injected_files: Vec<InjectedFile>,
+    /// parent message id (ROOT_KEY for the first message) -> id of the selected child
+    #[serde(default)]
+    pub selected_children: HashMap<String, String>,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// A short description of the conversation, either generated from the summary
+    /// template or written by hand
+    #[serde(default)]
+    pub summary: String,
+    #[serde(default)]
+    pub nexos_limits: NexosLimits,
 }
 impl Default for Conversation {
     fn default() -> Self {
@@ -325,9 +471,82 @@ impl Default for Conversation {
             messages: Vec::default(),
             injected_files: Vec::default(),
             time: SystemTime::now(),
+            selected_children: HashMap::default(),
+            title: String::default(),
+            tags: Vec::default(),
+            summary: String::default(),
+            nexos_limits: NexosLimits::default(),
         }
     }
 }
+const ROOT_KEY: &str = "";
+
+#[derive(Clone, Copy, PartialEq, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub enum BranchExport {
+    /// Only the currently selected branch
+    #[default]
+    Active,
+    /// Every jake message in the tree, each with its own history
+    All,
+}
+
+#[derive(Clone, PartialEq, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct TrainingDataOptions {
+    pub branches: BranchExport,
+    /// Only export conversations that have at least one of these tags, empty exports everything
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Drop the oldest history from prompts that come out longer than this many tokens
+    #[serde(default)]
+    pub token_budget: Option<usize>,
+    /// Only conversations started at or after this
+    #[serde(default)]
+    pub since: Option<SystemTime>,
+    /// Only conversations started before this
+    #[serde(default)]
+    pub until: Option<SystemTime>,
+    /// Only conversations with a message from one of these users (see `User::key`)
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// Also export responses marked exclude_from_training
+    #[serde(default)]
+    pub include_excluded: bool,
+    /// Leave out responses where one of the commands failed
+    #[serde(default)]
+    pub exclude_failed: bool,
+}
+
+/// One rendered prompt and whether it had to be cut down to fit
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrainingSample {
+    /// The jake message this is a sample of, None for injected files
+    pub message_id: Option<String>,
+    pub text: String,
+    pub truncation: Option<Truncation>,
+}
+
+impl TrainingDataOptions {
+    pub fn includes(&self, conversation: &Conversation) -> bool {
+        let tagged = self.tags.is_empty() || self.tags.iter().any(|t| conversation.has_tag(t));
+        let in_range = self.since.map(|t| conversation.time >= t).unwrap_or(true)
+            && self.until.map(|t| conversation.time < t).unwrap_or(true);
+        let users = self.users.is_empty()
+            || conversation.messages.iter().any(|m| {
+                self.users
+                    .iter()
+                    .any(|u| u.eq_ignore_ascii_case(m.user.key()))
+            });
+        tagged && in_range && users
+    }
+}
+
+/// Splits a comma separated tag list, dropping empty entries
+pub fn parse_tags(tags: &str) -> Vec<String> {
+    tags.split(',')
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+        .collect()
+}
 fn field_2_default() -> Vec<InjectedFile> {
     Vec::new()
 }
@@ -366,65 +585,248 @@ pub fn messages_prompt_data(
 }
 
 impl Conversation {
-    pub fn msg_training_data(&self, i: usize) -> anyhow::Result<String> {
-        let m = self.messages.get(i).ok_or(anyhow!(
-            "i {} not in messages {}",
-            i,
-            self.messages.len()
-        ))?;
+    /// The Nexos session every command of the conversation runs in
+    pub fn session_id(&self) -> &str {
+        self.id.as_deref().unwrap_or("scratch")
+    }
+    /// What to show for the conversation in lists, the title if it has one
+    pub fn display_name(&self) -> String {
+        if !self.title.trim().is_empty() {
+            return self.title.clone();
+        }
+        match self.id {
+            Some(ref id) => id.clone(),
+            None => "untitled".to_string(),
+        }
+    }
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t.eq_ignore_ascii_case(tag.trim()))
+    }
+    /// The prompt that asks the model to summarize the active branch
+    pub fn summary_prompt(&self) -> anyhow::Result<String> {
+        let mut data = PromptTemplateData::default();
+        for msg in self.active_messages() {
+            data.msgs
+                .push(msg.to_prompt_template().context("to prompt template")?)
+        }
+        templates::summary(&data)
+    }
+    /// The prompt data for a jake message, with the oldest history dropped if it's over `token_budget`
+    pub fn msg_prompt_data(
+        &self,
+        id: &str,
+        token_budget: Option<usize>,
+    ) -> anyhow::Result<(PromptTemplateData, Option<Truncation>)> {
+        let path = self.path_to(id);
+        let (m, prev) = path
+            .split_last()
+            .ok_or(anyhow!("message {} not in conversation", id))?;
         if m.user != User::Jake {
             bail!(
                 "tried to get training data for non jake user {}, {}",
                 m.user.to_string(),
-                i
+                id
             )
         }
-        let prompt_data = messages_prompt_data(&self.messages[0..i], &self.messages[i], self)
-            .context("getting messages prompt data")?;
-        templates::prompt(&prompt_data)
+        let mut prompt_data =
+            messages_prompt_data(prev, m, self).context("getting messages prompt data")?;
+        let Some(budget) = token_budget else {
+            return Ok((prompt_data, None));
+        };
+        // everything since the innermost open task was entered is kept, as is the last message
+        let task_start = self.get_task_stack(id, false)?.last().and_then(|task| {
+            prompt_data
+                .msgs
+                .iter()
+                .position(|e| e.id.as_deref() == Some(task.msg_start_id.as_str()))
+        });
+        let keep_from = task_start
+            .unwrap_or(usize::MAX)
+            .min(prompt_data.msgs.len().saturating_sub(1));
+        let (_, truncation) = token::fit_prompt(
+            &mut prompt_data,
+            keep_from,
+            budget,
+            &self.summary,
+            token::mistral()?,
+            templates::prompt,
+            id,
+        )?;
+        Ok((prompt_data, truncation))
+    }
+    pub fn msg_training_data(
+        &self,
+        id: &str,
+        token_budget: Option<usize>,
+    ) -> anyhow::Result<TrainingSample> {
+        let (prompt_data, truncation) = self.msg_prompt_data(id, token_budget)?;
+        Ok(TrainingSample {
+            message_id: Some(id.to_string()),
+            text: templates::prompt(&prompt_data)?,
+            truncation,
+        })
+    }
+    /// The jake messages that become training samples
+    pub fn training_messages(&self, options: &TrainingDataOptions) -> Vec<Message> {
+        let msgs = match options.branches {
+            BranchExport::Active => self.active_messages(),
+            BranchExport::All => self.messages.clone(),
+        };
+        msgs.into_iter()
+            .filter(|m| {
+                m.user == User::Jake
+                    && (options.include_excluded || !m.meta.exclude_from_training)
+                    && !(options.exclude_failed && self.commands_failed(&m.id))
+            })
+            .collect()
+    }
+    /// The results of the commands in message `id`, eval puts them right after it
+    pub fn command_results(&self, id: &str) -> Vec<&CommandResult> {
+        let mut results = Vec::new();
+        let mut current = id;
+        while let Some(next) = self
+            .children(Some(current))
+            .into_iter()
+            .find(|m| m.result.is_some())
+        {
+            results.extend(next.result.as_ref());
+            current = &next.id;
+        }
+        results
     }
-    pub fn to_training_data(&self) -> anyhow::Result<Vec<String>> {
+    pub fn commands_failed(&self, id: &str) -> bool {
+        self.command_results(id).iter().any(|r| r.failed())
+    }
+    pub fn to_training_data(
+        &self,
+        options: &TrainingDataOptions,
+    ) -> anyhow::Result<Vec<TrainingSample>> {
         let mut data = Vec::new();
-        for i in 0..self.messages.len() {
-            if self.messages[i].user == User::Jake {
-                if self.messages[i].meta.exclude_from_training {
-                    continue;
-                }
-                data.push(self.msg_training_data(i)?)
-            }
+        for msg in self.training_messages(options) {
+            data.push(self.msg_training_data(&msg.id, options.token_budget)?)
         }
         for file in &self.injected_files {
             let template = file.to_template_data()?;
-            let datum = templates::injested_file(&template)?;
-            data.push(datum);
+            data.push(TrainingSample {
+                message_id: None,
+                text: templates::injested_file(&template)?,
+                truncation: None,
+            });
         }
         return Ok(data);
     }
-    pub fn write_jsonl<W: std::io::Write>(&self, writer: &mut W) -> anyhow::Result<()> {
-        let training_data = self.to_training_data()?;
-        #[derive(Serialize)]
-        struct Data {
-            text: String,
+    /// Pairs every liked jake response with every disliked alternative of it
+    pub fn preference_pairs(&self) -> anyhow::Result<Vec<PreferencePair>> {
+        let mut pairs = Vec::new();
+        let mut seen_parents = Vec::new();
+        for msg in &self.messages {
+            if msg.user != User::Jake || seen_parents.contains(&msg.parent) {
+                continue;
+            }
+            seen_parents.push(msg.parent.clone());
+            let siblings: Vec<&Message> = self
+                .children(msg.parent.as_deref())
+                .into_iter()
+                .filter(|m| m.user == User::Jake && !m.meta.exclude_from_training)
+                .collect();
+            let rated = |rating: Rating| {
+                siblings
+                    .iter()
+                    .filter(|m| m.rating() == Some(rating))
+                    .map(|m| m.msg.clone())
+                    .collect::<Vec<String>>()
+            };
+            let (liked, disliked) = (rated(Rating::Like), rated(Rating::Dislike));
+            let Some(first) = siblings.iter().find(|m| m.rating() == Some(Rating::Like)) else {
+                continue;
+            };
+            if disliked.is_empty() {
+                continue;
+            }
+            // every sibling shares the history so the prompt only has to be built once
+            let path = self.path_to(&first.id);
+            let (curr, prev) = path
+                .split_last()
+                .ok_or(anyhow!("message {} not in conversation", first.id))?;
+            let mut prompt_data =
+                messages_prompt_data(prev, curr, self).context("getting messages prompt data")?;
+            prompt_data.response = String::new();
+            let prompt = templates::prompt(&prompt_data)?;
+            for chosen in &liked {
+                for rejected in &disliked {
+                    pairs.push(PreferencePair {
+                        prompt: prompt.clone(),
+                        chosen: chosen.clone(),
+                        rejected: rejected.clone(),
+                    });
+                }
+            }
         }
-        for msg in training_data {
-            // We use to_string here instead of to_vec because it verifies that the JSON is valid UTF-8,
-            // which is required by the JSON Lines specification (https://jsonlines.org).
-            let json = serde_json::to_string(&Data { text: msg })?;
-
-            writer.write_all(json.as_bytes())?;
-            writer.write_all(b"\n")?;
+        Ok(pairs)
+    }
+    /// Runs the commands of the message, the slow half of EvalMessage. Doesn't change the
+    /// conversation so it can run on a copy somewhere else. The commands at the indices in
+    /// `skip` aren't run
+    pub fn evaluate(&self, id: &str, skip: &[usize]) -> anyhow::Result<Evaluation> {
+        let mut message = self
+            .messages
+            .iter()
+            .find(|m| m.id == id)
+            .ok_or(anyhow::anyhow!("failed to eval because did not find id"))?
+            .clone();
+        let (new_msgs, new_files) = message.eval(self, skip)?;
+        // a failed snapshot shouldn't lose what the commands did
+        match snapshot::take(self.session_id(), id) {
+            Ok(snapshot) => message.snapshot = snapshot,
+            Err(e) => println!("failed to snapshot nexos: {e:#}"),
+        }
+        Ok(Evaluation {
+            message,
+            new_msgs,
+            new_files,
+        })
+    }
+    /// Puts the results of `evaluate` after the evaluated message
+    pub fn add_evaluation(&mut self, evaluation: Evaluation) -> anyhow::Result<()> {
+        let Evaluation {
+            message,
+            new_msgs,
+            new_files,
+        } = evaluation;
+        let index = self
+            .messages
+            .iter()
+            .position(|m| m.id == message.id)
+            .ok_or(anyhow::anyhow!("the evaluated message is gone"))?;
+        let mut after = message.id.clone();
+        self.messages[index] = message;
+        for newmsg in new_msgs {
+            let new_id = newmsg.id.clone();
+            self.insert_after(Some(&after), newmsg);
+            after = new_id;
+        }
+        for newfile in new_files {
+            self.injected_files.push(newfile)
         }
-
         Ok(())
     }
     pub fn apply(&mut self, action: ConversationAction) -> anyhow::Result<()> {
         match action {
             ConversationAction::AddMessage { index, user } => {
                 let msg = Message::new(user);
-                match index {
-                    Some(index) => self.messages.insert(index, msg),
-                    None => self.messages.push(msg),
-                }
+                let active = self.active_messages();
+                let after = match index {
+                    Some(0) => None,
+                    Some(index) => Some(
+                        active
+                            .get(index - 1)
+                            .ok_or(anyhow!("index {} out of range", index))?
+                            .id
+                            .clone(),
+                    ),
+                    None => active.last().map(|m| m.id.clone()),
+                };
+                self.insert_after(after.as_deref(), msg);
             }
             ConversationAction::MutateMessage { new_message } => {
                 let _ = std::mem::replace(
@@ -438,40 +840,183 @@ impl Conversation {
                 );
             }
             ConversationAction::EvalMessage { id } => {
-                let (index, msg) = self
+                let evaluation = self.evaluate(&id, &[])?;
+                self.add_evaluation(evaluation)?;
+            }
+            ConversationAction::DeleteMessage { id } => {
+                let msg = self
                     .messages
                     .iter()
-                    .enumerate()
-                    .find(|(_, m)| m.id == id)
-                    .ok_or(anyhow::anyhow!("failed to eval because did not find id"))?
+                    .find(|m| m.id == id)
+                    .ok_or(anyhow!("failed to delete because did not find id"))?
                     .clone();
-                let mut msg = msg.clone();
-                let (mut new_msgs, new_files) = msg.eval(&self)?;
-                self.messages[index] = msg;
-                new_msgs.reverse();
-                for newmsg in new_msgs {
-                    self.messages.insert(index + 1, newmsg)
+                let parent_key = msg.parent.clone().unwrap_or(ROOT_KEY.to_string());
+                // the children of the deleted message move up to take its place
+                let selected = self.selected_child(Some(&id)).map(|m| m.id.clone());
+                for m in self.messages.iter_mut() {
+                    if m.parent.as_deref() == Some(id.as_str()) {
+                        m.parent = msg.parent.clone();
+                    }
                 }
-                for newfile in new_files {
-                    self.injected_files.push(newfile)
+                self.selected_children.remove(&id);
+                if self.selected_children.get(&parent_key) == Some(&id) {
+                    match selected {
+                        Some(selected) => self.selected_children.insert(parent_key, selected),
+                        None => self.selected_children.remove(&parent_key),
+                    };
                 }
-            }
-            ConversationAction::DeleteMessage { id } => {
                 self.messages.retain(|i| i.id != id);
             }
+            ConversationAction::Regenerate { id } => {
+                let original = self
+                    .messages
+                    .iter()
+                    .find(|m| m.id == id)
+                    .ok_or(anyhow!("failed to regenerate because did not find id"))?;
+                let mut msg = Message::new(original.user.clone());
+                msg.parent = original.parent.clone();
+                let new_id = msg.id.clone();
+                self.messages.push(msg);
+                self.select(&new_id)?;
+            }
+            ConversationAction::SwitchBranch { id } => {
+                self.select(&id)?;
+            }
+            ConversationAction::SetTitle { title } => self.title = title,
+            ConversationAction::SetTags { tags } => {
+                let mut deduped: Vec<String> = Vec::new();
+                for tag in tags {
+                    let tag = tag.trim().to_string();
+                    if !tag.is_empty() && !deduped.iter().any(|t| t.eq_ignore_ascii_case(&tag)) {
+                        deduped.push(tag);
+                    }
+                }
+                self.tags = deduped;
+            }
+            ConversationAction::SetSummary { summary } => self.summary = summary,
+            ConversationAction::SetNexosLimits { limits } => self.nexos_limits = limits,
+            ConversationAction::PruneBranch { id } => {
+                let msg = self
+                    .messages
+                    .iter()
+                    .find(|m| m.id == id)
+                    .ok_or(anyhow!("failed to prune because did not find id"))?
+                    .clone();
+                let mut doomed = vec![id.clone()];
+                let mut i = 0;
+                while i < doomed.len() {
+                    for m in &self.messages {
+                        if m.parent.as_deref() == Some(doomed[i].as_str()) {
+                            doomed.push(m.id.clone());
+                        }
+                    }
+                    i += 1;
+                }
+                let parent_key = msg.parent.unwrap_or(ROOT_KEY.to_string());
+                if self.selected_children.get(&parent_key) == Some(&id) {
+                    self.selected_children.remove(&parent_key);
+                }
+                for doomed_id in &doomed {
+                    self.selected_children.remove(doomed_id);
+                }
+                self.messages.retain(|m| !doomed.contains(&m.id));
+            }
         }
         Ok(())
     }
-    pub fn get_msgs_till(&self, msgid: &str, inclusive: bool) -> Vec<Message> {
-        let mut result = Vec::new();
-        for msg in &self.messages {
-            if msg.id == msgid {
-                if inclusive {
-                    result.push(msg.clone());
-                }
+    /// Children of `parent` (None for the roots) in the order they were created
+    pub fn children(&self, parent: Option<&str>) -> Vec<&Message> {
+        self.messages
+            .iter()
+            .filter(|m| m.parent.as_deref() == parent)
+            .collect()
+    }
+    /// The child of `parent` on the active branch. Falls back to the newest child
+    pub fn selected_child(&self, parent: Option<&str>) -> Option<&Message> {
+        let children = self.children(parent);
+        self.selected_children
+            .get(parent.unwrap_or(ROOT_KEY))
+            .and_then(|id| children.iter().find(|m| m.id == *id).copied())
+            .or(children.last().copied())
+    }
+    /// The messages of the currently selected branch from the first to the last
+    pub fn active_messages(&self) -> Vec<Message> {
+        let mut result: Vec<Message> = Vec::new();
+        let mut curr = self.selected_child(None);
+        while let Some(msg) = curr {
+            // a cycle should never happen but it would hang the gui if it did
+            if result.len() > self.messages.len() {
+                break;
+            }
+            result.push(msg.clone());
+            curr = self.selected_child(Some(&msg.id));
+        }
+        result
+    }
+    /// The chain of messages from the first message down to `msgid` (inclusive),
+    /// empty if the message doesn't exist
+    pub fn path_to(&self, msgid: &str) -> Vec<Message> {
+        let mut result: Vec<Message> = Vec::new();
+        let mut curr = self.messages.iter().find(|m| m.id == msgid);
+        while let Some(msg) = curr {
+            if result.len() > self.messages.len() {
                 break;
             }
             result.push(msg.clone());
+            curr = match msg.parent {
+                Some(ref parent) => self.messages.iter().find(|m| m.id == *parent),
+                None => None,
+            };
+        }
+        result.reverse();
+        result
+    }
+    /// Ids of every alternative of `msgid` including itself
+    pub fn siblings(&self, msgid: &str) -> Vec<String> {
+        match self.messages.iter().find(|m| m.id == msgid) {
+            Some(msg) => self
+                .children(msg.parent.as_deref())
+                .iter()
+                .map(|m| m.id.clone())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+    /// Makes the branch that leads to `msgid` the active one
+    fn select(&mut self, msgid: &str) -> anyhow::Result<()> {
+        let path = self.path_to(msgid);
+        if path.is_empty() {
+            bail!("no message with id {}", msgid);
+        }
+        for msg in path {
+            let parent_key = msg.parent.clone().unwrap_or(ROOT_KEY.to_string());
+            self.selected_children.insert(parent_key, msg.id.clone());
+        }
+        Ok(())
+    }
+    /// Splices `msg` into the tree directly after `after` (None for the very start).
+    /// Everything that used to follow `after` now follows `msg` instead.
+    fn insert_after(&mut self, after: Option<&str>, mut msg: Message) {
+        let after_key = after.unwrap_or(ROOT_KEY).to_string();
+        for m in self.messages.iter_mut() {
+            if m.parent.as_deref() == after {
+                m.parent = Some(msg.id.clone());
+            }
+        }
+        if let Some(selected) = self.selected_children.remove(&after_key) {
+            self.selected_children.insert(msg.id.clone(), selected);
+        }
+        self.selected_children.insert(after_key, msg.id.clone());
+        msg.parent = after.map(|a| a.to_string());
+        self.messages.push(msg);
+    }
+    pub fn get_msgs_till(&self, msgid: &str, inclusive: bool) -> Vec<Message> {
+        let mut result = self.path_to(msgid);
+        if result.is_empty() {
+            return self.active_messages();
+        }
+        if !inclusive {
+            result.pop();
         }
         result
     }
@@ -541,6 +1086,16 @@ impl Conversation {
     }
 }
 
+/// What running the commands of a message produced
+#[derive(Clone, Debug)]
+pub struct Evaluation {
+    /// The message with its metadata and snapshot from the run
+    pub message: Message,
+    /// Results, in the order they go after the message
+    pub new_msgs: Vec<Message>,
+    pub new_files: Vec<InjectedFile>,
+}
+
 #[derive(Debug, Clone)]
 pub struct TaskInfo {
     pub id: String,
@@ -560,10 +1115,16 @@ pub struct Conversations {
 impl Conversations {
     pub fn new(db: Arc<DB>, bucket_name: Option<String>) -> anyhow::Result<Self> {
         let bucket_name = bucket_name.unwrap_or_else(|| "conversations".to_string());
+        let fresh_db = migrations::read_version(&db)?.is_none();
         let tx = db.tx(true)?;
         let create_result = tx.create_bucket(bucket_name.to_string());
         match create_result {
-            Ok(_) => {}
+            Ok(_) => {
+                // nothing to migrate in a brand new db so it starts at the latest schema
+                if fresh_db {
+                    migrations::write_version(&tx, migrations::current_version())?;
+                }
+            }
             Err(JammError::BucketExists) => {}
             Err(e) => anyhow::bail!("failed to create bucket {e}"),
         };
@@ -572,8 +1133,10 @@ impl Conversations {
 
         Ok(Conversations { db, bucket_name })
     }
+}
 
-    pub fn get(&self, uuid: &str) -> Result<Option<Conversation>> {
+impl ConversationStore for Conversations {
+    fn get(&self, uuid: &str) -> Result<Option<Conversation>> {
         let tx = self.db.tx(false)?;
         let bucket = tx.get_bucket(self.bucket_name.clone())?;
         let data = bucket.get(uuid.as_bytes());
@@ -587,7 +1150,7 @@ impl Conversations {
         Ok(Some(conv))
     }
 
-    pub fn insert(&mut self, to_insert: &mut Conversation) -> Result<String> {
+    fn insert(&mut self, to_insert: &mut Conversation) -> Result<String> {
         let uuid = match to_insert.id {
             Some(ref id) => id.clone(),
             None => {
@@ -600,58 +1163,131 @@ impl Conversations {
         let tx = self.db.tx(true)?;
         let bucket = tx.get_bucket(self.bucket_name.clone())?;
         let data =
-            rmp_serde::to_vec(&to_insert).context("Failed to serialize conversation data")?;
+            rmp_serde::to_vec_named(&to_insert).context("Failed to serialize conversation data")?;
         bucket.put(uuid_clone.as_bytes(), data)?;
         tx.commit()?;
         Ok(uuid)
     }
 
-    pub fn delete(&mut self, uuid: &str) -> Result<()> {
+    fn delete(&mut self, uuid: &str) -> Result<()> {
         let tx = self.db.tx(true)?;
         let bucket = tx.get_bucket(self.bucket_name.clone())?;
         bucket.delete(uuid)?;
         tx.commit()?;
+        history::jamm_delete_history(&self.db, uuid)?;
         Ok(())
     }
-}
 
-impl IntoIterator for Conversations {
-    type Item = (String, Conversation);
-    type IntoIter = Box<dyn Iterator<Item = Self::Item>>;
-
-    fn into_iter(self) -> Self::IntoIter {
-        let tx = match self.db.tx(false) {
-            Ok(tx) => tx,
-            Err(_) => return Box::new(std::iter::empty()),
-        };
-        let bucket = match tx.get_bucket(self.bucket_name) {
-            Ok(b) => b,
-            Err(_) => return Box::new(std::iter::empty()),
-        };
+    fn try_iter(&self) -> Result<Vec<(String, Result<Conversation>)>> {
+        let tx = self.db.tx(false)?;
+        let bucket = tx.get_bucket(self.bucket_name.clone())?;
 
         let mut data = Vec::new();
-        for k in bucket.into_iter() {
-            if let Ok(uuid_str) = std::str::from_utf8(k.kv().key().clone()) {
-                match rmp_serde::from_slice::<Conversation>(k.kv().value()) {
-                    Ok(conv) => {
-                        data.push((uuid_str.to_string(), conv.clone()));
-                    }
-                    Err(err) => {
-                        eprintln!("{err:?}")
-                    }
-                }
-            }
+        for k in bucket.cursor() {
+            let uuid_str = String::from_utf8_lossy(k.kv().key()).to_string();
+            let conv = rmp_serde::from_slice::<Conversation>(k.kv().value())
+                .with_context(|| format!("failed to decode conversation {uuid_str}"));
+            data.push((uuid_str, conv));
+        }
+        Ok(data)
+    }
+
+    fn schema_version(&self) -> Result<Option<u32>> {
+        migrations::read_version(&self.db)
+    }
+
+    fn raw_records(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        let tx = self.db.tx(false)?;
+        let bucket = tx.get_bucket(self.bucket_name.clone())?;
+        let mut records = Vec::new();
+        for data in bucket.cursor() {
+            let kv = data.kv();
+            records.push((
+                String::from_utf8_lossy(kv.key()).to_string(),
+                kv.value().to_vec(),
+            ));
+        }
+        Ok(records)
+    }
+
+    fn raw_history(&self, uuid: &str) -> Result<Vec<Vec<u8>>> {
+        history::jamm_raw_history(&self.db, uuid)
+    }
+
+    fn write_migrated(
+        &mut self,
+        records: Vec<(String, Vec<u8>)>,
+        history: Vec<(String, Vec<HistoryEntry>)>,
+        version: u32,
+    ) -> Result<()> {
+        let tx = self.db.tx(true)?;
+        let bucket = tx.get_bucket(self.bucket_name.clone())?;
+        for (id, bytes) in records {
+            bucket.put(id.into_bytes(), bytes)?;
         }
-        Box::new(data.into_iter())
+        for (id, entries) in history {
+            history::jamm_put_entries(&tx, &id, &entries)?;
+        }
+        migrations::write_version(&tx, version)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn history(&self, uuid: &str) -> Result<Vec<HistoryEntry>> {
+        history::jamm_history(&self.db, uuid)
+    }
+
+    fn stored_history_head(&self, uuid: &str) -> Result<Option<u64>> {
+        history::jamm_history_head(&self.db, uuid)
+    }
+
+    fn write_revision(&mut self, uuid: &str, update: RevisionUpdate) -> Result<()> {
+        history::jamm_write_revision(&self.db, &self.bucket_name, uuid, update)
     }
 }
 
-#[derive(Clone, Debug, Display, PartialEq)]
+#[derive(Clone, Debug, Display, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum ConversationAction {
-    DeleteMessage { id: String },
-    AddMessage { index: Option<usize>, user: User },
-    EvalMessage { id: String },
-    MutateMessage { new_message: Message },
+    DeleteMessage {
+        id: String,
+    },
+    /// index is a position in the active branch
+    AddMessage {
+        index: Option<usize>,
+        user: User,
+    },
+    EvalMessage {
+        id: String,
+    },
+    MutateMessage {
+        new_message: Message,
+    },
+    /// Add an empty alternative next to the message and switch to it
+    Regenerate {
+        id: String,
+    },
+    /// Make this message (and every message before it) part of the active branch
+    SwitchBranch {
+        id: String,
+    },
+    /// Delete the message and everything that follows it on every branch
+    PruneBranch {
+        id: String,
+    },
+    SetTitle {
+        title: String,
+    },
+    SetTags {
+        tags: Vec<String>,
+    },
+    /// Either a generated summary being accepted or a manual edit
+    SetSummary {
+        summary: String,
+    },
+    /// Takes effect on the container with the next command
+    SetNexosLimits {
+        limits: NexosLimits,
+    },
 }
 
 #[derive(Parser, Debug)]
@@ -669,6 +1305,11 @@ enum SystemSubcommand {
         #[command(subcommand)]
         command: SystemNexosCommand,
     },
+    /// Move files in and out of your home directory
+    File {
+        #[command(subcommand)]
+        command: SystemFileCommand,
+    },
     /// Work with your memory
     Memory {
         #[command(subcommand)]
@@ -681,12 +1322,105 @@ enum SystemSubcommand {
     },
     /// Abort and do not run any of the commands that would have been executed
     Abort {},
+    /// End the conversation, nothing else happens until Zack writes again
+    End {},
+}
+
+/// Whether a system command is `end`, the agent stops after a response with one
+pub fn is_end_command(command: &str) -> bool {
+    let Ok(mut args) = shellwords::split(command) else {
+        return false;
+    };
+    args.insert(0, String::new());
+    matches!(
+        SystemCli::try_parse_from(args),
+        Ok(SystemCli {
+            command: SystemSubcommand::End {}
+        })
+    )
 }
 
 #[derive(Subcommand, Debug)]
 enum SystemNexosCommand {
-    /// Rebuild Nexos from the dockerfile at ~/System/Dockerfile.txt
+    /// Rebuild Nexos from the dockerfile at ~/System/Dockerfile.txt. Reset to use the new image
     Rebuild {},
+    /// Go back to the image from before the last rebuild. Reset to use it
+    Restore {},
+    /// Throw away the container and shell, only ~ is kept
+    Reset {},
+    /// Start or reconnect to the container without running anything
+    Attach {},
+}
+
+#[derive(Subcommand, Debug)]
+enum SystemFileCommand {
+    /// Write a file in your home directory, replacing it if it is there
+    Put {
+        path: String,
+        /// Everything that goes in the file
+        #[arg(allow_hyphen_values = true)]
+        contents: String,
+    },
+    /// Show a file from your home directory
+    Get { path: String },
+    /// Change a file in your home directory
+    Edit {
+        path: String,
+        /// Blocks of "<<<<<<< SEARCH", the lines to replace, "=======", the new lines and
+        /// ">>>>>>> REPLACE", or a unified diff
+        #[arg(allow_hyphen_values = true)]
+        edit: String,
+    },
+}
+
+/// A text file from the conversation's Nexos session, `path` as Jake wrote it
+fn read_text(session_id: &str, path: &str, max_bytes: usize) -> Result<String> {
+    let path = files::home_path(path)?;
+    let bytes = nexos::with_session(session_id, |session| session.read_file(&path, max_bytes))?;
+    String::from_utf8(bytes).map_err(|_| anyhow!("~/{path} isn't a text file"))
+}
+
+/// Returns what to tell Jake
+fn file_command(
+    session_id: &str,
+    command: SystemFileCommand,
+    limits: &NexosLimits,
+) -> Result<String> {
+    let max_bytes = limits.max_file_bytes;
+    let write = |path: &str, contents: &str| {
+        if max_bytes > 0 && contents.len() > max_bytes {
+            bail!(
+                "~/{path} would be {} bytes, more than the {max_bytes} byte limit",
+                contents.len()
+            );
+        }
+        nexos::with_session(session_id, |session| {
+            session.write_file(path, contents.as_bytes())
+        })
+    };
+    match command {
+        SystemFileCommand::Put { path, contents } => {
+            let path = files::home_path(&path)?;
+            write(&path, &contents)?;
+            Ok(format!("Wrote {} bytes to ~/{path}", contents.len()))
+        }
+        SystemFileCommand::Get { path } => {
+            let text = read_text(session_id, &path, max_bytes)?;
+            let path = files::home_path(&path)?;
+            Ok(format!(
+                "~/{path}:\n{}",
+                nexos::cap_output(&text, limits.max_output_bytes)
+            ))
+        }
+        SystemFileCommand::Edit { path, edit } => {
+            let original = read_text(session_id, &path, max_bytes)?;
+            let path = files::home_path(&path)?;
+            let edited = files::apply_edit(&original, &edit)
+                .with_context(|| format!("~/{path} was not changed"))?;
+            write(&path, &edited)?;
+            Ok(format!("Edited ~/{path}"))
+        }
+    }
 }
 
 #[derive(Subcommand, Debug)]
@@ -714,3 +1448,182 @@ enum TaskNexosCommand {
         summary: String,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::nexos::DockerResult;
+
+    fn msgs(conversation: &Conversation) -> Vec<String> {
+        conversation
+            .active_messages()
+            .iter()
+            .map(|m| m.msg.clone())
+            .collect()
+    }
+
+    fn add(conversation: &mut Conversation, user: User, text: &str) -> Message {
+        conversation
+            .apply(ConversationAction::AddMessage { index: None, user })
+            .unwrap();
+        let mut msg = conversation.active_messages().last().unwrap().clone();
+        msg.msg = text.to_string();
+        conversation
+            .apply(ConversationAction::MutateMessage {
+                new_message: msg.clone(),
+            })
+            .unwrap();
+        msg
+    }
+
+    #[test]
+    fn test_regenerate_and_switch_branch() {
+        let mut conversation = Conversation::default();
+        add(&mut conversation, User::Zack, "hi");
+        let first = add(&mut conversation, User::Jake, "first");
+
+        conversation
+            .apply(ConversationAction::Regenerate {
+                id: first.id.clone(),
+            })
+            .unwrap();
+        let mut second = conversation.active_messages()[1].clone();
+        assert_eq!(conversation.siblings(&first.id).len(), 2);
+        second.msg = "second".into();
+        conversation
+            .apply(ConversationAction::MutateMessage {
+                new_message: second.clone(),
+            })
+            .unwrap();
+        assert_eq!(msgs(&conversation), vec!["hi", "second"]);
+
+        conversation
+            .apply(ConversationAction::SwitchBranch {
+                id: first.id.clone(),
+            })
+            .unwrap();
+        assert_eq!(msgs(&conversation), vec!["hi", "first"]);
+
+        conversation
+            .apply(ConversationAction::PruneBranch { id: first.id })
+            .unwrap();
+        assert_eq!(msgs(&conversation), vec!["hi", "second"]);
+        assert_eq!(conversation.messages.len(), 2);
+    }
+
+    #[test]
+    fn test_insert_into_middle_of_branch() {
+        let mut conversation = Conversation::default();
+        add(&mut conversation, User::Zack, "a");
+        add(&mut conversation, User::Jake, "c");
+        conversation
+            .apply(ConversationAction::AddMessage {
+                index: Some(1),
+                user: User::Docker,
+            })
+            .unwrap();
+        let mut msg = conversation.active_messages()[1].clone();
+        assert_eq!(msg.user, User::Docker);
+        msg.msg = "b".into();
+        conversation
+            .apply(ConversationAction::MutateMessage { new_message: msg })
+            .unwrap();
+        assert_eq!(msgs(&conversation), vec!["a", "b", "c"]);
+
+        let id = conversation.active_messages()[1].id.clone();
+        conversation
+            .apply(ConversationAction::DeleteMessage { id })
+            .unwrap();
+        assert_eq!(msgs(&conversation), vec!["a", "c"]);
+    }
+
+    #[test]
+    fn test_preference_pairs() {
+        templates::use_repo_templates();
+        let mut conversation = Conversation::default();
+        add(&mut conversation, User::Zack, "how do I list files");
+        let mut rejected = add(&mut conversation, User::Jake, "rm -rf /");
+        rejected.meta.feedback = Some(Feedback::new(Rating::Dislike));
+        conversation
+            .apply(ConversationAction::MutateMessage {
+                new_message: rejected.clone(),
+            })
+            .unwrap();
+        assert!(conversation.preference_pairs().unwrap().is_empty());
+
+        // the alternatives are siblings of the disliked response on their own branches
+        let first = rejected.id;
+        for (text, rating) in [("ls", Some(Rating::Like)), ("dunno", None)] {
+            conversation
+                .apply(ConversationAction::Regenerate { id: first.clone() })
+                .unwrap();
+            let mut msg = conversation.active_messages()[1].clone();
+            msg.msg = text.to_string();
+            msg.meta.feedback = rating.map(Feedback::new);
+            conversation
+                .apply(ConversationAction::MutateMessage { new_message: msg })
+                .unwrap();
+        }
+        assert_eq!(conversation.siblings(&first).len(), 3);
+
+        let pairs = conversation.preference_pairs().unwrap();
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(pairs[0].chosen, "ls");
+        assert_eq!(pairs[0].rejected, "rm -rf /");
+        assert!(pairs[0].prompt.contains("how do I list files"));
+        assert!(!pairs[0].prompt.contains("rm -rf /"));
+    }
+
+    #[test]
+    fn test_tags_filter_exports() {
+        let mut conversation = Conversation::default();
+        add(&mut conversation, User::Zack, "hi");
+        conversation
+            .apply(ConversationAction::SetTags {
+                tags: parse_tags("shell, Shell,,rust, "),
+            })
+            .unwrap();
+        assert_eq!(conversation.tags, vec!["shell", "rust"]);
+
+        let mut options = TrainingDataOptions::default();
+        assert!(options.includes(&conversation));
+        options.tags = vec!["SHELL".into()];
+        assert!(options.includes(&conversation));
+        options.tags = vec!["docker".into()];
+        assert!(!options.includes(&conversation));
+    }
+
+    #[test]
+    fn test_exclude_failed_commands() {
+        let mut conversation = Conversation::default();
+        add(&mut conversation, User::Zack, "hi");
+        add(&mut conversation, User::Jake, "[<false>]");
+        let mut msg = add(&mut conversation, User::Docker, "$ false");
+        msg.result = Some(CommandResult::new(
+            "false",
+            DockerResult {
+                exit_code: 1,
+                ..Default::default()
+            },
+            Duration::from_millis(10),
+        ));
+        conversation
+            .apply(ConversationAction::MutateMessage { new_message: msg })
+            .unwrap();
+        add(&mut conversation, User::Jake, "fine");
+
+        let mut options = TrainingDataOptions::default();
+        let texts = |options: &TrainingDataOptions| -> Vec<String> {
+            conversation
+                .training_messages(options)
+                .iter()
+                .map(|m| m.msg.clone())
+                .collect()
+        };
+        assert_eq!(texts(&options), vec!["[<false>]", "fine"]);
+        options.exclude_failed = true;
+        assert_eq!(texts(&options), vec!["fine"]);
+    }
+}
@@ -0,0 +1,545 @@
+use anyhow::{anyhow, Context, Result};
+use serde_json::{json, Value};
+
+use crate::conversation::{Conversation, TrainingDataOptions, User};
+use crate::manifest::{self, Manifest, ManifestConversation, ManifestSample, Split};
+use crate::templates::{self, MetadataPromptTemplateEntry, PromptTemplateData};
+use crate::token::Truncation;
+
+/// One line of an exported dataset and where it came from
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExportRecord {
+    pub conversation_id: String,
+    /// The jake message the record was built from, None for injected files and preference pairs
+    pub source_id: Option<String>,
+    pub value: Value,
+    pub truncation: Option<Truncation>,
+}
+
+/// A training data format. Add new ones to `exporters`
+pub trait Exporter {
+    /// What the format is called on the command line
+    fn name(&self) -> &'static str;
+    fn description(&self) -> &'static str;
+    fn default_path(&self) -> String {
+        format!("{}.jsonl", self.name())
+    }
+    fn records(
+        &self,
+        conversation: &Conversation,
+        options: &TrainingDataOptions,
+    ) -> Result<Vec<ExportRecord>>;
+}
+
+pub fn exporters() -> Vec<Box<dyn Exporter>> {
+    vec![
+        Box::new(Completion),
+        Box::new(ShareGpt),
+        Box::new(ChatMl),
+        Box::new(Alpaca),
+        Box::new(InputOutput),
+        Box::new(Dpo),
+    ]
+}
+
+pub fn exporter(name: &str) -> Result<Box<dyn Exporter>> {
+    exporters()
+        .into_iter()
+        .find(|e| e.name() == name)
+        .ok_or_else(|| {
+            let names: Vec<&str> = exporters().iter().map(|e| e.name()).collect();
+            anyhow!(
+                "unknown export format {name}, expected one of {}",
+                names.join(", ")
+            )
+        })
+}
+
+#[derive(Debug, Default)]
+pub struct ExportReport {
+    pub conversations: usize,
+    pub records: usize,
+    pub validation_conversations: usize,
+    pub validation_records: usize,
+    pub truncations: Vec<Truncation>,
+    /// What was exported, for the manifest
+    pub manifest_conversations: Vec<ManifestConversation>,
+    pub manifest_samples: Vec<ManifestSample>,
+}
+
+impl std::fmt::Display for ExportReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for truncation in &self.truncations {
+            writeln!(f, "truncated {truncation}")?;
+        }
+        writeln!(
+            f,
+            "train: {} records from {} conversations",
+            self.records, self.conversations
+        )?;
+        if self.validation_conversations > 0 {
+            writeln!(
+                f,
+                "validation: {} records from {} conversations",
+                self.validation_records, self.validation_conversations
+            )?;
+        }
+        write!(f, "{} truncated", self.truncations.len())
+    }
+}
+
+/// 64 bit FNV-1a. Used instead of std's hasher because that one isn't guaranteed
+/// to be the same across rust versions and the split has to be stable forever
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Whether a conversation goes in the validation set. Only depends on the id so a
+/// conversation lands on the same side of the split in every export
+pub fn is_validation(conversation_id: &str, validation_fraction: f64) -> bool {
+    if validation_fraction <= 0.0 {
+        return false;
+    }
+    (fnv1a(conversation_id.as_bytes()) % 10_000) as f64 / 10_000.0 < validation_fraction
+}
+
+/// Returns the hash of the line that was written
+fn write_record<W: std::io::Write>(writer: &mut W, record: &ExportRecord) -> Result<String> {
+    // to_string instead of to_vec because it verifies that the JSON is valid UTF-8,
+    // which is required by the JSON Lines specification (https://jsonlines.org).
+    let json = serde_json::to_string(&record.value)?;
+    writer.write_all(json.as_bytes())?;
+    writer.write_all(b"\n")?;
+    Ok(manifest::sha256(json.as_bytes()))
+}
+
+/// Writes every record of every conversation the options include as jsonl.
+/// With `validation` set, that fraction of the conversations goes to its writer instead
+pub fn export<W: std::io::Write>(
+    exporter: &dyn Exporter,
+    conversations: impl IntoIterator<Item = Conversation>,
+    options: &TrainingDataOptions,
+    writer: &mut W,
+    mut validation: Option<(&mut W, f64)>,
+) -> Result<ExportReport> {
+    let mut report = ExportReport::default();
+    for conversation in conversations {
+        if !options.includes(&conversation) {
+            continue;
+        }
+        let conversation_id = conversation.id.clone().unwrap_or_default();
+        let records = exporter
+            .records(&conversation, options)
+            .with_context(|| format!("failed to export {conversation_id}"))?;
+        let validation_writer = match validation {
+            Some((ref mut writer, fraction)) if is_validation(&conversation_id, fraction) => {
+                Some(writer)
+            }
+            _ => None,
+        };
+        let split = match validation_writer {
+            Some(_) => {
+                report.validation_conversations += 1;
+                report.validation_records += records.len();
+                Split::Validation
+            }
+            None => {
+                report.conversations += 1;
+                report.records += records.len();
+                Split::Train
+            }
+        };
+        let out: &mut W = match validation_writer {
+            Some(validation_writer) => validation_writer,
+            None => &mut *writer,
+        };
+        for (index, record) in records.iter().enumerate() {
+            let hash = write_record(out, record)?;
+            report.manifest_samples.push(ManifestSample {
+                conversation_id: conversation_id.clone(),
+                source_id: record.source_id.clone(),
+                index,
+                hash,
+                split,
+            });
+        }
+        report.manifest_conversations.push(ManifestConversation {
+            id: conversation_id.clone(),
+            hash: manifest::conversation_hash(&conversation)?,
+            split,
+        });
+        report
+            .truncations
+            .extend(records.into_iter().filter_map(|r| r.truncation));
+    }
+    Ok(report)
+}
+
+/// Writes the manifest for an export next to the first output and returns its path
+pub fn write_manifest(
+    exporter: &dyn Exporter,
+    options: &TrainingDataOptions,
+    validation_fraction: f64,
+    outputs: Vec<String>,
+    report: &ExportReport,
+) -> Result<String> {
+    let path = manifest::manifest_path(outputs.first().map(|o| o.as_str()).unwrap_or("export"));
+    let mut manifest = Manifest::new(exporter.name(), options, validation_fraction);
+    manifest.outputs = outputs;
+    manifest.conversations = report.manifest_conversations.clone();
+    manifest.samples = report.manifest_samples.clone();
+    manifest.write(&path)?;
+    Ok(path)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+/// Jake is the assistant and Zack the user. Docker output and task reports are things the
+/// assistant is shown so they're user turns too, prefixed with who they're from.
+pub fn role(user: &User) -> Role {
+    match user {
+        User::Jake => Role::Assistant,
+        User::Zack | User::Docker | User::TaskReport { .. } => Role::User,
+        User::System => Role::System,
+    }
+}
+
+struct Turn {
+    role: Role,
+    content: String,
+}
+
+fn meta_text(meta: &[MetadataPromptTemplateEntry]) -> String {
+    meta.iter()
+        .map(|e| format!("{}:\n{}", e.key, e.value))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// The history of a jake message as turns followed by the response. The meta block
+/// becomes a leading system turn and omission notes from the token budget are system turns.
+/// Chat formats expect the roles to alternate so consecutive messages of the same role
+/// (Zack and then Docker output for example) are merged into one turn.
+fn turns(conversation: &Conversation, data: &PromptTemplateData) -> (Vec<Turn>, String) {
+    let mut turns = vec![Turn {
+        role: Role::System,
+        content: meta_text(&data.meta),
+    }];
+    for entry in &data.msgs {
+        let msg = entry
+            .id
+            .as_ref()
+            .and_then(|id| conversation.messages.iter().find(|m| &m.id == id));
+        let turn = match msg {
+            Some(msg) => {
+                let role = role(&msg.user);
+                let content = match msg.user {
+                    User::Zack | User::Jake | User::System => msg.msg.clone(),
+                    _ => format!("{}:\n{}", msg.user, msg.msg),
+                };
+                Turn { role, content }
+            }
+            None => Turn {
+                role: Role::System,
+                content: entry.value.trim().to_string(),
+            },
+        };
+        match turns.last_mut() {
+            Some(last) if last.role == turn.role => {
+                if !last.content.is_empty() {
+                    last.content.push_str("\n\n");
+                }
+                last.content.push_str(&turn.content);
+            }
+            _ => turns.push(turn),
+        }
+    }
+    (turns, data.response.clone())
+}
+
+/// Calls `build` with the (budgeted) prompt data of every training message
+fn per_message(
+    conversation: &Conversation,
+    options: &TrainingDataOptions,
+    build: impl Fn(&PromptTemplateData) -> Result<Value>,
+) -> Result<Vec<ExportRecord>> {
+    let conversation_id = conversation.id.clone().unwrap_or_default();
+    let mut records = Vec::new();
+    for msg in conversation.training_messages(options) {
+        let (data, truncation) = conversation.msg_prompt_data(&msg.id, options.token_budget)?;
+        records.push(ExportRecord {
+            conversation_id: conversation_id.clone(),
+            source_id: Some(msg.id.clone()),
+            value: build(&data)?,
+            truncation,
+        });
+    }
+    Ok(records)
+}
+
+/// The prompt up to where the response starts
+fn prompt_without_response(data: &PromptTemplateData) -> Result<String> {
+    let mut data = data.clone();
+    data.response = String::new();
+    templates::prompt(&data)
+}
+
+/// `{"text": ...}` rendered through prompt.template, what axolotl's `completion` type reads
+pub struct Completion;
+impl Exporter for Completion {
+    fn name(&self) -> &'static str {
+        "completion"
+    }
+    fn description(&self) -> &'static str {
+        "prompt.template rendered into {\"text\"}"
+    }
+    fn default_path(&self) -> String {
+        // what core/mistralif.yml trains on
+        "data.jsonl".to_string()
+    }
+    fn records(
+        &self,
+        conversation: &Conversation,
+        options: &TrainingDataOptions,
+    ) -> Result<Vec<ExportRecord>> {
+        let conversation_id = conversation.id.clone().unwrap_or_default();
+        Ok(conversation
+            .to_training_data(options)?
+            .into_iter()
+            .map(|sample| ExportRecord {
+                conversation_id: conversation_id.clone(),
+                source_id: sample.message_id,
+                value: json!({ "text": sample.text }),
+                truncation: sample.truncation,
+            })
+            .collect())
+    }
+}
+
+pub struct ShareGpt;
+impl Exporter for ShareGpt {
+    fn name(&self) -> &'static str {
+        "sharegpt"
+    }
+    fn description(&self) -> &'static str {
+        "{\"conversations\": [{\"from\": \"human\"|\"gpt\"|\"system\", \"value\"}]}"
+    }
+    fn records(
+        &self,
+        conversation: &Conversation,
+        options: &TrainingDataOptions,
+    ) -> Result<Vec<ExportRecord>> {
+        per_message(conversation, options, |data| {
+            let (turns, response) = turns(conversation, data);
+            let from = |role: Role| match role {
+                Role::System => "system",
+                Role::User => "human",
+                Role::Assistant => "gpt",
+            };
+            let mut messages: Vec<Value> = turns
+                .iter()
+                .map(|t| json!({ "from": from(t.role), "value": t.content }))
+                .collect();
+            messages.push(json!({ "from": "gpt", "value": response }));
+            Ok(json!({ "conversations": messages }))
+        })
+    }
+}
+
+pub struct ChatMl;
+impl Exporter for ChatMl {
+    fn name(&self) -> &'static str {
+        "chatml"
+    }
+    fn description(&self) -> &'static str {
+        "{\"messages\": [{\"role\": \"system\"|\"user\"|\"assistant\", \"content\"}]}"
+    }
+    fn records(
+        &self,
+        conversation: &Conversation,
+        options: &TrainingDataOptions,
+    ) -> Result<Vec<ExportRecord>> {
+        per_message(conversation, options, |data| {
+            let (turns, response) = turns(conversation, data);
+            let role = |role: Role| match role {
+                Role::System => "system",
+                Role::User => "user",
+                Role::Assistant => "assistant",
+            };
+            let mut messages: Vec<Value> = turns
+                .iter()
+                .map(|t| json!({ "role": role(t.role), "content": t.content }))
+                .collect();
+            messages.push(json!({ "role": "assistant", "content": response }));
+            Ok(json!({ "messages": messages }))
+        })
+    }
+}
+
+pub struct Alpaca;
+impl Exporter for Alpaca {
+    fn name(&self) -> &'static str {
+        "alpaca"
+    }
+    fn description(&self) -> &'static str {
+        "{\"instruction\", \"input\", \"output\"} with the rendered history as the instruction"
+    }
+    fn records(
+        &self,
+        conversation: &Conversation,
+        options: &TrainingDataOptions,
+    ) -> Result<Vec<ExportRecord>> {
+        per_message(conversation, options, |data| {
+            Ok(json!({
+                "instruction": prompt_without_response(data)?,
+                "input": "",
+                "output": data.response,
+            }))
+        })
+    }
+}
+
+/// axolotl's `input_output` format. Only the response segment is labeled so nothing
+/// but jake's reply is trained on when `train_on_inputs: false`
+pub struct InputOutput;
+impl Exporter for InputOutput {
+    fn name(&self) -> &'static str {
+        "input_output"
+    }
+    fn description(&self) -> &'static str {
+        "{\"segments\": [{\"label\", \"text\"}]} with only the response labeled"
+    }
+    fn records(
+        &self,
+        conversation: &Conversation,
+        options: &TrainingDataOptions,
+    ) -> Result<Vec<ExportRecord>> {
+        per_message(conversation, options, |data| {
+            let prompt = prompt_without_response(data)?;
+            let full = templates::prompt(data)?;
+            // the template may put something after the response, keep that unlabeled too
+            let response_end = prompt.len() + data.response.len();
+            let (response, rest) = match full.get(prompt.len()..response_end) {
+                Some(response) if full.starts_with(&prompt) => {
+                    (response.to_string(), full[response_end..].to_string())
+                }
+                _ => (data.response.clone(), String::new()),
+            };
+            let mut segments = vec![
+                json!({ "label": false, "text": prompt }),
+                json!({ "label": true, "text": response }),
+            ];
+            if !rest.is_empty() {
+                segments.push(json!({ "label": false, "text": rest }));
+            }
+            Ok(json!({ "segments": segments }))
+        })
+    }
+}
+
+/// Liked / disliked response pairs for DPO
+pub struct Dpo;
+impl Exporter for Dpo {
+    fn name(&self) -> &'static str {
+        "dpo"
+    }
+    fn description(&self) -> &'static str {
+        "{\"prompt\", \"chosen\", \"rejected\"} from liked and disliked alternatives"
+    }
+    fn records(
+        &self,
+        conversation: &Conversation,
+        _options: &TrainingDataOptions,
+    ) -> Result<Vec<ExportRecord>> {
+        let conversation_id = conversation.id.clone().unwrap_or_default();
+        conversation
+            .preference_pairs()?
+            .into_iter()
+            .map(|pair| {
+                Ok(ExportRecord {
+                    conversation_id: conversation_id.clone(),
+                    source_id: None,
+                    value: serde_json::to_value(pair)?,
+                    truncation: None,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversation::Message;
+
+    #[test]
+    fn test_exporter_names_are_unique() {
+        let names: Vec<&str> = exporters().iter().map(|e| e.name()).collect();
+        for name in &names {
+            assert_eq!(names.iter().filter(|n| n == &name).count(), 1);
+            assert!(exporter(name).is_ok());
+        }
+        assert!(exporter("nope").is_err());
+    }
+
+    #[test]
+    fn test_validation_split() {
+        // known FNV-1a 64 values so the split can never silently change
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+
+        assert!(!is_validation("anything", 0.0));
+        assert!(is_validation("anything", 1.0));
+        let ids: Vec<String> = (0..1000).map(|i| format!("conversation-{i}")).collect();
+        let validation = ids.iter().filter(|id| is_validation(id, 0.1)).count();
+        assert!(validation > 50 && validation < 150, "{validation}");
+        // growing the fraction only ever moves conversations into validation
+        for id in &ids {
+            if is_validation(id, 0.1) {
+                assert!(is_validation(id, 0.2));
+            }
+        }
+    }
+
+    #[test]
+    fn test_turn_roles() {
+        let mut conversation = Conversation::default();
+        let zack = Message::new_with_msg(User::Zack, "ls".into());
+        let mut docker = Message::new_with_msg(User::Docker, "file.txt".into());
+        docker.parent = Some(zack.id.clone());
+        let mut jake = Message::new_with_msg(User::Jake, "[<cat file.txt>]".into());
+        jake.parent = Some(docker.id.clone());
+        let mut again = Message::new_with_msg(User::Jake, "[<wc file.txt>]".into());
+        again.parent = Some(jake.id.clone());
+        conversation.messages = vec![zack.clone(), docker.clone(), jake.clone(), again.clone()];
+
+        let data = PromptTemplateData {
+            msgs: conversation
+                .messages
+                .iter()
+                .map(|m| m.to_prompt_template().unwrap())
+                .collect(),
+            response: "there's one file".into(),
+            ..Default::default()
+        };
+        let (turns, response) = turns(&conversation, &data);
+        assert_eq!(turns.len(), 3);
+        assert_eq!(turns[0].role, Role::System);
+        // the docker output is shown to jake the same way zack's messages are
+        assert_eq!(turns[1].role, Role::User);
+        assert_eq!(turns[1].content, "ls\n\nDocker:\nfile.txt");
+        assert_eq!(turns[2].role, Role::Assistant);
+        assert_eq!(turns[2].content, "[<cat file.txt>]\n\n[<wc file.txt>]");
+        assert_eq!(response, "there's one file");
+    }
+}
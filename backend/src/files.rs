@@ -0,0 +1,263 @@
+use anyhow::{anyhow, bail, Result};
+
+pub const HOME: &str = "/home/jake";
+
+/// A path Jake gave, made relative to the home directory. `notes.txt`,
+/// `~/notes.txt` and `/home/jake/notes.txt` are all the same file, anything that ends
+/// up outside of the home directory is refused
+pub fn home_path(path: &str) -> Result<String> {
+    let relative = if path == "~" || path == HOME {
+        ""
+    } else if let Some(rest) = path.strip_prefix("~/") {
+        rest
+    } else if let Some(rest) = path.strip_prefix(&format!("{HOME}/")) {
+        rest
+    } else if path.starts_with('/') || path.starts_with('~') {
+        bail!("{path} is outside of {HOME}, only files in there can be used");
+    } else {
+        path
+    };
+    let mut parts: Vec<&str> = Vec::new();
+    for part in relative.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop().ok_or(anyhow!(
+                    "{path} is outside of {HOME}, only files in there can be used"
+                ))?;
+            }
+            part => parts.push(part),
+        }
+    }
+    if parts.is_empty() {
+        bail!("{path} is the home directory, not a file");
+    }
+    Ok(parts.join("/"))
+}
+
+const SEARCH: &str = "<<<<<<< SEARCH";
+const DIVIDER: &str = "=======";
+const REPLACE: &str = ">>>>>>> REPLACE";
+
+/// Applies `edit` to a file's contents. The edit is either search/replace blocks
+///
+/// ```text
+/// <<<<<<< SEARCH
+/// old lines
+/// =======
+/// new lines
+/// >>>>>>> REPLACE
+/// ```
+///
+/// where every search has to match exactly once, or a unified diff
+pub fn apply_edit(original: &str, edit: &str) -> Result<String> {
+    if edit.lines().any(|l| l.trim_end() == SEARCH) {
+        apply_search_replace(original, edit)
+    } else if edit.lines().any(|l| l.starts_with("@@")) {
+        apply_unified_diff(original, edit)
+    } else {
+        bail!("the edit has to be {SEARCH} / {DIVIDER} / {REPLACE} blocks or a unified diff")
+    }
+}
+
+fn apply_search_replace(original: &str, edit: &str) -> Result<String> {
+    enum State {
+        Outside,
+        Search(Vec<String>),
+        Replace(Vec<String>, Vec<String>),
+    }
+    let mut blocks = Vec::new();
+    let mut state = State::Outside;
+    for line in edit.lines() {
+        let marker = line.trim_end();
+        state = match state {
+            State::Outside if marker == SEARCH => State::Search(Vec::new()),
+            // anything around the blocks is ignored
+            State::Outside => State::Outside,
+            State::Search(search) if marker == DIVIDER => State::Replace(search, Vec::new()),
+            State::Search(mut search) => {
+                search.push(line.to_string());
+                State::Search(search)
+            }
+            State::Replace(search, replace) if marker == REPLACE => {
+                blocks.push((search.join("\n"), replace.join("\n")));
+                State::Outside
+            }
+            State::Replace(search, mut replace) => {
+                replace.push(line.to_string());
+                State::Replace(search, replace)
+            }
+        };
+    }
+    if !matches!(state, State::Outside) {
+        bail!("the last block isn't finished, every {SEARCH} needs a {DIVIDER} and a {REPLACE}");
+    }
+
+    let mut text = original.to_string();
+    for (i, (search, replace)) in blocks.iter().enumerate() {
+        let n = i + 1;
+        if search.is_empty() {
+            // only a file with nothing in it can be matched by nothing
+            if !text.is_empty() {
+                bail!("block {n} has an empty search and the file isn't empty");
+            }
+            text = format!("{replace}\n");
+            continue;
+        }
+        match text.matches(search.as_str()).count() {
+            0 => bail!("block {n}: the search text isn't in the file"),
+            1 => text = text.replacen(search.as_str(), replace, 1),
+            count => bail!(
+                "block {n}: the search text is in the file {count} times, include more lines so it only matches once"
+            ),
+        }
+    }
+    Ok(text)
+}
+
+struct Hunk {
+    /// 0 based line the hunk says it starts at in the original
+    start: usize,
+    old: Vec<String>,
+    new: Vec<String>,
+}
+
+/// `@@ -12,3 +12,4 @@` -> 11
+fn hunk_start(header: &str) -> Result<usize> {
+    let old = header
+        .split_whitespace()
+        .find_map(|part| part.strip_prefix('-'))
+        .ok_or(anyhow!("bad hunk header {header}"))?;
+    let line: usize = old
+        .split(',')
+        .next()
+        .unwrap_or_default()
+        .parse()
+        .map_err(|_| anyhow!("bad hunk header {header}"))?;
+    Ok(line.saturating_sub(1))
+}
+
+/// The line counts in the hunk headers aren't trusted, a hunk goes until the next one
+fn parse_hunks(diff: &str) -> Result<Vec<Hunk>> {
+    let mut hunks: Vec<Hunk> = Vec::new();
+    for line in diff.lines() {
+        if line.starts_with("@@") {
+            hunks.push(Hunk {
+                start: hunk_start(line)?,
+                old: Vec::new(),
+                new: Vec::new(),
+            });
+            continue;
+        }
+        let Some(hunk) = hunks.last_mut() else {
+            // ---, +++, diff --git and whatever else comes before the first hunk
+            continue;
+        };
+        if line.starts_with("--- ") || line.starts_with("+++ ") || line.starts_with("diff ") {
+            continue;
+        }
+        if let Some(removed) = line.strip_prefix('-') {
+            hunk.old.push(removed.to_string());
+        } else if let Some(added) = line.strip_prefix('+') {
+            hunk.new.push(added.to_string());
+        } else if line.starts_with('\\') {
+            // \ No newline at end of file
+        } else {
+            // blank context lines often lose their leading space
+            let context = line.strip_prefix(' ').unwrap_or(line);
+            hunk.old.push(context.to_string());
+            hunk.new.push(context.to_string());
+        }
+    }
+    Ok(hunks)
+}
+
+fn apply_unified_diff(original: &str, diff: &str) -> Result<String> {
+    let mut lines: Vec<String> = original.lines().map(String::from).collect();
+    // how far the hunks applied so far moved things, and where the next one can start
+    let mut shift: isize = 0;
+    let mut min_pos = 0;
+    for (i, hunk) in parse_hunks(diff)?.into_iter().enumerate() {
+        let n = i + 1;
+        let expected = (hunk.start as isize + shift).max(min_pos as isize) as usize;
+        let pos = if hunk.old.is_empty() {
+            expected.min(lines.len())
+        } else {
+            // where the hunk says it goes, or the closest place its lines are at
+            (min_pos..lines.len())
+                .filter(|&p| lines[p..].starts_with(&hunk.old))
+                .min_by_key(|&p| p.abs_diff(expected))
+                .ok_or(anyhow!(
+                    "hunk {n} doesn't match the file, the context and - lines have to be what is there now"
+                ))?
+        };
+        shift += hunk.new.len() as isize - hunk.old.len() as isize;
+        min_pos = pos + hunk.new.len();
+        lines.splice(pos..pos + hunk.old.len(), hunk.new);
+    }
+    let mut text = lines.join("\n");
+    if !text.is_empty() && (original.ends_with('\n') || original.is_empty()) {
+        text.push('\n');
+    }
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_home_path() {
+        assert_eq!(home_path("notes.txt").unwrap(), "notes.txt");
+        assert_eq!(home_path("~/a/./b/../c.txt").unwrap(), "a/c.txt");
+        assert_eq!(home_path("/home/jake/a//b").unwrap(), "a/b");
+        assert!(home_path("../zack/secrets").is_err());
+        assert!(home_path("a/../../etc/passwd").is_err());
+        assert!(home_path("/etc/passwd").is_err());
+        assert!(home_path("~zack/x").is_err());
+        assert!(home_path("/home/jakeover/x").is_err());
+        assert!(home_path("~").is_err());
+    }
+
+    #[test]
+    fn test_search_replace() {
+        let original = "fn main() {\n    println!(\"hi\");\n}\n";
+        let edit = "Changing the greeting\n<<<<<<< SEARCH\n    println!(\"hi\");\n=======\n    println!(\"hello\");\n    println!(\"world\");\n>>>>>>> REPLACE\n";
+        assert_eq!(
+            apply_edit(original, edit).unwrap(),
+            "fn main() {\n    println!(\"hello\");\n    println!(\"world\");\n}\n"
+        );
+
+        let twice = "a\nb\na\n";
+        let edit = "<<<<<<< SEARCH\na\n=======\nc\n>>>>>>> REPLACE";
+        assert!(apply_edit(twice, edit)
+            .unwrap_err()
+            .to_string()
+            .contains("2 times"));
+        let edit = "<<<<<<< SEARCH\nz\n=======\nc\n>>>>>>> REPLACE";
+        assert!(apply_edit(twice, edit).is_err());
+        let edit = "<<<<<<< SEARCH\na\n=======\nc\n";
+        assert!(apply_edit(twice, edit).is_err());
+        let edit = "<<<<<<< SEARCH\n=======\nnew file\n>>>>>>> REPLACE";
+        assert_eq!(apply_edit("", edit).unwrap(), "new file\n");
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let original = "one\ntwo\nthree\nfour\nfive\nsix\nseven\n";
+        let diff = "--- a/count.txt\n+++ b/count.txt\n@@ -1,3 +1,3 @@\n one\n-two\n+2\n three\n@@ -5,3 +5,4 @@\n five\n six\n+six and a half\n seven\n";
+        assert_eq!(
+            apply_edit(original, diff).unwrap(),
+            "one\n2\nthree\nfour\nfive\nsix\nsix and a half\nseven\n"
+        );
+        // wrong line numbers still find the right place
+        let diff = "@@ -40,2 +40,2 @@\n four\n-five\n+5\n";
+        assert_eq!(
+            apply_edit(original, diff).unwrap(),
+            "one\ntwo\nthree\nfour\n5\nsix\nseven\n"
+        );
+        let diff = "@@ -1,2 +1,2 @@\n one\n-2\n+two\n";
+        assert!(apply_edit(original, diff).is_err());
+        assert!(apply_edit(original, "just some text").is_err());
+    }
+}
@@ -1,20 +1,36 @@
 use std::{
     fs::File,
-    sync::Arc,
+    sync::mpsc,
     time::{Duration, SystemTime},
 };
 
+use anyhow::{anyhow, bail, Context};
 use eframe::{egui, HardwareAcceleration};
-use egui::{Ui, Widget, WidgetInfo, TextStyle, Style};
+use egui::{Style, TextStyle, Ui, Widget, WidgetInfo};
 
 use crate::{
-    conversation::{Conversation, ConversationAction, Conversations, Message, Metadata, User},
-    model_server::{GenerationConfig, InferReq, InferenceServerArgs, ServerManager, ServerStatus},
-    nexos::{extract_commands, LogLine, NexosInstance},
+    agent::{self, AgentConfig, AgentStop, Turn},
+    conversation::{
+        parse_tags, BranchExport, Conversation, ConversationAction, Evaluation, Feedback, Message,
+        Rating, TrainingDataOptions, User,
+    },
+    export::{self, Exporter},
+    model_server::{
+        GenerationConfig, InferEvent, InferReq, InferResp, InferenceConfig, InferenceServerArgs,
+        ServerManager, ServerStatus, TokenStream,
+    },
+    nexos::{self, CommandResult, LimitHit},
+    search::{DocKind, IndexedStore, SearchHit},
+    snapshot,
+    store::ConversationStore,
+    token::{self, DEFAULT_TOKEN_BUDGET},
 };
-pub fn launch_gui(db: String) -> anyhow::Result<()> {
-    let db = jammdb::DB::open(db).unwrap();
-    let mut conversations = Conversations::new(Arc::new(db), None).unwrap();
+pub fn launch_gui(
+    conversations: Box<dyn ConversationStore>,
+    inference: InferenceConfig,
+    agent: AgentConfig,
+) -> anyhow::Result<()> {
+    conversations.check_schema()?;
     let options = eframe::NativeOptions {
         // initial_window_size: Some(egui::vec2(300.0, 240.0)),
         // hardware_acceleration: HardwareAcceleration::,
@@ -27,29 +43,396 @@ pub fn launch_gui(db: String) -> anyhow::Result<()> {
             // This gives us image support:
             // egui_extras::install_image_loaders(&cc.egui_ctx);
 
-            Box::new(MyApp::new(conversations))
+            Box::new(MyApp::new(conversations, inference, agent))
         }),
     );
     Ok(())
 }
 struct MyApp {
-    conversations: Conversations,
+    conversations: IndexedStore,
     selected_convo: Option<String>,
     server_manager: ServerManager,
+    training_data_options: TrainingDataOptions,
+    search_query: String,
+    /// What `search_query` found when it was last changed
+    search_hits: Vec<SearchHit>,
+    /// The tags box of the selected conversation as typed, (conversation id, text)
+    tags_edit: Option<(String, String)>,
+    /// Comma separated tags the export is limited to
+    export_tags: String,
+    export_report: Option<String>,
+    /// Name of the exporter the export button uses
+    export_format: String,
+    /// Message to scroll to the next time the conversation is drawn
+    scroll_to: Option<String>,
+    /// Snapshot the next "diff to" compares against
+    snapshot_base: Option<String>,
+    /// Output of the last snapshot restore or diff
+    snapshot_report: Option<String>,
+    /// The generation being streamed into a conversation, at most one at a time
+    generation: Option<Generation>,
+    /// How the last generation ended
+    last_generation: Option<Result<InferResp, String>>,
+    /// The commands being run, at most one message at a time
+    eval: Option<Eval>,
+    /// Why the last eval failed
+    eval_error: Option<String>,
+    agent_config: AgentConfig,
+    /// The agent running on a conversation, at most one at a time
+    agent: Option<AgentRun>,
+    /// Why the agent stopped last time
+    agent_report: Option<String>,
+}
+
+struct AgentRun {
+    convo_id: String,
+    /// Responses generated so far
+    steps: usize,
+    phase: AgentPhase,
+}
+
+/// The agent moves on by one of these a frame
+#[derive(Clone, Debug)]
+enum AgentPhase {
+    /// Time to generate the next response
+    Respond,
+    /// The response is being streamed into this message
+    Generating(String),
+    /// Waiting for the commands in this message to be approved, one flag per command
+    Approval(String, Vec<bool>),
+    /// Run the commands in this message except the ones at these indices
+    Run(String, Vec<usize>),
+    /// The commands in this message are running, without the ones at these indices
+    Evaluating(String, Vec<usize>),
+}
+
+/// Where the tokens of a generation go
+enum GenerationTarget {
+    Message(String),
+    Summary,
+}
+
+struct Generation {
+    convo_id: String,
+    target: GenerationTarget,
+    tokens: TokenStream,
+}
+
+struct Eval {
+    convo_id: String,
+    message_id: String,
+    result: mpsc::Receiver<anyhow::Result<Evaluation>>,
+}
+
+impl Eval {
+    /// Runs the commands of the message on a worker thread, they take as long as they take
+    /// and the window has to keep drawing meanwhile. The commands at the indices in `skip`
+    /// aren't run
+    fn start(
+        conversation: Conversation,
+        convo_id: &str,
+        message_id: &str,
+        skip: Vec<usize>,
+    ) -> Self {
+        let (tx, result) = mpsc::channel();
+        let id = message_id.to_string();
+        std::thread::spawn(move || {
+            // nobody is waiting for it anymore if the window was closed
+            let _ = tx.send(conversation.evaluate(&id, &skip));
+        });
+        Self {
+            convo_id: convo_id.to_string(),
+            message_id: message_id.to_string(),
+            result,
+        }
+    }
 }
 
 impl MyApp {
-    fn new(conversations: Conversations) -> Self {
+    fn new(
+        conversations: Box<dyn ConversationStore>,
+        inference: InferenceConfig,
+        agent_config: AgentConfig,
+    ) -> Self {
         Self {
-            conversations,
+            conversations: IndexedStore::new(conversations),
             selected_convo: None,
-            server_manager: ServerManager::default(),
+            server_manager: ServerManager::new(inference),
+            training_data_options: TrainingDataOptions::default(),
+            search_query: String::new(),
+            search_hits: Vec::new(),
+            tags_edit: None,
+            export_tags: String::new(),
+            export_report: None,
+            export_format: export::Completion.name().to_string(),
+            scroll_to: None,
+            snapshot_base: None,
+            snapshot_report: None,
+            generation: None,
+            last_generation: None,
+            eval: None,
+            eval_error: None,
+            agent_config,
+            agent: None,
+            agent_report: None,
+        }
+    }
+}
+impl MyApp {
+    fn export(&self) -> anyhow::Result<export::ExportReport> {
+        let exporter = export::exporter(&self.export_format)?;
+        let path = exporter.default_path();
+        let mut file = File::create(&path)?;
+        let conversations = self.conversations.iter().into_iter().map(|(_, c)| c);
+        let report = export::export(
+            exporter.as_ref(),
+            conversations,
+            &self.training_data_options,
+            &mut file,
+            None,
+        )?;
+        println!("wrote {path}");
+        let manifest = export::write_manifest(
+            exporter.as_ref(),
+            &self.training_data_options,
+            0.0,
+            vec![path],
+            &report,
+        )?;
+        println!("wrote {manifest}");
+        Ok(report)
+    }
+    /// Puts the tokens that came in since the last frame where the generation is going.
+    /// Every frame is one edit, the history merges them into one. When it is done the
+    /// text becomes the final completion, which can be shorter than what was streamed
+    /// if it ran into a stop sequence
+    fn poll_generation(&mut self) {
+        let Some(ref generation) = self.generation else {
+            return;
+        };
+        let mut text = String::new();
+        let mut completion = None;
+        let mut finished = false;
+        loop {
+            match generation.tokens.try_recv() {
+                Ok(InferEvent::Token { text: token }) => text.push_str(&token),
+                Ok(InferEvent::Done(resp)) => {
+                    completion = Some(resp.completion.clone());
+                    self.last_generation = Some(Ok(resp));
+                    finished = true;
+                }
+                Ok(InferEvent::Failed { error }) => {
+                    self.last_generation = Some(Err(error));
+                    finished = true;
+                }
+                Err(std::sync::mpsc::TryRecvError::Empty) => break,
+                Err(std::sync::mpsc::TryRecvError::Disconnected) => {
+                    finished = true;
+                    break;
+                }
+            }
+        }
+        if !text.is_empty() || finished {
+            let convo_id = generation.convo_id.clone();
+            let action = match self.conversations.get(&convo_id) {
+                Ok(Some(conversation)) => match generation.target {
+                    GenerationTarget::Message(ref id) => {
+                        conversation.messages.iter().find(|m| &m.id == id).map(|m| {
+                            let mut new_message = m.clone();
+                            match completion {
+                                Some(ref completion) => new_message.msg = completion.clone(),
+                                None => new_message.msg.push_str(&text),
+                            }
+                            ConversationAction::MutateMessage { new_message }
+                        })
+                    }
+                    GenerationTarget::Summary => {
+                        let mut summary = match completion {
+                            Some(ref completion) => completion.clone(),
+                            None => conversation.summary.clone() + &text,
+                        };
+                        if finished {
+                            summary = summary.trim().to_string();
+                        }
+                        Some(ConversationAction::SetSummary { summary })
+                    }
+                },
+                _ => None,
+            };
+            match action {
+                Some(action) => {
+                    if let Err(e) = self.conversations.apply(&convo_id, action) {
+                        println!("failed to apply generated text {e}")
+                    }
+                }
+                // deleted while it was generating
+                None => finished = true,
+            }
+        }
+        if finished {
+            self.generation = None;
+        }
+    }
+    /// Records the eval once the worker is done. The results go on top of whatever was
+    /// changed in the meantime
+    fn poll_eval(&mut self) {
+        let Some(eval) = self.eval.take() else {
+            return;
+        };
+        let evaluation = match eval.result.try_recv() {
+            Ok(evaluation) => evaluation,
+            Err(mpsc::TryRecvError::Empty) => {
+                self.eval = Some(eval);
+                return;
+            }
+            Err(mpsc::TryRecvError::Disconnected) => Err(anyhow!("the eval thread died")),
+        };
+        let res = evaluation.and_then(|evaluation| {
+            let mut conversation = self
+                .conversations
+                .get(&eval.convo_id)?
+                .context("the conversation is gone")?;
+            conversation.add_evaluation(evaluation)?;
+            self.conversations.record(
+                &eval.convo_id,
+                ConversationAction::EvalMessage {
+                    id: eval.message_id,
+                },
+                conversation,
+            )
+        });
+        if let Err(e) = res {
+            self.eval_error = Some(format!("{e:#}"));
+        }
+    }
+    fn step_agent(&mut self) {
+        let Some(mut run) = self.agent.take() else {
+            return;
+        };
+        match self.advance_agent(&mut run) {
+            Ok(None) => self.agent = Some(run),
+            Ok(Some(stop)) => self.agent_report = Some(stop.to_string()),
+            Err(e) => self.agent_report = Some(format!("failed {e:#}")),
+        }
+    }
+    /// Some when the agent is done
+    fn advance_agent(&mut self, run: &mut AgentRun) -> anyhow::Result<Option<AgentStop>> {
+        match run.phase.clone() {
+            AgentPhase::Respond => {
+                // something else is being generated, wait for it
+                if self.generation.is_some() {
+                    return Ok(None);
+                }
+                if run.steps >= self.agent_config.agent_max_steps {
+                    return Ok(Some(AgentStop::StepBudget(run.steps)));
+                }
+                let backend = self
+                    .server_manager
+                    .inference_server
+                    .clone()
+                    .context("start the model first")?;
+                let (message, req) = agent::start_response(&mut self.conversations, &run.convo_id)?;
+                let tokens = backend.lock().unwrap().infer(req)?;
+                self.last_generation = None;
+                self.generation = Some(Generation {
+                    convo_id: run.convo_id.clone(),
+                    target: GenerationTarget::Message(message.id.clone()),
+                    tokens,
+                });
+                run.steps += 1;
+                run.phase = AgentPhase::Generating(message.id);
+            }
+            AgentPhase::Generating(id) => {
+                if self.generation.is_some() {
+                    return Ok(None);
+                }
+                if let Some(Err(ref error)) = self.last_generation {
+                    bail!("generation failed {error}");
+                }
+                let conversation = self
+                    .conversations
+                    .get(&run.convo_id)?
+                    .context("the conversation is gone")?;
+                let message = conversation
+                    .messages
+                    .iter()
+                    .find(|m| m.id == id)
+                    .context("the response is gone")?;
+                let turn = Turn::of(&message.msg);
+                if turn.is_empty() {
+                    return Ok(Some(AgentStop::NoCommands));
+                }
+                run.phase = if self.agent_config.agent_approve && turn.needs_approval() {
+                    AgentPhase::Approval(id, vec![true; turn.commands.len()])
+                } else {
+                    AgentPhase::Run(id, Vec::new())
+                };
+            }
+            AgentPhase::Approval(..) => {}
+            AgentPhase::Run(id, skip) => {
+                // an eval started by hand has to finish first
+                if self.eval.is_some() {
+                    return Ok(None);
+                }
+                let conversation = self
+                    .conversations
+                    .get(&run.convo_id)?
+                    .context("the conversation is gone")?;
+                self.eval_error = None;
+                self.eval = Some(Eval::start(conversation, &run.convo_id, &id, skip.clone()));
+                run.phase = AgentPhase::Evaluating(id, skip);
+            }
+            AgentPhase::Evaluating(id, skip) => {
+                if self.eval.is_some() {
+                    return Ok(None);
+                }
+                if let Some(ref error) = self.eval_error {
+                    bail!("eval failed {error}");
+                }
+                let conversation = self
+                    .conversations
+                    .get(&run.convo_id)?
+                    .context("the conversation is gone")?;
+                let message = conversation
+                    .messages
+                    .iter()
+                    .find(|m| m.id == id)
+                    .context("the response is gone")?;
+                if Turn::of(&message.msg).without(&skip).ends() {
+                    return Ok(Some(AgentStop::Ended));
+                }
+                run.phase = AgentPhase::Respond;
+            }
+        }
+        Ok(None)
+    }
+    /// Selects the conversation and makes sure the message is on the active branch
+    fn jump_to(&mut self, convo_id: &str, id: &str) {
+        self.selected_convo = Some(convo_id.to_string());
+        let Ok(Some(conversation)) = self.conversations.get(convo_id) else {
+            return;
+        };
+        let is_message = conversation.messages.iter().any(|m| m.id == id);
+        let is_active = conversation.active_messages().iter().any(|m| m.id == id);
+        if is_message && !is_active {
+            // just looking around, nothing to undo
+            let res = self.conversations.apply_unrecorded(
+                convo_id,
+                ConversationAction::SwitchBranch { id: id.to_string() },
+            );
+            if let Err(e) = res {
+                println!("failed to switch to the matching branch {e}")
+            }
         }
+        self.scroll_to = Some(id.to_string());
     }
 }
 impl eframe::App for MyApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         ctx.request_repaint_after(Duration::from_millis(50));
+        self.poll_generation();
+        self.poll_eval();
+        self.step_agent();
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.heading("Jake");
             let mut style = Style::default();
@@ -72,12 +455,38 @@ impl eframe::App for MyApp {
                             }
                         }
                     }
+                    let search = ui.add(
+                        egui::TextEdit::singleline(&mut self.search_query)
+                            .hint_text("search, user:docker \"a phrase\""),
+                    );
+                    if search.changed() {
+                        self.search_hits = self.conversations.search(&self.search_query);
+                    }
+                    if !self.search_query.trim().is_empty() {
+                        ui.label(format!("{} matches", self.search_hits.len()));
+                        let mut jump = None;
+                        egui::ScrollArea::vertical()
+                            .id_source("search_hits")
+                            .max_height(300.0)
+                            .show(ui, |ui| {
+                                for hit in &self.search_hits {
+                                    let label = match hit.kind {
+                                        DocKind::Message { ref user } => user.to_string(),
+                                        DocKind::InjectedFile { ref filename } => filename.clone(),
+                                        DocKind::Task => "task".to_string(),
+                                    };
+                                    if ui.button(format!("{label}: {}", hit.snippet)).clicked() {
+                                        jump = Some((hit.conversation_id.clone(), hit.id.clone()));
+                                    }
+                                }
+                            });
+                        if let Some((convo_id, id)) = jump {
+                            self.jump_to(&convo_id, &id);
+                        }
+                        ui.separator();
+                    }
                     egui::ScrollArea::vertical().show(ui, |ui| {
-                        let mut conversations = self
-                            .conversations
-                            .clone()
-                            .into_iter()
-                            .collect::<Vec<(String, Conversation)>>();
+                        let mut conversations = self.conversations.iter();
 
                         conversations.sort_by(|(_, b), (_, a)| {
                             a.time
@@ -85,8 +494,12 @@ impl eframe::App for MyApp {
                                 .unwrap_or(std::cmp::Ordering::Equal)
                         });
 
-                        for (u, _) in conversations {
-                            if ui.button(u.clone()).clicked() {
+                        for (u, c) in conversations {
+                            let mut label = c.display_name();
+                            if !c.tags.is_empty() {
+                                label = format!("{label} [{}]", c.tags.join(", "));
+                            }
+                            if ui.button(label).on_hover_text(u.clone()).clicked() {
                                 self.selected_convo = Some(u.clone());
                             }
                         }
@@ -110,90 +523,214 @@ impl eframe::App for MyApp {
                                     if res.is_err() {
                                         println!("failed to delete a convo {:?}", res)
                                     }
+                                    nexos::close_session(convo_id);
                                     self.selected_convo = None;
                                     return;
                                 }
+                                ui.horizontal(|ui| {
+                                    if ui.button("undo").clicked() {
+                                        if let Err(e) = self.conversations.undo(convo_id) {
+                                            println!("failed to undo {e}")
+                                        }
+                                    }
+                                    if ui.button("redo").clicked() {
+                                        if let Err(e) = self.conversations.redo(convo_id) {
+                                            println!("failed to redo {e}")
+                                        }
+                                    }
+                                    if let Ok(Some(head)) =
+                                        self.conversations.history_head(convo_id)
+                                    {
+                                        ui.label(format!("revision {head}"));
+                                    }
+                                });
                                 let conversation = self.conversations.get(convo_id);
-                                if let Ok(Some(mut conversation)) = conversation {
-                                    let mut action: Option<ConversationAction> = None;
-                                    for (i, msg) in conversation.messages.iter().enumerate() {
-                                        let mut msg = msg.clone();
+                                if let Ok(Some(conversation)) = conversation {
+                                    let mut action: Option<ConversationAction> =
+                                        conversation_info_ui(
+                                            ui,
+                                            &conversation,
+                                            &mut self.tags_edit,
+                                            &self.server_manager,
+                                            &mut self.generation,
+                                        );
+                                    agent_ui(
+                                        ui,
+                                        &conversation,
+                                        &mut self.agent_config,
+                                        &mut self.agent,
+                                        &mut self.agent_report,
+                                    );
+                                    if let Some(ref error) = self.eval_error {
+                                        ui.colored_label(
+                                            egui::Color32::RED,
+                                            format!("eval failed {error}"),
+                                        );
+                                    }
+                                    let mut eval = None;
+                                    if let Some(ref report) = self.snapshot_report {
+                                        let mut close = false;
                                         ui.group(|ui| {
-                                            if ui.button("delete").clicked() {
-                                                action = Some(ConversationAction::DeleteMessage {
-                                                    id: msg.id.clone(),
+                                            egui::ScrollArea::vertical()
+                                                .id_source("snapshot_report")
+                                                .max_height(300.0)
+                                                .show(ui, |ui| {
+                                                    ui.label(report);
                                                 });
-                                            }
+                                            close = ui.button("close").clicked();
+                                        });
+                                        if close {
+                                            self.snapshot_report = None;
+                                        }
+                                    }
+                                    let active_messages = conversation.active_messages();
+                                    for (i, msg) in active_messages.iter().enumerate() {
+                                        let mut msg = msg.clone();
+                                        let group = ui.group(|ui| {
+                                            ui.horizontal(|ui| {
+                                                if ui.button("delete").clicked() {
+                                                    action =
+                                                        Some(ConversationAction::DeleteMessage {
+                                                            id: msg.id.clone(),
+                                                        });
+                                                }
+                                                let siblings = conversation.siblings(&msg.id);
+                                                if siblings.len() > 1 {
+                                                    let pos = siblings
+                                                        .iter()
+                                                        .position(|id| *id == msg.id)
+                                                        .unwrap_or(0);
+                                                    if ui.button("<").clicked() && pos > 0 {
+                                                        action = Some(
+                                                            ConversationAction::SwitchBranch {
+                                                                id: siblings[pos - 1].clone(),
+                                                            },
+                                                        );
+                                                    }
+                                                    ui.label(format!(
+                                                        "{}/{}",
+                                                        pos + 1,
+                                                        siblings.len()
+                                                    ));
+                                                    if ui.button(">").clicked()
+                                                        && pos + 1 < siblings.len()
+                                                    {
+                                                        action = Some(
+                                                            ConversationAction::SwitchBranch {
+                                                                id: siblings[pos + 1].clone(),
+                                                            },
+                                                        );
+                                                    }
+                                                    if ui.button("prune").clicked() {
+                                                        action =
+                                                            Some(ConversationAction::PruneBranch {
+                                                                id: msg.id.clone(),
+                                                            });
+                                                    }
+                                                }
+                                                if msg.user == User::Jake
+                                                    && ui.button("regen").clicked()
+                                                {
+                                                    action = Some(ConversationAction::Regenerate {
+                                                        id: msg.id.clone(),
+                                                    });
+                                                }
+                                            });
                                             let datetime: chrono::DateTime<chrono::offset::Utc> =
                                                 msg.time.clone().into();
                                             ui.label(datetime.format("%Y-%m-%d %T").to_string());
-                                            ui.label(format!("{}:", msg.user.to_string()));
+                                            ui.label(format!("{}:", msg.user));
+                                            if let Some(ref result) = msg.result {
+                                                command_result_ui(ui, result);
+                                            }
+                                            if let Some(ref snapshot) = msg.snapshot {
+                                                if let Some(report) = snapshot_ui(
+                                                    ui,
+                                                    convo_id,
+                                                    snapshot,
+                                                    &mut self.snapshot_base,
+                                                ) {
+                                                    self.snapshot_report = Some(report);
+                                                }
+                                            }
                                             let output = egui::TextEdit::multiline(&mut msg.msg)
                                                 .hint_text("Type something!")
                                                 .desired_width(1000.0)
                                                 .show(ui);
-                                            if ui.button("eval").clicked() {
-                                                action = Some(ConversationAction::EvalMessage {
-                                                    id: msg.id.clone(),
-                                                });
+                                            let evaluating = matches!(
+                                                self.eval,
+                                                Some(Eval { ref message_id, .. }) if message_id == &msg.id
+                                            );
+                                            if evaluating {
+                                                ui.spinner();
+                                            } else if self.eval.is_none()
+                                                && ui.button("eval").clicked()
+                                            {
+                                                eval = Some(msg.id.clone());
                                             }
                                             if msg.user == User::Jake {
                                                 ui.checkbox(
                                                     &mut msg.meta.exclude_from_training,
                                                     "exclude",
                                                 );
+                                                feedback_ui(ui, &mut msg);
                                                 if let Some(ref is) =
                                                     self.server_manager.inference_server
                                                 {
-                                                    ui.label("Inference server");
-                                                    let status =
-                                                        is.lock().unwrap().status().cloned();
-                                                    if status.is_err() {
-                                                        ui.label(format!(
-                                                            "Status error {:?}",
-                                                            status
-                                                        ));
-                                                        return;
-                                                    }
-                                                    let status = status.unwrap();
-                                                    if let ServerStatus::Ready { .. }
-                                                    | ServerStatus::DoneGenerating { .. } =
-                                                        status
+                                                    ui.label(is.lock().unwrap().name());
+                                                    let generating = matches!(
+                                                        self.generation,
+                                                        Some(Generation {
+                                                            target: GenerationTarget::Message(ref id),
+                                                            ..
+                                                        }) if id == &msg.id
+                                                    );
+                                                    if generating {
+                                                        ui.spinner();
+                                                    } else if self.generation.is_none()
+                                                        && ui.button("infer").clicked()
                                                     {
-                                                        if ui.button("infer").clicked() {
-                                                            println!(
-                                                                "{:?}",
-                                                                conversation.msg_training_data(i)
-                                                            );
-                                                            let prompt =
-                                                                conversation.msg_training_data(i);
-                                                            if let Ok(prompt) = prompt {
-                                                                let resp = is
-                                                                .lock()
-                                                                .unwrap()
-                                                                .infer(InferReq {
-                                                                    prompt,
-                                                                    config:
-                                                                        GenerationConfig::default(),
+                                                        let tokens = GenerationConfig::for_prompt()
+                                                            .and_then(|config| {
+                                                                let budget = token::prompt_budget(
+                                                                    config.max_new_tokens,
+                                                                );
+                                                                let prompt = conversation
+                                                                    .msg_training_data(
+                                                                        &msg.id, budget,
+                                                                    )?;
+                                                                is.lock().unwrap().infer(InferReq {
+                                                                    prompt: prompt.text,
+                                                                    config,
                                                                 })
-                                                                .unwrap();
-                                                                println!("{:?}", resp)
+                                                            });
+                                                        match tokens {
+                                                            Ok(tokens) => {
+                                                                // the response replaces what is there
+                                                                msg.msg.clear();
+                                                                self.last_generation = None;
+                                                                self.generation = Some(Generation {
+                                                                    convo_id: convo_id.to_string(),
+                                                                    target: GenerationTarget::Message(
+                                                                        msg.id.clone(),
+                                                                    ),
+                                                                    tokens,
+                                                                });
+                                                            }
+                                                            Err(e) => {
+                                                                self.last_generation =
+                                                                    Some(Err(format!("{e:#}")))
                                                             }
                                                         }
                                                     };
-
-                                                    if let ServerStatus::DoneGenerating {
-                                                        text: val,
-                                                    } = status
-                                                    {
-                                                        if ui.button("copy into").clicked() {
-                                                            msg.msg = String::from(val);
-                                                        }
-                                                    }
                                                 };
                                             };
                                         });
-                                        if msg != conversation.messages[i] {
+                                        if self.scroll_to.as_deref() == Some(msg.id.as_str()) {
+                                            group.response.scroll_to_me(Some(egui::Align::Center));
+                                            self.scroll_to = None;
+                                        }
+                                        if msg != active_messages[i] {
                                             action = Some(ConversationAction::MutateMessage {
                                                 new_message: msg,
                                             })
@@ -211,11 +748,19 @@ impl eframe::App for MyApp {
                                             user: User::Jake,
                                         });
                                     }
+                                    if let Some(id) = eval {
+                                        self.eval_error = None;
+                                        self.eval = Some(Eval::start(
+                                            conversation.clone(),
+                                            convo_id,
+                                            &id,
+                                            Vec::new(),
+                                        ));
+                                    }
                                     if let Some(action) = action {
-                                        conversation.apply(action).unwrap();
-                                        let res = self.conversations.insert(&mut conversation);
+                                        let res = self.conversations.apply(convo_id, action);
                                         if let Err(res) = res {
-                                            println!("err saving {:?}", res)
+                                            println!("err applying {:?}", res)
                                         }
                                         return;
                                     }
@@ -239,13 +784,23 @@ impl eframe::App for MyApp {
                             if let Some(ref convo_id) = self.selected_convo {
                                 let conversation = self.conversations.get(convo_id);
                                 if let Ok(Some(conversation)) = conversation {
-                                    match conversation.to_training_data() {
+                                    match conversation.to_training_data(&self.training_data_options)
+                                    {
                                         Ok(data) => {
                                             for datum in data {
                                                 ui.group(|ui| {
+                                                    if let Some(ref t) = datum.truncation {
+                                                        ui.colored_label(
+                                                            egui::Color32::YELLOW,
+                                                            format!(
+                                                                "truncated {} -> {} tokens",
+                                                                t.tokens_before, t.tokens_after
+                                                            ),
+                                                        );
+                                                    }
                                                     ui.add(
                                                         egui::TextEdit::multiline(
-                                                            &mut datum.clone(),
+                                                            &mut datum.text.clone(),
                                                         )
                                                         .text_style(egui::TextStyle::Body),
                                                     )
@@ -266,7 +821,7 @@ impl eframe::App for MyApp {
                         ui.heading("Model");
                         match self.server_manager.inference_server {
                             Some(ref is) => {
-                                ui.label("Inference server");
+                                ui.label(is.lock().unwrap().name());
                                 let status = is.lock().unwrap().status().cloned();
                                 if status.is_err() {
                                     ui.label(format!("Status error {:?}", status));
@@ -274,64 +829,42 @@ impl eframe::App for MyApp {
                                 }
                                 let status = status.unwrap();
                                 ui.label(format!("Status: {}", status.to_string()));
-                                match status {
-                                    ServerStatus::Generating { text } => {
-                                        egui::ScrollArea::vertical()
-                                            .max_width(500.0)
-                                            .id_source("generating")
-                                            .show(ui, |ui| {
-                                                ui.label(text);
-                                            });
-
-                                        if ui.button("stop").clicked() {
-                                            is.lock().unwrap().stop().unwrap();
-                                        }
+                                let generating = matches!(status, ServerStatus::Generating { .. });
+                                if let ServerStatus::Failed { error } = status {
+                                    ui.colored_label(egui::Color32::RED, error);
+                                }
+                                if (self.generation.is_some() || generating)
+                                    && ui.button("stop").clicked()
+                                {
+                                    if let Err(e) = is.lock().unwrap().stop() {
+                                        self.last_generation = Some(Err(format!("{e:#}")));
                                     }
-                                    ServerStatus::DoneGenerating { text } => {
-                                        egui::ScrollArea::vertical()
-                                            .max_width(500.0)
-                                            .id_source("done_generating")
-                                            .show(ui, |ui| {
-                                                ui.label(text);
-                                            });
-                                        if ui.button("infer!").clicked() {
-                                            println!("inferclicked");
-                                            let resp = is
-                                                .lock()
-                                                .unwrap()
-                                                .infer(InferReq {
-                                                    prompt: "the average".to_owned(),
-                                                    config: GenerationConfig::default(),
-                                                })
-                                                .unwrap();
-                                            println!("{:?}", resp)
-                                        }
+                                    // whatever arrived so far stays
+                                    self.generation = None;
+                                    if self.agent.take().is_some() {
+                                        self.agent_report = Some("stopped".to_string());
                                     }
-                                    ServerStatus::Ready {} => {
-                                        if ui.button("infer!").clicked() {
-                                            println!("inferclicked");
-                                            let resp = is
-                                                .lock()
-                                                .unwrap()
-                                                .infer(InferReq {
-                                                    prompt: "the length of".to_owned(),
-                                                    config: GenerationConfig::default(),
-                                                })
-                                                .unwrap();
-                                            println!("{:?}", resp)
-                                        }
+                                }
+                                match self.last_generation {
+                                    Some(Ok(ref resp)) => {
+                                        ui.label(format!("last generation: {resp}"));
+                                    }
+                                    Some(Err(ref error)) => {
+                                        ui.colored_label(
+                                            egui::Color32::RED,
+                                            format!("generation failed {error}"),
+                                        );
                                     }
-                                    _ => {}
+                                    None => {}
                                 }
                             }
                             None => {
-                                if ui.button("start server").clicked() {
-                                    let res =
-                                        self.server_manager.start_inference(&InferenceServerArgs {
-                                            model_config: ".".into(),
-                                            image_name: "fuck".into(),
-                                            port: 9090,
-                                        });
+                                let kind =
+                                    format!("{:?}", self.server_manager.config.inference_backend);
+                                if ui.button(format!("start {kind}")).clicked() {
+                                    let res = self
+                                        .server_manager
+                                        .start_inference(&InferenceServerArgs::default());
                                     dbg!(&res);
                                 };
                             }
@@ -341,12 +874,58 @@ impl eframe::App for MyApp {
 
                 ui.vertical(|ui| {
                     ui.group(|ui| {
-                        if ui.button("export data").clicked() {
-                            let mut file = File::create("data.jsonl").unwrap();
-
-                            for (_, c) in self.conversations.clone().into_iter() {
-                                c.write_jsonl(&mut file).unwrap()
+                        let mut all_branches =
+                            self.training_data_options.branches == BranchExport::All;
+                        if ui.checkbox(&mut all_branches, "all branches").changed() {
+                            self.training_data_options.branches = if all_branches {
+                                BranchExport::All
+                            } else {
+                                BranchExport::Active
+                            };
+                        }
+                        ui.checkbox(
+                            &mut self.training_data_options.exclude_failed,
+                            "skip failed commands",
+                        );
+                        ui.horizontal(|ui| {
+                            ui.label("only tags");
+                            if ui.text_edit_singleline(&mut self.export_tags).changed() {
+                                self.training_data_options.tags = parse_tags(&self.export_tags);
+                            }
+                        });
+                        let mut budgeted = self.training_data_options.token_budget.is_some();
+                        ui.horizontal(|ui| {
+                            if ui.checkbox(&mut budgeted, "token budget").changed() {
+                                self.training_data_options.token_budget =
+                                    budgeted.then_some(DEFAULT_TOKEN_BUDGET);
                             }
+                            if let Some(ref mut budget) = self.training_data_options.token_budget {
+                                ui.add(egui::DragValue::new(budget).speed(16));
+                            }
+                        });
+                        egui::ComboBox::from_label("format")
+                            .selected_text(self.export_format.clone())
+                            .show_ui(ui, |ui| {
+                                for exporter in export::exporters() {
+                                    ui.selectable_value(
+                                        &mut self.export_format,
+                                        exporter.name().to_string(),
+                                        exporter.name(),
+                                    )
+                                    .on_hover_text(exporter.description());
+                                }
+                            });
+                        if ui.button("export").clicked() {
+                            self.export_report = Some(match self.export() {
+                                Ok(report) => {
+                                    println!("{report}");
+                                    report.to_string()
+                                }
+                                Err(e) => format!("export failed {e:#}"),
+                            });
+                        }
+                        if let Some(ref report) = self.export_report {
+                            ui.label(report);
                         }
                     });
                 });
@@ -354,3 +933,317 @@ impl eframe::App for MyApp {
         });
     }
 }
+
+/// Title, tags and summary of the conversation
+fn conversation_info_ui(
+    ui: &mut Ui,
+    conversation: &Conversation,
+    tags_edit: &mut Option<(String, String)>,
+    server_manager: &ServerManager,
+    generation: &mut Option<Generation>,
+) -> Option<ConversationAction> {
+    let mut action = None;
+    let convo_id = conversation.id.clone().unwrap_or_default();
+    ui.group(|ui| {
+        let mut title = conversation.title.clone();
+        ui.horizontal(|ui| {
+            ui.label("title");
+            if ui.text_edit_singleline(&mut title).changed() {
+                action = Some(ConversationAction::SetTitle {
+                    title: title.clone(),
+                });
+            }
+        });
+
+        // the typed text is kept separately so a trailing comma survives being parsed
+        if tags_edit
+            .as_ref()
+            .map(|(id, _)| id != &convo_id)
+            .unwrap_or(true)
+        {
+            *tags_edit = Some((convo_id.clone(), conversation.tags.join(", ")));
+        }
+        if let Some((_, ref mut tags)) = tags_edit {
+            ui.horizontal(|ui| {
+                ui.label("tags");
+                if ui.text_edit_singleline(tags).changed() {
+                    action = Some(ConversationAction::SetTags {
+                        tags: parse_tags(tags),
+                    });
+                }
+            });
+        }
+
+        // only the stream started by "summarize" below writes the summary, and nothing
+        // typed in the meantime gets mixed into it
+        let summarizing = matches!(
+            generation,
+            Some(Generation {
+                convo_id: ref id,
+                target: GenerationTarget::Summary,
+                ..
+            }) if id == &convo_id
+        );
+        let mut summary = conversation.summary.clone();
+        ui.label("summary");
+        if egui::TextEdit::multiline(&mut summary)
+            .desired_rows(2)
+            .desired_width(1000.0)
+            .interactive(!summarizing)
+            .show(ui)
+            .response
+            .changed()
+        {
+            action = Some(ConversationAction::SetSummary {
+                summary: summary.clone(),
+            });
+        }
+        ui.collapsing("nexos limits", |ui| {
+            let mut limits = conversation.nexos_limits.clone();
+            ui.horizontal(|ui| {
+                ui.label("timeout (s)");
+                ui.add(egui::DragValue::new(&mut limits.timeout_secs).clamp_range(1..=86400));
+                ui.label("output (bytes)");
+                ui.add(egui::DragValue::new(&mut limits.max_output_bytes).speed(256));
+                ui.label("files (bytes)");
+                ui.add(egui::DragValue::new(&mut limits.max_file_bytes).speed(1024));
+            });
+            ui.horizontal(|ui| {
+                ui.label("cpus");
+                ui.add(
+                    egui::DragValue::new(&mut limits.cpus)
+                        .speed(0.1)
+                        .clamp_range(0.0..=64.0),
+                );
+                ui.label("memory (MB)");
+                ui.add(egui::DragValue::new(&mut limits.memory_mb).speed(64));
+                ui.label("pids");
+                ui.add(egui::DragValue::new(&mut limits.pids).clamp_range(0..=65536));
+            });
+            ui.label("0 is no limit, lifting a limit takes a nexos reset");
+            if limits != conversation.nexos_limits {
+                action = Some(ConversationAction::SetNexosLimits { limits });
+            }
+        });
+        if let Some(ref is) = server_manager.inference_server {
+            ui.horizontal(|ui| match generation {
+                Some(_) if summarizing => {
+                    ui.spinner();
+                    ui.label("summarizing");
+                }
+                Some(_) => {}
+                None => {
+                    if ui.button("summarize").clicked() {
+                        let tokens = conversation.summary_prompt().and_then(|prompt| {
+                            is.lock().unwrap().infer(InferReq {
+                                prompt,
+                                config: GenerationConfig::for_prompt()?,
+                            })
+                        });
+                        match tokens {
+                            Ok(tokens) => {
+                                // the summary is written from scratch
+                                action = Some(ConversationAction::SetSummary {
+                                    summary: String::new(),
+                                });
+                                *generation = Some(Generation {
+                                    convo_id: convo_id.clone(),
+                                    target: GenerationTarget::Summary,
+                                    tokens,
+                                });
+                            }
+                            Err(e) => println!("failed to summarize {e:#}"),
+                        }
+                    }
+                }
+            });
+        }
+    });
+    action
+}
+
+/// Starts and stops the agent on the conversation, and asks whether to run the commands
+/// when it waits for approval
+fn agent_ui(
+    ui: &mut Ui,
+    conversation: &Conversation,
+    config: &mut AgentConfig,
+    agent: &mut Option<AgentRun>,
+    report: &mut Option<String>,
+) {
+    let convo_id = conversation.id.clone().unwrap_or_default();
+    let mut stop = None;
+    ui.group(|ui| {
+        ui.horizontal(|ui| {
+            let mut running = matches!(agent, Some(run) if run.convo_id == convo_id);
+            // one agent at a time
+            let enabled = running || agent.is_none();
+            if ui
+                .add_enabled(enabled, egui::Checkbox::new(&mut running, "agent"))
+                .changed()
+            {
+                if running {
+                    *report = None;
+                    *agent = Some(AgentRun {
+                        convo_id: convo_id.clone(),
+                        steps: 0,
+                        phase: AgentPhase::Respond,
+                    });
+                } else {
+                    stop = Some("stopped".to_string());
+                }
+            }
+            ui.label("max steps");
+            ui.add(egui::DragValue::new(&mut config.agent_max_steps).clamp_range(1..=1000));
+            ui.checkbox(&mut config.agent_approve, "approve commands");
+        });
+        match agent {
+            Some(run) if run.convo_id == convo_id => {
+                let step = format!("step {}/{}", run.steps, config.agent_max_steps);
+                match run.phase {
+                    AgentPhase::Generating(_) => {
+                        ui.horizontal(|ui| {
+                            ui.spinner();
+                            ui.label(format!("{step}, generating"));
+                        });
+                    }
+                    AgentPhase::Approval(ref id, ref mut approved) => {
+                        let turn = conversation
+                            .messages
+                            .iter()
+                            .find(|m| m.id == *id)
+                            .map(|m| Turn::of(&m.msg));
+                        ui.label(format!("{step}, run these?"));
+                        if let Some(turn) = turn {
+                            for (command, approved) in turn.commands.iter().zip(approved.iter_mut())
+                            {
+                                ui.checkbox(approved, agent::describe(command));
+                            }
+                        }
+                        let mut next = None;
+                        ui.horizontal(|ui| {
+                            if ui.button("run").clicked() {
+                                let skip: Vec<usize> = approved
+                                    .iter()
+                                    .enumerate()
+                                    .filter(|(_, approved)| !**approved)
+                                    .map(|(i, _)| i)
+                                    .collect();
+                                if skip.len() == approved.len() {
+                                    stop = Some(AgentStop::Rejected.to_string());
+                                } else {
+                                    next = Some(AgentPhase::Run(id.clone(), skip));
+                                }
+                            }
+                            if ui.button("don't").clicked() {
+                                stop = Some(AgentStop::Rejected.to_string());
+                            }
+                        });
+                        if let Some(next) = next {
+                            run.phase = next;
+                        }
+                    }
+                    AgentPhase::Evaluating(..) => {
+                        ui.horizontal(|ui| {
+                            ui.spinner();
+                            ui.label(format!("{step}, running the commands"));
+                        });
+                    }
+                    AgentPhase::Respond | AgentPhase::Run(..) => {
+                        ui.label(step);
+                    }
+                }
+            }
+            _ => {
+                if let Some(ref report) = report {
+                    ui.label(format!("agent stopped: {report}"));
+                }
+            }
+        }
+    });
+    if let Some(why) = stop {
+        *agent = None;
+        *report = Some(why);
+    }
+}
+
+/// One line summary of a command, red when it failed
+fn command_result_ui(ui: &mut Ui, result: &CommandResult) {
+    let mut status = format!(
+        "exit {} in {:.1}s",
+        result.exit_code,
+        result.duration.as_secs_f64()
+    );
+    if result.truncated {
+        status += ", truncated";
+    }
+    for limit in &result.limits {
+        if !matches!(limit, LimitHit::Output { .. }) {
+            status += &format!(", {limit}");
+        }
+    }
+    if result.failed() {
+        ui.colored_label(egui::Color32::RED, status);
+    } else {
+        ui.label(status);
+    }
+}
+
+/// Restore and diff buttons for the files as they were after the message was evaluated.
+/// Returns what happened when one was clicked
+fn snapshot_ui(
+    ui: &mut Ui,
+    convo_id: &str,
+    snapshot: &str,
+    base: &mut Option<String>,
+) -> Option<String> {
+    let short = &snapshot[..snapshot.len().min(8)];
+    let mut report = None;
+    ui.horizontal(|ui| {
+        ui.label(format!("files {short}"));
+        if ui.button("restore files").clicked() {
+            report = Some(match snapshot::restore(convo_id, snapshot) {
+                Ok(before) => format!("restored the files to {short}, they were {before} before"),
+                Err(e) => format!("failed to restore the files: {e:#}"),
+            });
+        }
+        if ui.button("diff from").clicked() {
+            *base = Some(snapshot.to_string());
+        }
+        if let Some(ref from) = base {
+            if from != snapshot && ui.button("diff to").clicked() {
+                report = Some(match snapshot::diff(convo_id, from, snapshot) {
+                    Ok(diff) if diff.is_empty() => "no changes".to_string(),
+                    Ok(diff) => diff,
+                    Err(e) => format!("failed to diff the files: {e:#}"),
+                });
+            }
+        }
+    });
+    report
+}
+
+/// Like / dislike buttons for a response and a box to say why
+fn feedback_ui(ui: &mut Ui, msg: &mut Message) {
+    ui.horizontal(|ui| {
+        for (rating, label) in [(Rating::Like, "like"), (Rating::Dislike, "dislike")] {
+            let selected = msg.rating() == Some(rating);
+            if ui.selectable_label(selected, label).clicked() {
+                msg.meta.feedback = if selected {
+                    None
+                } else {
+                    Some(Feedback::new(rating))
+                };
+            }
+        }
+    });
+    if let Some(ref mut feedback) = msg.meta.feedback {
+        let reason = egui::TextEdit::singleline(&mut feedback.reason)
+            .hint_text("why?")
+            .desired_width(1000.0)
+            .show(ui);
+        if reason.response.changed() {
+            feedback.time = SystemTime::now();
+        }
+    }
+}
@@ -0,0 +1,510 @@
+use std::time::SystemTime;
+
+use anyhow::{anyhow, bail, Context, Result};
+use jammdb::{Bucket, Error as JammError, Tx, DB};
+
+use crate::conversation::{Conversation, ConversationAction};
+use crate::store::{ConversationStore, RevisionUpdate};
+
+pub const HISTORY_BUCKET: &str = "history";
+pub const HISTORY_HEADS_BUCKET: &str = "history_heads";
+
+/// One revision of a conversation. Every action applied through `ConversationStore::apply`
+/// appends one of these, the conversation record itself is just the entry at the head.
+///
+/// The resulting conversation is stored instead of replaying actions because
+/// actions like EvalMessage run commands and can't be replayed deterministically.
+#[derive(Clone, PartialEq, Debug, serde::Serialize, serde::Deserialize)]
+pub struct HistoryEntry {
+    pub revision: u64,
+    pub time: SystemTime,
+    /// None for entries that didn't come from an action (the initial state, restores)
+    pub action: Option<ConversationAction>,
+    pub description: String,
+    pub conversation: Conversation,
+}
+
+impl HistoryEntry {
+    fn new(
+        revision: u64,
+        action: Option<ConversationAction>,
+        description: String,
+        conversation: Conversation,
+    ) -> Self {
+        Self {
+            revision,
+            time: SystemTime::now(),
+            action,
+            description,
+            conversation,
+        }
+    }
+}
+
+fn describe(action: &ConversationAction) -> String {
+    match action {
+        ConversationAction::DeleteMessage { id }
+        | ConversationAction::EvalMessage { id }
+        | ConversationAction::Regenerate { id }
+        | ConversationAction::SwitchBranch { id }
+        | ConversationAction::PruneBranch { id } => format!("{action} {id}"),
+        ConversationAction::MutateMessage { new_message } => {
+            format!("{action} {}", new_message.id)
+        }
+        ConversationAction::AddMessage { user, .. } => format!("{action} {user}"),
+        ConversationAction::SetTitle { title } => format!("{action} {title}"),
+        ConversationAction::SetTags { tags } => format!("{action} {}", tags.join(",")),
+        ConversationAction::SetSummary { .. } | ConversationAction::SetNexosLimits { .. } => {
+            action.to_string()
+        }
+    }
+}
+
+fn history_bucket<'b, 'tx>(tx: &'b Tx<'tx>, uuid: &str) -> Result<Bucket<'b, 'tx>> {
+    let history = tx.get_or_create_bucket(HISTORY_BUCKET)?;
+    Ok(history.get_or_create_bucket(uuid.to_string())?)
+}
+
+fn read_entries(bucket: &Bucket) -> Result<Vec<HistoryEntry>> {
+    let mut entries = Vec::new();
+    for data in bucket.cursor() {
+        let entry: HistoryEntry = rmp_serde::from_slice(data.kv().value())
+            .context("Failed to deserialize history entry")?;
+        entries.push(entry);
+    }
+    entries.sort_by_key(|e| e.revision);
+    Ok(entries)
+}
+
+fn put_entry(bucket: &Bucket, entry: &HistoryEntry) -> Result<()> {
+    let data = rmp_serde::to_vec_named(entry).context("Failed to serialize history entry")?;
+    bucket.put(entry.revision.to_be_bytes().to_vec(), data)?;
+    Ok(())
+}
+
+fn read_head(tx: &Tx, uuid: &str) -> Result<Option<u64>> {
+    let heads = match tx.get_bucket(HISTORY_HEADS_BUCKET) {
+        Ok(heads) => heads,
+        Err(JammError::BucketMissing) => return Ok(None),
+        Err(e) => bail!("failed to open history heads bucket {e}"),
+    };
+    let data = match heads.get(uuid) {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+    let bytes: [u8; 8] = data
+        .kv()
+        .value()
+        .try_into()
+        .map_err(|_| anyhow!("corrupt history head for {uuid}"))?;
+    Ok(Some(u64::from_be_bytes(bytes)))
+}
+
+fn write_head(tx: &Tx, uuid: &str, head: u64) -> Result<()> {
+    let heads = tx.get_or_create_bucket(HISTORY_HEADS_BUCKET)?;
+    heads.put(uuid.to_string(), head.to_be_bytes().to_vec())?;
+    Ok(())
+}
+
+fn put_conversation(tx: &Tx, bucket_name: &str, conversation: &Conversation) -> Result<()> {
+    let uuid = conversation
+        .id
+        .clone()
+        .ok_or(anyhow!("conversation has no id"))?;
+    let bucket = tx.get_bucket(bucket_name.to_string())?;
+    let data =
+        rmp_serde::to_vec_named(conversation).context("Failed to serialize conversation data")?;
+    bucket.put(uuid.into_bytes(), data)?;
+    Ok(())
+}
+
+/// Applies `action` to the stored conversation and records it in the conversation's history
+pub fn apply<S: ConversationStore + ?Sized>(
+    store: &mut S,
+    uuid: &str,
+    action: ConversationAction,
+) -> Result<Conversation> {
+    let before = store
+        .get(uuid)?
+        .ok_or(anyhow!("no conversation with id {uuid}"))?;
+    let mut after = before.clone();
+    after.apply(action.clone())?;
+    record(store, uuid, before, action, after)
+}
+
+/// Records `after` as the result of applying `action` to `before`, for actions whose
+/// result was worked out somewhere else
+pub fn record<S: ConversationStore + ?Sized>(
+    store: &mut S,
+    uuid: &str,
+    before: Conversation,
+    action: ConversationAction,
+    after: Conversation,
+) -> Result<Conversation> {
+    let entries = store.history(uuid)?;
+    let mut update = RevisionUpdate {
+        conversation: after.clone(),
+        entries: Vec::new(),
+        truncate_after: None,
+        head: 0,
+    };
+    let head = match (store.stored_history_head(uuid)?, entries.last()) {
+        (Some(head), _) => head,
+        (None, None) => {
+            // conversations from before history existed get their current state as revision 0
+            update.entries.push(HistoryEntry::new(
+                0,
+                None,
+                "initial state".to_string(),
+                before,
+            ));
+            0
+        }
+        (None, Some(last)) => last.revision,
+    };
+    // applying something after an undo throws away the redo branch
+    update.truncate_after = Some(head);
+
+    // typing into a message (or the title etc) changes it on every keystroke,
+    // fold those into one revision. Not after an undo though, that would overwrite the
+    // revision the undo went back to
+    let latest = entries.last().map(|e| e.revision);
+    let head_entry = entries
+        .iter()
+        .find(|e| e.revision == head)
+        .filter(|_| latest == Some(head));
+    let coalesce = match (head_entry.and_then(|e| e.action.as_ref()), &action) {
+        (
+            Some(ConversationAction::MutateMessage { new_message: prev }),
+            ConversationAction::MutateMessage { new_message },
+        ) => prev.id == new_message.id,
+        (Some(ConversationAction::SetTitle { .. }), ConversationAction::SetTitle { .. })
+        | (Some(ConversationAction::SetTags { .. }), ConversationAction::SetTags { .. })
+        | (Some(ConversationAction::SetSummary { .. }), ConversationAction::SetSummary { .. })
+        | (
+            Some(ConversationAction::SetNexosLimits { .. }),
+            ConversationAction::SetNexosLimits { .. },
+        ) => true,
+        _ => false,
+    };
+    update.head = if coalesce { head } else { head + 1 };
+    update.entries.push(HistoryEntry::new(
+        update.head,
+        Some(action.clone()),
+        describe(&action),
+        after.clone(),
+    ));
+    store.write_revision(uuid, update)?;
+    Ok(after)
+}
+
+/// Applies `action` to the stored conversation without recording a revision, so it can't
+/// be undone and doesn't drop what could be redone
+pub fn apply_unrecorded<S: ConversationStore + ?Sized>(
+    store: &mut S,
+    uuid: &str,
+    action: ConversationAction,
+) -> Result<Conversation> {
+    let mut conversation = store
+        .get(uuid)?
+        .ok_or(anyhow!("no conversation with id {uuid}"))?;
+    conversation.apply(action)?;
+    let mut update = RevisionUpdate {
+        conversation: conversation.clone(),
+        entries: Vec::new(),
+        truncate_after: None,
+        head: 0,
+    };
+    match head(store, uuid)? {
+        Some(head) => update.head = head,
+        // without any history this is where it starts
+        None => update.entries.push(HistoryEntry::new(
+            0,
+            None,
+            "initial state".to_string(),
+            conversation.clone(),
+        )),
+    }
+    store.write_revision(uuid, update)?;
+    Ok(conversation)
+}
+
+pub fn head<S: ConversationStore + ?Sized>(store: &S, uuid: &str) -> Result<Option<u64>> {
+    let head = store.stored_history_head(uuid)?;
+    Ok(head.or(store.history(uuid)?.last().map(|e| e.revision)))
+}
+
+fn move_head<S: ConversationStore + ?Sized>(
+    store: &mut S,
+    uuid: &str,
+    revision: u64,
+) -> Result<Conversation> {
+    let entry = store
+        .history(uuid)?
+        .into_iter()
+        .find(|e| e.revision == revision)
+        .ok_or(anyhow!("no revision {revision} for {uuid}"))?;
+    store.write_revision(
+        uuid,
+        RevisionUpdate {
+            conversation: entry.conversation.clone(),
+            entries: Vec::new(),
+            truncate_after: None,
+            head: revision,
+        },
+    )?;
+    Ok(entry.conversation)
+}
+
+pub fn undo<S: ConversationStore + ?Sized>(store: &mut S, uuid: &str) -> Result<Conversation> {
+    match head(store, uuid)? {
+        Some(head) if head > 0 => move_head(store, uuid, head - 1),
+        _ => bail!("nothing to undo"),
+    }
+}
+
+pub fn redo<S: ConversationStore + ?Sized>(store: &mut S, uuid: &str) -> Result<Conversation> {
+    let head = head(store, uuid)?.ok_or(anyhow!("nothing to redo"))?;
+    let last = store.history(uuid)?.last().map(|e| e.revision).unwrap_or(0);
+    if head >= last {
+        bail!("nothing to redo");
+    }
+    move_head(store, uuid, head + 1)
+}
+
+pub fn restore<S: ConversationStore + ?Sized>(
+    store: &mut S,
+    uuid: &str,
+    revision: u64,
+) -> Result<Conversation> {
+    let entries = store.history(uuid)?;
+    let entry = entries
+        .iter()
+        .find(|e| e.revision == revision)
+        .ok_or(anyhow!("no revision {revision} for {uuid}"))?;
+    let last = entries.last().map(|e| e.revision).unwrap_or(0);
+    store.write_revision(
+        uuid,
+        RevisionUpdate {
+            conversation: entry.conversation.clone(),
+            entries: vec![HistoryEntry::new(
+                last + 1,
+                None,
+                format!("restored revision {revision}"),
+                entry.conversation.clone(),
+            )],
+            truncate_after: None,
+            head: last + 1,
+        },
+    )?;
+    Ok(entry.conversation.clone())
+}
+
+// The jammdb side of ConversationStore's history methods for `Conversations`.
+// Each conversation gets a nested bucket in HISTORY_BUCKET keyed by big endian revision.
+
+/// Keys are big endian revisions so the cursor already goes oldest first
+pub(crate) fn jamm_raw_history(db: &DB, uuid: &str) -> Result<Vec<Vec<u8>>> {
+    let tx = db.tx(false)?;
+    let history = match tx.get_bucket(HISTORY_BUCKET) {
+        Ok(history) => history,
+        Err(JammError::BucketMissing) => return Ok(Vec::new()),
+        Err(e) => bail!("failed to open history bucket {e}"),
+    };
+    let bucket = match history.get_bucket(uuid) {
+        Ok(bucket) => bucket,
+        Err(JammError::BucketMissing) => return Ok(Vec::new()),
+        Err(e) => bail!("failed to open history for {uuid} {e}"),
+    };
+    let entries = bucket
+        .cursor()
+        .map(|data| data.kv().value().to_vec())
+        .collect();
+    Ok(entries)
+}
+
+pub(crate) fn jamm_history(db: &DB, uuid: &str) -> Result<Vec<HistoryEntry>> {
+    jamm_raw_history(db, uuid)?
+        .iter()
+        .map(|data| rmp_serde::from_slice(data).context("Failed to deserialize history entry"))
+        .collect()
+}
+
+pub(crate) fn jamm_put_entries(tx: &Tx, uuid: &str, entries: &[HistoryEntry]) -> Result<()> {
+    let bucket = history_bucket(tx, uuid)?;
+    for entry in entries {
+        put_entry(&bucket, entry)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn jamm_history_head(db: &DB, uuid: &str) -> Result<Option<u64>> {
+    read_head(&db.tx(false)?, uuid)
+}
+
+pub(crate) fn jamm_write_revision(
+    db: &DB,
+    bucket_name: &str,
+    uuid: &str,
+    update: RevisionUpdate,
+) -> Result<()> {
+    let tx = db.tx(true)?;
+    let bucket = history_bucket(&tx, uuid)?;
+    if let Some(truncate_after) = update.truncate_after {
+        for entry in read_entries(&bucket)?
+            .iter()
+            .filter(|e| e.revision > truncate_after)
+        {
+            bucket.delete(entry.revision.to_be_bytes())?;
+        }
+    }
+    for entry in &update.entries {
+        put_entry(&bucket, entry)?;
+    }
+    write_head(&tx, uuid, update.head)?;
+    put_conversation(&tx, bucket_name, &update.conversation)?;
+    tx.commit()?;
+    Ok(())
+}
+
+pub(crate) fn jamm_delete_history(db: &DB, uuid: &str) -> Result<()> {
+    let tx = db.tx(true)?;
+    let history = tx.get_or_create_bucket(HISTORY_BUCKET)?;
+    match history.delete_bucket(uuid) {
+        Ok(_) | Err(JammError::BucketMissing) => {}
+        Err(e) => bail!("failed to delete history for {uuid} {e}"),
+    }
+    let heads = tx.get_or_create_bucket(HISTORY_HEADS_BUCKET)?;
+    if heads.get(uuid).is_some() {
+        heads.delete(uuid)?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::conversation::{parse_tags, Conversations, User};
+
+    fn msgs(conversation: &Conversation) -> Vec<String> {
+        conversation
+            .active_messages()
+            .iter()
+            .map(|m| m.msg.clone())
+            .collect()
+    }
+
+    fn add(conversations: &mut Conversations, uuid: &str, user: User, text: &str) -> Conversation {
+        let conversation = conversations
+            .apply(uuid, ConversationAction::AddMessage { index: None, user })
+            .unwrap();
+        let mut msg = conversation.active_messages().last().unwrap().clone();
+        msg.msg = text.to_string();
+        conversations
+            .apply(uuid, ConversationAction::MutateMessage { new_message: msg })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_undo_redo() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = jammdb::DB::open(dir.path().join("history.db")).unwrap();
+        let mut conversations = Conversations::new(Arc::new(db), None).unwrap();
+        let uuid = conversations.insert(&mut Conversation::default()).unwrap();
+        add(&mut conversations, &uuid, User::Zack, "hello");
+        let conversation = add(&mut conversations, &uuid, User::Jake, "hey");
+        assert_eq!(msgs(&conversation), vec!["hello", "hey"]);
+
+        // the edit and the add are separate revisions
+        assert_eq!(msgs(&conversations.undo(&uuid).unwrap()), vec!["hello", ""]);
+        assert_eq!(msgs(&conversations.undo(&uuid).unwrap()), vec!["hello"]);
+        assert_eq!(msgs(&conversations.redo(&uuid).unwrap()), vec!["hello", ""]);
+
+        // a new action drops what could have been redone
+        let conversation = add(&mut conversations, &uuid, User::Zack, "bye");
+        assert_eq!(msgs(&conversation), vec!["hello", "", "bye"]);
+        assert!(conversations.redo(&uuid).is_err());
+
+        let restored = conversations.restore(&uuid, 0).unwrap();
+        assert!(restored.messages.is_empty());
+        assert_eq!(conversations.get(&uuid).unwrap().unwrap(), restored);
+        assert!(conversations.history(&uuid).unwrap().len() > 1);
+    }
+
+    #[test]
+    fn test_tags_coalesce() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = jammdb::DB::open(dir.path().join("history.db")).unwrap();
+        let mut conversations = Conversations::new(Arc::new(db), None).unwrap();
+        let uuid = conversations.insert(&mut Conversation::default()).unwrap();
+        for tags in ["shell", "shell, rust"] {
+            conversations
+                .apply(
+                    &uuid,
+                    ConversationAction::SetTags {
+                        tags: parse_tags(tags),
+                    },
+                )
+                .unwrap();
+        }
+        // typing tags is a single revision on top of the initial state
+        assert_eq!(conversations.history(&uuid).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_edit_after_undo() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = jammdb::DB::open(dir.path().join("history.db")).unwrap();
+        let mut conversations = Conversations::new(Arc::new(db), None).unwrap();
+        let uuid = conversations.insert(&mut Conversation::default()).unwrap();
+        let conversation = add(&mut conversations, &uuid, User::Zack, "hello");
+        let mut msg = conversation.active_messages()[0].clone();
+        add(&mut conversations, &uuid, User::Jake, "hey");
+
+        conversations.undo(&uuid).unwrap();
+        let conversation = conversations.undo(&uuid).unwrap();
+        assert_eq!(msgs(&conversation), vec!["hello"]);
+
+        // the head is the edit of the same message but it must not be folded into
+        msg.msg = "hello there".into();
+        let conversation = conversations
+            .apply(
+                &uuid,
+                ConversationAction::MutateMessage { new_message: msg },
+            )
+            .unwrap();
+        assert_eq!(msgs(&conversation), vec!["hello there"]);
+        assert_eq!(msgs(&conversations.undo(&uuid).unwrap()), vec!["hello"]);
+        assert_eq!(
+            msgs(&conversations.redo(&uuid).unwrap()),
+            vec!["hello there"]
+        );
+    }
+
+    #[test]
+    fn test_switch_branch_unrecorded() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = jammdb::DB::open(dir.path().join("history.db")).unwrap();
+        let mut conversations = Conversations::new(Arc::new(db), None).unwrap();
+        let uuid = conversations.insert(&mut Conversation::default()).unwrap();
+        let conversation = add(&mut conversations, &uuid, User::Jake, "first");
+        let first = conversation.active_messages()[0].id.clone();
+        conversations
+            .apply(&uuid, ConversationAction::Regenerate { id: first.clone() })
+            .unwrap();
+        let revisions = conversations.history(&uuid).unwrap().len();
+
+        let conversation = conversations
+            .apply_unrecorded(&uuid, ConversationAction::SwitchBranch { id: first })
+            .unwrap();
+        assert_eq!(msgs(&conversation), vec!["first"]);
+        assert_eq!(conversations.history(&uuid).unwrap().len(), revisions);
+        // undo goes to before the regenerate, not back to the other branch
+        let conversation = conversations.undo(&uuid).unwrap();
+        assert_eq!(msgs(&conversation), vec!["first"]);
+        assert_eq!(conversation.messages.len(), 1);
+    }
+}
@@ -2,25 +2,44 @@
 extern crate mopa;
 
 extern crate pty;
+mod agent;
+mod cassette;
+mod command_parser;
 mod conversation;
 mod editor;
+mod export;
+mod files;
 mod frontend;
+mod history;
+mod manifest;
+mod migrations;
 mod model_server;
 mod mpty;
 mod nexos;
 mod openai;
+mod sandbox;
+mod search;
+mod snapshot;
+mod store;
 mod templates;
 mod token;
 use anyhow::Context;
 use chrono::Local;
-use clap::Parser;
-use std::{sync::Arc, time::SystemTime};
+use clap::{Parser, Subcommand};
+use std::{
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
 
 use conversation::*;
 use model_server::*;
-use openai::*;
 
+use crate::agent::AgentConfig;
+use crate::cassette::Cassette;
 use crate::frontend::launch_gui;
+use crate::nexos::NexosConfig;
+use crate::store::{open_store, ConversationStore, StoreKind};
+use crate::token::TokenizerConfig;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -28,6 +47,15 @@ use crate::frontend::launch_gui;
 struct Cli {
     #[command(subcommand)]
     command: Subcommands,
+
+    /// Record every Nexos call and inference request to this cassette
+    #[arg(long, global = true, conflicts_with = "replay")]
+    record: Option<String>,
+
+    /// Answer Nexos calls and inference requests from a recorded cassette instead of
+    /// running them
+    #[arg(long, global = true)]
+    replay: Option<String>,
 }
 
 #[derive(Parser, Debug)]
@@ -45,25 +73,289 @@ enum Subcommands {
         #[arg(short, long, default_value = "real.db")]
         db: String,
 
+        #[arg(short, long, value_enum, default_value_t = StoreKind::Jammdb)]
+        store: StoreKind,
+
         #[arg(short, long, default_value = "migrate_copy.db")]
         copy_name: String,
+
+        /// Report what would be migrated without writing anything
+        #[arg(long)]
+        dry_run: bool,
     },
     Frontend {
         #[arg(short, long, default_value = "real.db")]
         db: String,
+
+        #[arg(short, long, value_enum, default_value_t = StoreKind::Jammdb)]
+        store: StoreKind,
+
+        #[command(flatten)]
+        nexos: NexosConfig,
+
+        #[command(flatten)]
+        inference: InferenceConfig,
+
+        #[command(flatten)]
+        agent: AgentConfig,
+
+        #[command(flatten)]
+        tokenizer: TokenizerConfig,
+    },
+    /// Show the edit history of a conversation
+    History {
+        #[arg(short, long, default_value = "real.db")]
+        db: String,
+
+        #[arg(short, long, value_enum, default_value_t = StoreKind::Jammdb)]
+        store: StoreKind,
+
+        /// The conversation id
+        id: String,
+
+        /// Make this revision the current state of the conversation
+        #[arg(short, long)]
+        restore: Option<u64>,
+    },
+    /// Write a training dataset in one of the export formats
+    Export {
+        #[arg(short, long, default_value = "real.db")]
+        db: String,
+
+        #[arg(short, long, value_enum, default_value_t = StoreKind::Jammdb)]
+        store: StoreKind,
+
+        /// completion, sharegpt, chatml, alpaca, input_output or dpo
+        #[arg(short, long, default_value = "completion")]
+        format: String,
+
+        /// Defaults to a file named after the format
+        #[arg(short, long)]
+        output: Option<String>,
+
+        /// Export every branch instead of only the selected ones
+        #[arg(long)]
+        all_branches: bool,
+
+        /// Comma separated, only conversations with one of these tags are exported
+        #[arg(long)]
+        tags: Option<String>,
+
+        /// Drop the oldest history from prompts over this many tokens
+        #[arg(long)]
+        token_budget: Option<usize>,
+
+        #[command(flatten)]
+        tokenizer: TokenizerConfig,
+
+        /// Only conversations started on or after this date (YYYY-MM-DD)
+        #[arg(long, value_parser = parse_date)]
+        since: Option<chrono::NaiveDate>,
+
+        /// Only conversations started on or before this date (YYYY-MM-DD)
+        #[arg(long, value_parser = parse_date)]
+        until: Option<chrono::NaiveDate>,
+
+        /// Comma separated, only conversations with a message from one of these users
+        /// (jake, zack, docker, system, taskreport)
+        #[arg(long)]
+        users: Option<String>,
+
+        /// Also export responses marked exclude_from_training
+        #[arg(long)]
+        include_excluded: bool,
+
+        /// Leave out responses where one of the commands exited with an error
+        #[arg(long)]
+        exclude_failed: bool,
+
+        /// Fraction of conversations that go to the validation file instead, picked
+        /// by hashing the conversation id so the split is the same every export
+        #[arg(long, default_value_t = 0.0)]
+        val_fraction: f64,
+
+        /// Defaults to the output path with .val before the extension
+        #[arg(long)]
+        val_output: Option<String>,
+    },
+    /// Work with exported datasets
+    Dataset {
+        #[command(subcommand)]
+        command: DatasetCommand,
+    },
+    /// Talk to the model in the terminal. Messages are written in $EDITOR and Jake's
+    /// responses can be edited before they are kept
+    Chat {
+        #[arg(short, long, default_value = "real.db")]
+        db: String,
+
+        #[arg(short, long, value_enum, default_value_t = StoreKind::Jammdb)]
+        store: StoreKind,
+
+        /// Carry on with this conversation instead of starting a new one
+        #[arg(long)]
+        id: Option<String>,
+
+        #[command(flatten)]
+        inference: InferenceConfig,
+
+        #[command(flatten)]
+        tokenizer: TokenizerConfig,
+    },
+    /// Let Jake respond, run the commands in the response and respond to their results
+    /// until Jake is done
+    Agent {
+        #[arg(short, long, default_value = "real.db")]
+        db: String,
+
+        #[arg(short, long, value_enum, default_value_t = StoreKind::Jammdb)]
+        store: StoreKind,
+
+        /// The conversation to carry on, otherwise a new one starts with a message
+        /// written in $EDITOR
+        #[arg(long)]
+        id: Option<String>,
+
+        #[command(flatten)]
+        agent: AgentConfig,
+
+        #[command(flatten)]
+        nexos: NexosConfig,
+
+        #[command(flatten)]
+        inference: InferenceConfig,
+
+        #[command(flatten)]
+        tokenizer: TokenizerConfig,
+    },
+    /// Search every conversation, e.g. `"could not compile" user:docker`
+    Search {
+        #[arg(short, long, default_value = "real.db")]
+        db: String,
+
+        #[arg(short, long, value_enum, default_value_t = StoreKind::Jammdb)]
+        store: StoreKind,
+
+        query: String,
+
+        /// How many messages either side of a match to print
+        #[arg(short, long, default_value_t = 0)]
+        context: usize,
     },
 }
 
+#[derive(Subcommand, Debug)]
+enum DatasetCommand {
+    /// List the samples that were added, removed or changed between two export manifests
+    Diff { old: String, new: String },
+}
+
 fn main() {
     env_logger::init(); // Log to stderr (if you run with `RUST_LOG=debug`).
     let subcommands = Cli::parse();
     println!("{:?}", subcommands);
+    if let Some(ref path) = subcommands.record {
+        cassette::install(Cassette::record(path).unwrap());
+    }
+    if let Some(ref path) = subcommands.replay {
+        cassette::install(Cassette::replay(path).unwrap());
+    }
     match subcommands.command {
-        Subcommands::Frontend { db } => {
-            make_copy(&db).unwrap();
-            launch_gui(db).unwrap()
+        Subcommands::Frontend {
+            db,
+            store,
+            nexos: nexos_config,
+            inference,
+            agent,
+            tokenizer,
+        } => {
+            make_copy(&db, store).unwrap();
+            nexos::configure(nexos_config);
+            token::configure(tokenizer);
+            launch_gui(open_store(store, &db).unwrap(), inference, agent).unwrap();
+            nexos::close_sessions();
+        }
+        Subcommands::Migrate {
+            db,
+            store,
+            copy_name,
+            dry_run,
+        } => migrate(db, store, copy_name, dry_run).unwrap(),
+        Subcommands::History {
+            db,
+            store,
+            id,
+            restore,
+        } => history(db, store, id, restore).unwrap(),
+        Subcommands::Export {
+            db,
+            store,
+            format,
+            output,
+            all_branches,
+            tags,
+            token_budget,
+            tokenizer,
+            since,
+            until,
+            users,
+            include_excluded,
+            exclude_failed,
+            val_fraction,
+            val_output,
+        } => {
+            token::configure(tokenizer);
+            let options = TrainingDataOptions {
+                branches: if all_branches {
+                    BranchExport::All
+                } else {
+                    BranchExport::Active
+                },
+                tags: tags.map(|t| parse_tags(&t)).unwrap_or_default(),
+                token_budget,
+                since: since.map(start_of_day),
+                // until is inclusive so it ends at the start of the next day
+                until: until.and_then(|d| d.succ_opt()).map(start_of_day),
+                users: users.map(|u| parse_tags(&u)).unwrap_or_default(),
+                include_excluded,
+                exclude_failed,
+            };
+            export(db, store, format, output, options, val_fraction, val_output).unwrap()
+        }
+        Subcommands::Dataset {
+            command: DatasetCommand::Diff { old, new },
+        } => dataset_diff(old, new).unwrap(),
+        Subcommands::Search {
+            db,
+            store,
+            query,
+            context,
+        } => search(db, store, query, context).unwrap(),
+        Subcommands::Chat {
+            db,
+            store,
+            id,
+            inference,
+            tokenizer,
+        } => {
+            token::configure(tokenizer);
+            chat(db, store, id, inference).unwrap()
+        }
+        Subcommands::Agent {
+            db,
+            store,
+            id,
+            agent,
+            nexos: nexos_config,
+            inference,
+            tokenizer,
+        } => {
+            nexos::configure(nexos_config);
+            token::configure(tokenizer);
+            let res = run_agent(db, store, id, agent, inference);
+            nexos::close_sessions();
+            res.unwrap()
         }
-        Subcommands::Migrate { db, copy_name } => migrate(db, copy_name).unwrap(),
         Subcommands::Test { .. } => mpty::testpty(),
 
         // Subcommands::Test { .. } => test().await,
@@ -71,23 +363,292 @@ fn main() {
         _ => todo!(),
     }
 }
-fn make_copy(db: &str) -> anyhow::Result<()> {
+fn make_copy(db: &str, store: StoreKind) -> anyhow::Result<()> {
+    if store == StoreKind::Memory {
+        return Ok(());
+    }
     std::fs::create_dir_all("backups")?;
     let backup_name = format!("backups/{}.db", Local::now().format("%Y-%m-%d-%H-%M-%S"));
     std::fs::copy(db, backup_name)?;
 
     Ok(())
 }
-fn migrate(db: String, copy_name: String) -> anyhow::Result<()> {
-    println!("making copy");
-    std::fs::copy(&db, copy_name)?;
-    let db = jammdb::DB::open(db).unwrap();
-    let mut conversations = Conversations::new(Arc::new(db), None).unwrap();
+fn migrate(db: String, store: StoreKind, copy_name: String, dry_run: bool) -> anyhow::Result<()> {
+    if store != StoreKind::Memory {
+        println!("making copy");
+        std::fs::copy(&db, copy_name)?;
+    }
+    let mut conversations = open_store(store, &db)?;
+
+    let report = migrations::run(conversations.as_mut(), dry_run)?;
+    println!("{report}");
+    if !report.failed.is_empty() {
+        anyhow::bail!("{} conversations failed to migrate", report.failed.len());
+    }
+
+    Ok(())
+}
+fn history(db: String, store: StoreKind, id: String, restore: Option<u64>) -> anyhow::Result<()> {
+    make_copy(&db, store)?;
+    let mut conversations = open_store(store, &db)?;
+    conversations.check_schema()?;
+
+    if let Some(revision) = restore {
+        conversations.restore(&id, revision)?;
+        println!("restored revision {revision}");
+    }
+    let head = conversations.history_head(&id)?;
+    for entry in conversations.history(&id)? {
+        let datetime: chrono::DateTime<chrono::offset::Local> = entry.time.into();
+        println!(
+            "{} {:>4} {} {} ({} messages)",
+            if Some(entry.revision) == head {
+                "*"
+            } else {
+                " "
+            },
+            entry.revision,
+            datetime.format("%Y-%m-%d %T"),
+            entry.description,
+            entry.conversation.messages.len()
+        );
+    }
+    Ok(())
+}
+fn parse_date(date: &str) -> Result<chrono::NaiveDate, String> {
+    chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|e| format!("{e}, use YYYY-MM-DD"))
+}
+fn start_of_day(date: chrono::NaiveDate) -> SystemTime {
+    let midnight = date.and_hms_opt(0, 0, 0).unwrap_or_default();
+    match midnight.and_local_timezone(Local).earliest() {
+        Some(time) => time.into(),
+        // the date falls in a dst gap, close enough
+        None => chrono::TimeZone::from_utc_datetime(&chrono::Utc, &midnight).into(),
+    }
+}
+fn export(
+    db: String,
+    store: StoreKind,
+    format: String,
+    output: Option<String>,
+    options: TrainingDataOptions,
+    val_fraction: f64,
+    val_output: Option<String>,
+) -> anyhow::Result<()> {
+    if !(0.0..=1.0).contains(&val_fraction) {
+        anyhow::bail!("--val-fraction has to be between 0 and 1");
+    }
+    let exporter = export::exporter(&format)?;
+    let conversations = open_store(store, &db)?;
+    conversations.check_schema()?;
+
+    let output = output.unwrap_or_else(|| exporter.default_path());
+    let mut file =
+        std::fs::File::create(&output).with_context(|| format!("failed to create {output}"))?;
+    let val_output = val_output.unwrap_or_else(|| match output.rsplit_once('.') {
+        Some((stem, ext)) => format!("{stem}.val.{ext}"),
+        None => format!("{output}.val"),
+    });
+    let mut val_file = if val_fraction > 0.0 {
+        Some(
+            std::fs::File::create(&val_output)
+                .with_context(|| format!("failed to create {val_output}"))?,
+        )
+    } else {
+        None
+    };
+    let conversations = conversations.iter().into_iter().map(|(_, c)| c);
+    let report = export::export(
+        exporter.as_ref(),
+        conversations,
+        &options,
+        &mut file,
+        val_file.as_mut().map(|f| (f, val_fraction)),
+    )?;
+    println!("{report}");
+    println!("wrote {output}");
+    let mut outputs = vec![output];
+    if val_file.is_some() {
+        println!("wrote {val_output}");
+        outputs.push(val_output);
+    }
+    let manifest =
+        export::write_manifest(exporter.as_ref(), &options, val_fraction, outputs, &report)?;
+    println!("wrote {manifest}");
+    Ok(())
+}
+fn dataset_diff(old: String, new: String) -> anyhow::Result<()> {
+    let old = manifest::Manifest::read(&old)?;
+    let new = manifest::Manifest::read(&new)?;
+    println!("{}", manifest::diff(&old, &new));
+    Ok(())
+}
+fn search(db: String, store: StoreKind, query: String, context: usize) -> anyhow::Result<()> {
+    let conversations = open_store(store, &db)?;
+    conversations.check_schema()?;
+    let conversations = search::IndexedStore::new(conversations);
 
-    for conversation in conversations.clone().into_iter() {
-        conversations.insert(&mut conversation.1.clone())?;
+    let hits = conversations.search(&query);
+    for hit in &hits {
+        let Some(conversation) = conversations.get(&hit.conversation_id)? else {
+            continue;
+        };
+        println!("== {} ==", hit.conversation_id);
+        let path = match hit.kind {
+            search::DocKind::Message { .. } => conversation.path_to(&hit.id),
+            _ => Vec::new(),
+        };
+        if path.is_empty() || context == 0 {
+            println!("{}", hit.snippet);
+            continue;
+        }
+        // the match is always the last message of the path, show what led up to it
+        // and whatever follows it on the branch it's on
+        let mut shown: Vec<Message> = path[path.len().saturating_sub(context + 1)..].to_vec();
+        let mut parent = hit.id.clone();
+        for _ in 0..context {
+            let Some(child) = conversation.selected_child(Some(&parent)) else {
+                break;
+            };
+            parent = child.id.clone();
+            shown.push(child.clone());
+        }
+        for msg in shown {
+            let marker = if msg.id == hit.id { ">" } else { " " };
+            println!("{marker} {}: {}", msg.user, msg.msg);
+        }
+    }
+    println!("{} matches", hits.len());
+    Ok(())
+}
+/// Adds a message to the end of the active branch
+fn add_message(
+    conversations: &mut dyn ConversationStore,
+    id: &str,
+    user: User,
+    msg: String,
+) -> anyhow::Result<Message> {
+    let conversation =
+        conversations.apply(id, ConversationAction::AddMessage { index: None, user })?;
+    let mut message = conversation
+        .active_messages()
+        .pop()
+        .ok_or(anyhow::anyhow!("the message wasn't added"))?;
+    message.msg = msg;
+    conversations.apply(
+        id,
+        ConversationAction::MutateMessage {
+            new_message: message.clone(),
+        },
+    )?;
+    Ok(message)
+}
+/// Starts the inference backend and waits until it can generate
+fn start_backend(
+    inference: InferenceConfig,
+) -> anyhow::Result<Arc<Mutex<Box<dyn InferenceBackend>>>> {
+    let mut manager = ServerManager::new(inference);
+    manager.start_inference(&InferenceServerArgs::default())?;
+    let backend = manager
+        .inference_server
+        .clone()
+        .context("no inference backend")?;
+    // the axolotl server takes a while to load the model
+    loop {
+        match backend.lock().unwrap().status() {
+            Ok(
+                ServerStatus::Ready {}
+                | ServerStatus::DoneGenerating { .. }
+                | ServerStatus::Failed { .. },
+            ) => break,
+            Ok(ServerStatus::Dead {}) => anyhow::bail!("the inference server died"),
+            Ok(status) => println!("waiting for the model, {status}"),
+            Err(e) => println!("waiting for the model, {e:#}"),
+        }
+        std::thread::sleep(std::time::Duration::from_secs(2));
     }
+    Ok(backend)
+}
+fn run_agent(
+    db: String,
+    store: StoreKind,
+    id: Option<String>,
+    config: AgentConfig,
+    inference: InferenceConfig,
+) -> anyhow::Result<()> {
+    make_copy(&db, store)?;
+    let mut conversations = open_store(store, &db)?;
+    conversations.check_schema()?;
+    let id = match id {
+        Some(id) => id,
+        None => {
+            let text = editor::edit_content("")?;
+            if text.trim().is_empty() {
+                anyhow::bail!("no message to start the conversation with");
+            }
+            let id = conversations.insert(&mut Conversation::default())?;
+            add_message(conversations.as_mut(), &id, User::Zack, text)?;
+            id
+        }
+    };
+    println!("running the agent in {id}");
+    let backend = start_backend(inference)?;
+    let mut backend = backend.lock().unwrap();
+    let stop = agent::run(
+        conversations.as_mut(),
+        &id,
+        &mut **backend,
+        &config,
+        |command| {
+            println!("{}\nrun it? [y/N]", agent::describe(command));
+            let mut answer = String::new();
+            std::io::stdin().read_line(&mut answer).is_ok()
+                && answer.trim().eq_ignore_ascii_case("y")
+        },
+    )?;
+    println!("agent stopped: {stop}");
+    Ok(())
+}
+/// An empty message or `exit` ends it
+fn chat(
+    db: String,
+    store: StoreKind,
+    id: Option<String>,
+    inference: InferenceConfig,
+) -> anyhow::Result<()> {
+    make_copy(&db, store)?;
+    let mut conversations = open_store(store, &db)?;
+    conversations.check_schema()?;
+    let id = match id {
+        Some(id) => id,
+        None => conversations.insert(&mut Conversation::default())?,
+    };
+    println!("chatting in {id}");
 
+    let backend = start_backend(inference)?;
+    loop {
+        let text = editor::edit_content("")?;
+        if text.trim().is_empty() || text.trim().eq_ignore_ascii_case("exit") {
+            break;
+        }
+        println!("zack: {}", text.trim());
+        add_message(conversations.as_mut(), &id, User::Zack, text)?;
+        let mut jake = add_message(conversations.as_mut(), &id, User::Jake, String::new())?;
+        let conversation = conversations
+            .get(&id)?
+            .context("the conversation is gone")?;
+        let config = GenerationConfig::for_prompt()?;
+        let budget = token::prompt_budget(config.max_new_tokens);
+        let prompt = conversation.msg_training_data(&jake.id, budget)?;
+        let resp = backend.lock().unwrap().infer_and_wait(InferReq {
+            prompt: prompt.text,
+            config,
+        })?;
+        println!("{resp}");
+        jake.msg = editor::edit_content(&resp.completion)?;
+        println!("jake: {}", jake.msg.trim());
+        conversations.apply(&id, ConversationAction::MutateMessage { new_message: jake })?;
+    }
     Ok(())
 }
 async fn server() -> anyhow::Result<()> {
@@ -118,52 +679,3 @@ async fn test() {
     dbg!("{:?}", &resp);
     println!("{}", serde_json::to_string(&resp).unwrap());
 }
-// async fn test() {
-//     let mut conversation = Conversation::default();
-//     let db = jammdb::DB::open("real.db").unwrap();
-//     let mut conversations = Conversations::new(Arc::new(db), None).unwrap();
-
-//     let mut serv = InferenceServer::new("http://localhost:9090");
-
-//     for k in conversations.clone().into_iter() {
-//         println!("iter: {:?}", k);
-//     }
-//     loop {
-//         let my_msg = editor::edit_content("").unwrap();
-//         if my_msg.to_lowercase() == "exit" {
-//             break;
-//         }
-//         conversation.messages.push(Message::UserMessage {
-//             user: User::Zack,
-//             msg: my_msg.clone(),
-//             time: SystemTime::now(),
-//         });
-
-//         conversations.insert(&mut conversation).unwrap();
-//         let prompt = templates::prompt(&templates::PromptDetails {
-//             conversation: messages_tostr(conversation.messages.as_slice())
-//                 .unwrap()
-//                 .into(),
-//         })
-//         .unwrap();
-//         println!("{}", prompt);
-//         let infer = serv
-//             .infer(InferReq {
-//                 prompt: my_msg,
-//                 config: GenerationConfig::default(),
-//             })
-//             .await
-//             .unwrap();
-//         println!("{:?}", &infer);
-//         let jake_msg = editor::edit_content(infer.completion).unwrap();
-//         conversation.messages.push(Message::UserMessage {
-//             user: User::Jake,
-//             msg: jake_msg,
-//             time: SystemTime::now(),
-//         });
-//         conversations.insert(&mut conversation).unwrap();
-//     }
-//     for (_, c) in conversations.into_iter() {
-//         println!("{:+?}", c.to_training_data())
-//     }
-// }
@@ -0,0 +1,257 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+use crate::conversation::{Conversation, TrainingDataOptions};
+use crate::templates;
+
+/// The templates that can end up in an export
+pub const TRACKED_TEMPLATES: [&str; 3] = [
+    "prompt.template",
+    "injested_file.template",
+    "tool_result.template",
+];
+
+pub fn sha256(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// Hash of everything in the conversation. Goes through a json Value because its
+/// maps are sorted, unlike the HashMaps in the conversation itself
+pub fn conversation_hash(conversation: &Conversation) -> Result<String> {
+    let value = serde_json::to_value(conversation)?;
+    Ok(sha256(serde_json::to_string(&value)?.as_bytes()))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Split {
+    Train,
+    Validation,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ManifestConversation {
+    pub id: String,
+    pub hash: String,
+    pub split: Split,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ManifestSample {
+    pub conversation_id: String,
+    /// The jake message the sample came from
+    pub source_id: Option<String>,
+    /// Position among the samples of the conversation, tells apart samples without a source
+    pub index: usize,
+    /// Hash of the jsonl line
+    pub hash: String,
+    pub split: Split,
+}
+
+impl ManifestSample {
+    /// What identifies "the same sample" between two exports
+    pub fn key(&self) -> String {
+        match self.source_id {
+            Some(ref source_id) => format!("{}/{}", self.conversation_id, source_id),
+            None => format!("{}/#{}", self.conversation_id, self.index),
+        }
+    }
+}
+
+/// Everything needed to tell later what went into an export
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    pub created: SystemTime,
+    pub format: String,
+    pub options: TrainingDataOptions,
+    pub validation_fraction: f64,
+    pub outputs: Vec<String>,
+    /// `git describe` of the tree the export ran from, with -dirty if it had changes
+    pub git_revision: Option<String>,
+    /// template name -> sha256, None when the template doesn't exist
+    pub templates: BTreeMap<String, Option<String>>,
+    pub conversations: Vec<ManifestConversation>,
+    pub samples: Vec<ManifestSample>,
+}
+
+impl Manifest {
+    pub fn new(format: &str, options: &TrainingDataOptions, validation_fraction: f64) -> Self {
+        Self {
+            created: SystemTime::now(),
+            format: format.to_string(),
+            options: options.clone(),
+            validation_fraction,
+            outputs: Vec::new(),
+            git_revision: git_revision(),
+            templates: template_hashes(),
+            conversations: Vec::new(),
+            samples: Vec::new(),
+        }
+    }
+    pub fn read(path: &str) -> Result<Self> {
+        let file = std::fs::File::open(path).with_context(|| format!("failed to open {path}"))?;
+        serde_json::from_reader(file).with_context(|| format!("failed to parse manifest {path}"))
+    }
+    pub fn write(&self, path: &str) -> Result<()> {
+        let file =
+            std::fs::File::create(path).with_context(|| format!("failed to create {path}"))?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
+
+/// `data.jsonl` -> `data.manifest.json`
+pub fn manifest_path(output: &str) -> String {
+    match output.rsplit_once('.') {
+        Some((stem, _)) => format!("{stem}.manifest.json"),
+        None => format!("{output}.manifest.json"),
+    }
+}
+
+pub fn git_revision() -> Option<String> {
+    let output = std::process::Command::new("git")
+        .args(["describe", "--always", "--dirty", "--abbrev=40"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+pub fn template_hashes() -> BTreeMap<String, Option<String>> {
+    TRACKED_TEMPLATES
+        .iter()
+        .map(|name| {
+            let path = Path::new(templates::TEMPLATE_DIR).join(name);
+            let hash = std::fs::read(path).ok().map(|bytes| sha256(&bytes));
+            (name.to_string(), hash)
+        })
+        .collect()
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ManifestDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+    /// Other differences worth knowing about, templates, options and so on
+    pub notes: Vec<String>,
+}
+
+impl std::fmt::Display for ManifestDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for note in &self.notes {
+            writeln!(f, "{note}")?;
+        }
+        for key in &self.added {
+            writeln!(f, "+ {key}")?;
+        }
+        for key in &self.removed {
+            writeln!(f, "- {key}")?;
+        }
+        for key in &self.changed {
+            writeln!(f, "~ {key}")?;
+        }
+        write!(
+            f,
+            "{} added, {} removed, {} changed",
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len()
+        )
+    }
+}
+
+pub fn diff(old: &Manifest, new: &Manifest) -> ManifestDiff {
+    let mut result = ManifestDiff::default();
+    if old.format != new.format {
+        result
+            .notes
+            .push(format!("format {} -> {}", old.format, new.format));
+    }
+    if old.git_revision != new.git_revision {
+        result.notes.push(format!(
+            "git revision {} -> {}",
+            old.git_revision.as_deref().unwrap_or("unknown"),
+            new.git_revision.as_deref().unwrap_or("unknown")
+        ));
+    }
+    for (name, hash) in &new.templates {
+        if old.templates.get(name) != Some(hash) {
+            result.notes.push(format!("{name} changed"));
+        }
+    }
+    if old.options != new.options || old.validation_fraction != new.validation_fraction {
+        result.notes.push("export options changed".to_string());
+    }
+
+    let old_samples: BTreeMap<String, &ManifestSample> =
+        old.samples.iter().map(|s| (s.key(), s)).collect();
+    let new_samples: BTreeMap<String, &ManifestSample> =
+        new.samples.iter().map(|s| (s.key(), s)).collect();
+    for (key, sample) in &new_samples {
+        match old_samples.get(key) {
+            None => result.added.push(key.clone()),
+            Some(old) if old.hash != sample.hash || old.split != sample.split => {
+                result.changed.push(key.clone())
+            }
+            Some(_) => {}
+        }
+    }
+    for key in old_samples.keys() {
+        if !new_samples.contains_key(key) {
+            result.removed.push(key.clone());
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(source_id: &str, hash: &str) -> ManifestSample {
+        ManifestSample {
+            conversation_id: "c".into(),
+            source_id: Some(source_id.into()),
+            index: 0,
+            hash: hash.into(),
+            split: Split::Train,
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut old = Manifest::new("completion", &TrainingDataOptions::default(), 0.0);
+        old.samples = vec![sample("a", "1"), sample("b", "2")];
+        let mut new = old.clone();
+        new.samples = vec![sample("b", "3"), sample("c", "4")];
+
+        let diff = diff(&old, &new);
+        assert_eq!(diff.added, vec!["c/c"]);
+        assert_eq!(diff.removed, vec!["c/a"]);
+        assert_eq!(diff.changed, vec!["c/b"]);
+        assert!(diff.notes.is_empty());
+    }
+
+    #[test]
+    fn test_conversation_hash_is_stable() {
+        let mut a = Conversation::default();
+        let mut b = a.clone();
+        for i in 0..20 {
+            a.selected_children
+                .insert(format!("parent{i}"), format!("child{i}"));
+            b.selected_children
+                .insert(format!("parent{}", 19 - i), format!("child{}", 19 - i));
+        }
+        assert_eq!(
+            conversation_hash(&a).unwrap(),
+            conversation_hash(&b).unwrap()
+        );
+    }
+}
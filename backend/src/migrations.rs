@@ -0,0 +1,389 @@
+use std::time::SystemTime;
+
+use anyhow::{anyhow, bail, Context};
+use jammdb::{Error as JammError, DB};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::conversation::Conversation;
+use crate::history::HistoryEntry;
+use crate::store::ConversationStore;
+
+pub const META_BUCKET: &str = "meta";
+pub const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// A single step in the schema history of the stored conversations.
+///
+/// Steps operate on the record as a `serde_json::Value` (the same shape that
+/// `serde_json::to_value(&Conversation)` produces) so that a step can fix up
+/// records that the current `Conversation` struct would refuse to decode.
+/// The conversation in every history entry goes through the same steps.
+pub struct Migration {
+    /// The schema version the record is at after this step runs
+    pub version: u32,
+    pub description: &'static str,
+    pub apply: fn(&mut Value) -> anyhow::Result<()>,
+}
+
+/// All migrations in the order they must be applied.
+/// Never edit or reorder an existing entry, only append new ones.
+pub fn migrations() -> Vec<Migration> {
+    vec![
+        Migration {
+            version: 1,
+            description: "re-encode conversations with named fields instead of positional arrays",
+            // The work for this one happens in decode_record: v0 records can only be read
+            // through the frozen v0 structs because rmp_serde::to_vec wrote structs as arrays.
+            apply: |_| Ok(()),
+        },
+        Migration {
+            version: 2,
+            description: "link the flat message list into a message tree",
+            apply: link_message_tree,
+        },
+    ]
+}
+
+fn link_message_tree(record: &mut Value) -> anyhow::Result<()> {
+    let messages = record["messages"]
+        .as_array_mut()
+        .ok_or(anyhow!("conversation has no messages array"))?;
+    let mut prev: Option<Value> = None;
+    for msg in messages.iter_mut() {
+        let msg = msg
+            .as_object_mut()
+            .ok_or(anyhow!("message is not an object"))?;
+        msg.insert("parent".to_string(), prev.clone().unwrap_or(Value::Null));
+        prev = msg.get("id").cloned();
+    }
+    Ok(())
+}
+
+pub fn current_version() -> u32 {
+    migrations().iter().map(|m| m.version).max().unwrap_or(0)
+}
+
+pub fn read_version(db: &DB) -> anyhow::Result<Option<u32>> {
+    let tx = db.tx(false)?;
+    let bucket = match tx.get_bucket(META_BUCKET) {
+        Ok(bucket) => bucket,
+        Err(JammError::BucketMissing) => return Ok(None),
+        Err(e) => bail!("failed to open meta bucket {e}"),
+    };
+    let data = match bucket.get(SCHEMA_VERSION_KEY) {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+    let version = std::str::from_utf8(data.kv().value())
+        .context("schema version is not utf8")?
+        .parse::<u32>()
+        .context("schema version is not a number")?;
+    Ok(Some(version))
+}
+
+pub fn write_version(tx: &jammdb::Tx, version: u32) -> anyhow::Result<()> {
+    let bucket = match tx.create_bucket(META_BUCKET) {
+        Ok(bucket) => bucket,
+        Err(JammError::BucketExists) => tx.get_bucket(META_BUCKET)?,
+        Err(e) => bail!("failed to create meta bucket {e}"),
+    };
+    bucket.put(SCHEMA_VERSION_KEY, version.to_string().into_bytes())?;
+    Ok(())
+}
+
+// The conversation as it was stored before migrations existed. v0 records are positional
+// arrays so they can only be read with exactly these fields in exactly this order, never
+// change them along with `Conversation`.
+#[derive(Serialize, Deserialize)]
+struct ConversationV0 {
+    id: Option<String>,
+    messages: Vec<MessageV0>,
+    #[serde(default = "SystemTime::now")]
+    time: SystemTime,
+    injected_files: Vec<InjectedFileV0>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MessageV0 {
+    time: SystemTime,
+    meta: MetadataV0,
+    user: UserV0,
+    msg: String,
+    id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MetadataV0 {
+    task_actions: Vec<TaskActionV0>,
+    omit_history_until: Option<String>,
+    exclude_from_training: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+enum TaskActionV0 {
+    Create { id: String, name: String },
+    Enter { id: String },
+    Exit { id: String, summary: String },
+}
+
+#[derive(Serialize, Deserialize)]
+enum UserV0 {
+    Jake,
+    Zack,
+    Docker,
+    System,
+    TaskReport { creator: Box<UserV0> },
+}
+
+#[derive(Serialize, Deserialize)]
+struct InjectedFileV0 {
+    id: String,
+    msg_id: String,
+    time: SystemTime,
+    filename: String,
+    context: String,
+    filetext: String,
+}
+
+/// Turns the stored bytes of a record at `version` into the json shape the migrations work on
+fn decode_record(bytes: &[u8], version: u32) -> anyhow::Result<Value> {
+    if version == 0 {
+        let conversation: ConversationV0 =
+            rmp_serde::from_slice(bytes).context("failed to decode v0 conversation")?;
+        return Ok(serde_json::to_value(conversation)?);
+    }
+    rmp_serde::from_slice::<Value>(bytes).context("failed to decode conversation")
+}
+
+fn apply_migrations(record: &mut Value, version: u32) -> anyhow::Result<()> {
+    for migration in migrations().iter().filter(|m| m.version > version) {
+        (migration.apply)(record)
+            .with_context(|| format!("migration to v{} failed", migration.version))?;
+    }
+    Ok(())
+}
+
+/// Runs every migration newer than `version` over a single record and returns the bytes to store
+pub fn migrate_record(bytes: &[u8], version: u32) -> anyhow::Result<Vec<u8>> {
+    let mut record = decode_record(bytes, version)?;
+    apply_migrations(&mut record, version)?;
+    // make sure the result is something the rest of the program can actually read
+    let conversation: Conversation = serde_json::from_value(record)
+        .context("migrated record does not decode as a conversation")?;
+    rmp_serde::to_vec_named(&conversation).context("failed to encode migrated conversation")
+}
+
+/// Runs every migration newer than `version` over the conversation a history entry holds.
+/// History came after named records so entries never need decode_record's v0 handling
+pub fn migrate_history_entry(bytes: &[u8], version: u32) -> anyhow::Result<HistoryEntry> {
+    let mut entry =
+        rmp_serde::from_slice::<Value>(bytes).context("failed to decode history entry")?;
+    let conversation = entry
+        .get_mut("conversation")
+        .ok_or(anyhow!("history entry has no conversation"))?;
+    apply_migrations(conversation, version)?;
+    serde_json::from_value(entry).context("migrated history entry does not decode")
+}
+
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    pub from: u32,
+    pub to: u32,
+    pub steps: Vec<String>,
+    pub migrated: Vec<String>,
+    pub history_entries: usize,
+    pub failed: Vec<(String, String)>,
+    pub committed: bool,
+}
+
+impl std::fmt::Display for MigrationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "schema v{} -> v{}", self.from, self.to)?;
+        for step in &self.steps {
+            writeln!(f, "\t{step}")?;
+        }
+        writeln!(
+            f,
+            "{} conversations migrated with {} history entries",
+            self.migrated.len(),
+            self.history_entries
+        )?;
+        for (id, err) in &self.failed {
+            writeln!(f, "FAILED {id}: {err}")?;
+        }
+        if self.committed {
+            write!(f, "changes committed")
+        } else {
+            write!(f, "nothing was written")
+        }
+    }
+}
+
+/// Migrates every record in the store to the current schema version.
+///
+/// Records are all migrated in memory first and only written (in one transaction)
+/// if every one of them succeeded, so a failure or a `dry_run` leaves the store untouched.
+pub fn run(store: &mut dyn ConversationStore, dry_run: bool) -> anyhow::Result<MigrationReport> {
+    let from = store.schema_version()?.unwrap_or(0);
+    let to = current_version();
+    let mut report = MigrationReport {
+        from,
+        to,
+        steps: migrations()
+            .iter()
+            .filter(|m| m.version > from)
+            .map(|m| format!("v{}: {}", m.version, m.description))
+            .collect(),
+        ..Default::default()
+    };
+    if from > to {
+        bail!("db is at schema v{from} but this build only knows up to v{to}");
+    }
+    if from == to {
+        return Ok(report);
+    }
+
+    let mut migrated = Vec::new();
+    let mut history = Vec::new();
+    for (id, bytes) in store.raw_records()? {
+        let entries = store.raw_history(&id).and_then(|entries| {
+            entries
+                .iter()
+                .map(|entry| migrate_history_entry(entry, from))
+                .collect::<anyhow::Result<Vec<HistoryEntry>>>()
+                .context("failed to migrate history")
+        });
+        match migrate_record(&bytes, from).and_then(|new_bytes| Ok((new_bytes, entries?))) {
+            Ok((new_bytes, entries)) => {
+                report.migrated.push(id.clone());
+                report.history_entries += entries.len();
+                migrated.push((id.clone(), new_bytes));
+                history.push((id, entries));
+            }
+            Err(e) => report.failed.push((id, format!("{e:#}"))),
+        }
+    }
+    if dry_run || !report.failed.is_empty() {
+        return Ok(report);
+    }
+    store.write_migrated(migrated, history, to)?;
+    report.committed = true;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::conversation::{Conversations, User};
+    use crate::history::HISTORY_BUCKET;
+
+    fn v0_record(users: Vec<UserV0>) -> ConversationV0 {
+        ConversationV0 {
+            id: Some("abc".into()),
+            messages: users
+                .into_iter()
+                .map(|user| MessageV0 {
+                    time: SystemTime::now(),
+                    meta: MetadataV0 {
+                        task_actions: Vec::new(),
+                        omit_history_until: None,
+                        exclude_from_training: false,
+                    },
+                    user,
+                    msg: "hi".into(),
+                    id: uuid::Uuid::new_v4().to_string(),
+                })
+                .collect(),
+            time: SystemTime::now(),
+            injected_files: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_migrate_v0_record() {
+        let conversation = v0_record(vec![UserV0::TaskReport {
+            creator: Box::new(UserV0::Jake),
+        }]);
+        let legacy = rmp_serde::to_vec(&conversation).unwrap();
+
+        let migrated = migrate_record(&legacy, 0).unwrap();
+        let decoded: Conversation = rmp_serde::from_slice(&migrated).unwrap();
+        assert_eq!(decoded.id, conversation.id);
+        assert_eq!(decoded.time, conversation.time);
+        assert_eq!(decoded.messages.len(), 1);
+        assert_eq!(decoded.messages[0].id, conversation.messages[0].id);
+        assert_eq!(decoded.messages[0].msg, "hi");
+        assert_eq!(
+            decoded.messages[0].user,
+            User::TaskReport {
+                creator: Box::new(User::Jake)
+            }
+        );
+        // named records must be readable by the json based migrations
+        assert!(rmp_serde::from_slice::<Value>(&migrated).unwrap()["messages"].is_array());
+    }
+
+    #[test]
+    fn test_link_message_tree() {
+        let conversation = v0_record(vec![UserV0::Zack, UserV0::Jake, UserV0::Zack]);
+        let ids: Vec<String> = conversation.messages.iter().map(|m| m.id.clone()).collect();
+        let legacy = rmp_serde::to_vec(&conversation).unwrap();
+
+        let migrated: Conversation =
+            rmp_serde::from_slice(&migrate_record(&legacy, 0).unwrap()).unwrap();
+        let active: Vec<String> = migrated
+            .active_messages()
+            .iter()
+            .map(|m| m.id.clone())
+            .collect();
+        assert_eq!(active, ids);
+        assert_eq!(migrated.messages[2].parent, Some(ids[1].clone()));
+    }
+
+    #[test]
+    fn test_migrate_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Arc::new(DB::open(dir.path().join("migrate.db")).unwrap());
+        let mut conversations = Conversations::new(db.clone(), None).unwrap();
+        // a v1 record from before the message tree that already has a revision
+        let conversation = v0_record(vec![UserV0::Zack, UserV0::Jake]);
+        let ids: Vec<String> = conversation.messages.iter().map(|m| m.id.clone()).collect();
+        let entry = serde_json::json!({
+            "revision": 0,
+            "time": SystemTime::now(),
+            "action": null,
+            "description": "initial state",
+            "conversation": &conversation,
+        });
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.get_bucket("conversations").unwrap();
+        bucket
+            .put("abc", rmp_serde::to_vec_named(&conversation).unwrap())
+            .unwrap();
+        let history = tx.get_or_create_bucket(HISTORY_BUCKET).unwrap();
+        history
+            .get_or_create_bucket("abc")
+            .unwrap()
+            .put(
+                0u64.to_be_bytes().to_vec(),
+                rmp_serde::to_vec_named(&entry).unwrap(),
+            )
+            .unwrap();
+        write_version(&tx, 1).unwrap();
+        tx.commit().unwrap();
+        let history = conversations.history("abc").unwrap();
+        assert_eq!(history[0].conversation.messages[1].parent, None);
+
+        let report = run(&mut conversations, false).unwrap();
+        assert!(report.committed, "{report}");
+        assert_eq!(report.history_entries, 1);
+        conversations.check_schema().unwrap();
+        let history = conversations.history("abc").unwrap();
+        assert_eq!(history.len(), 1);
+        let migrated = &history[0].conversation;
+        assert_eq!(migrated.messages[1].parent, Some(ids[0].clone()));
+        assert_eq!(Some(migrated), conversations.get("abc").unwrap().as_ref());
+    }
+}
@@ -1,11 +1,14 @@
 use std::fmt;
+use std::future::Future;
 use std::path::PathBuf;
 use std::process::{Command, Stdio};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::{Duration, SystemTime};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
 
+use crate::cassette::{self, Call, Interaction};
+use crate::openai::OpenAiBackend;
 use crate::templates;
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
@@ -51,6 +54,10 @@ pub struct GenerationConfig {
 
     /// Whether to output scores in generation.
     pub output_scores: bool,
+
+    /// Generation stops at the first of these and the completion ends before it.
+    #[serde(default)]
+    pub stop_sequences: Vec<String>,
 }
 
 impl Default for GenerationConfig {
@@ -67,10 +74,21 @@ impl Default for GenerationConfig {
             output_attentions: false,
             output_hidden_states: false,
             output_scores: false,
+            stop_sequences: Vec::new(),
         }
     }
 }
 
+impl GenerationConfig {
+    /// The defaults with the stop sequences of the prompt template
+    pub fn for_prompt() -> anyhow::Result<Self> {
+        Ok(Self {
+            stop_sequences: templates::default_stop_sequences()?,
+            ..Default::default()
+        })
+    }
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct StatusReq {}
 
@@ -85,26 +103,258 @@ pub struct StopReq {}
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct StopResp {}
 
-#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
-pub struct InferResp {}
+/// Why a generation ended
+#[derive(
+    serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, strum_macros::Display,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum FinishReason {
+    Eos,
+    MaxNewTokens,
+    /// stop was called
+    Stopped,
+    StopSequence,
+}
+
+/// A finished generation
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct InferResp {
+    /// Made up by whatever did the generating, for finding it in its logs
+    pub job_id: String,
+    pub completion: String,
+    pub prompt_tokens: usize,
+    /// What the server generated, including anything `trim_stop_sequences` cut off
+    pub completion_tokens: usize,
+    pub finish_reason: FinishReason,
+    /// From the request getting there to the last token
+    pub duration_ms: u64,
+}
+
+impl fmt::Display for InferResp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} tokens after a {} token prompt in {:.1}s, ended by {}",
+            self.completion_tokens,
+            self.prompt_tokens,
+            self.duration_ms as f64 / 1000.0,
+            self.finish_reason
+        )
+    }
+}
+
+/// A piece of a generation as it comes in
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum InferEvent {
+    Token { text: String },
+    Done(InferResp),
+    Failed { error: String },
+}
+
+/// What `infer` hands back. The generation is over after Done or Failed, or when the
+/// sending side hangs up
+pub type TokenStream = std::sync::mpsc::Receiver<InferEvent>;
+
+/// Something that generates text. `infer` starts generating in the background and the
+/// text comes through the returned stream as it is made
+pub trait InferenceBackend: Send {
+    /// Whether the backend is up and what it is doing, for the frontend
+    fn status(&mut self) -> anyhow::Result<&ServerStatus>;
+    fn infer(&mut self, body: InferReq) -> anyhow::Result<TokenStream>;
+    fn stop(&mut self) -> anyhow::Result<StopResp>;
+    /// What to call it in the frontend
+    fn name(&self) -> String;
+
+    /// Generates to the end, for when there is nothing to show until then
+    fn infer_and_wait(&mut self, body: InferReq) -> anyhow::Result<InferResp> {
+        wait(self.infer(body)?)
+    }
+}
+
+/// Cuts the completion at the first stop sequence in it. The servers stop on them but
+/// not every one cuts them off, and some only check every few tokens. The token count
+/// is left alone since the tokens were generated either way
+pub fn trim_stop_sequences(resp: &mut InferResp, stop_sequences: &[String]) {
+    let cut = stop_sequences
+        .iter()
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| resp.completion.find(s.as_str()))
+        .min();
+    if let Some(cut) = cut {
+        resp.completion.truncate(cut);
+        resp.finish_reason = FinishReason::StopSequence;
+    }
+}
+
+/// Skips the tokens of a generation and returns how it ended
+pub fn wait(tokens: TokenStream) -> anyhow::Result<InferResp> {
+    for event in tokens {
+        match event {
+            InferEvent::Token { .. } => {}
+            InferEvent::Done(resp) => return Ok(resp),
+            InferEvent::Failed { error } => bail!("generation failed: {error}"),
+        }
+    }
+    bail!("the generation ended without finishing")
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum InferenceKind {
+    /// core/main.py in the jake-axolotl image
+    #[default]
+    Axolotl,
+    /// Anything with an OpenAI style /v1/completions, llama.cpp's server, vLLM and so on
+    OpenAi,
+    /// Canned responses, for trying things out without a model
+    Mock,
+}
+
+#[derive(clap::Args, Clone, Debug, PartialEq)]
+pub struct InferenceConfig {
+    /// What the infer button runs on
+    #[arg(long, value_enum, default_value_t = InferenceKind::Axolotl)]
+    pub inference_backend: InferenceKind,
+    /// Where the OpenAI compatible server is, without the /v1. The api key is taken
+    /// from OPENAI_API_KEY if it is set
+    #[arg(long, default_value = "http://localhost:8000")]
+    pub inference_url: String,
+    /// The model the OpenAI compatible server is asked for
+    #[arg(long, default_value = "default")]
+    pub inference_model: String,
+    /// A file with one json string per line that the mock backend answers with, in order
+    #[arg(long)]
+    pub mock_responses: Option<String>,
+}
+
+impl Default for InferenceConfig {
+    fn default() -> Self {
+        Self {
+            inference_backend: InferenceKind::default(),
+            inference_url: "http://localhost:8000".to_string(),
+            inference_model: "default".to_string(),
+            mock_responses: None,
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct ServerManager {
-    pub inference_server: Option<Arc<Mutex<InferenceServer>>>,
+    pub config: InferenceConfig,
+    pub inference_server: Option<Arc<Mutex<Box<dyn InferenceBackend>>>>,
 }
 impl ServerManager {
+    pub fn new(config: InferenceConfig) -> Self {
+        Self {
+            config,
+            inference_server: None,
+        }
+    }
+    /// `args` are only used by the axolotl server
     pub fn start_inference(&mut self, args: &InferenceServerArgs) -> anyhow::Result<()> {
-        match self.inference_server {
-            Some(_) => {}
-            None => {
-                let srv =
-                    InferenceServer::start(args).context("failed to start inference server")?;
-                self.inference_server = Some(Arc::new(Mutex::new(srv)))
-            }
+        if self.inference_server.is_some() {
+            return Ok(());
         }
+        let backend: Box<dyn InferenceBackend> = match self.config.inference_backend {
+            InferenceKind::Axolotl => {
+                Box::new(InferenceServer::start(args).context("failed to start inference server")?)
+            }
+            InferenceKind::OpenAi => Box::new(OpenAiBackend::new(
+                &self.config.inference_url,
+                &self.config.inference_model,
+                std::env::var("OPENAI_API_KEY").ok(),
+            )),
+            InferenceKind::Mock => Box::new(match self.config.mock_responses {
+                Some(ref path) => MockBackend::from_file(path)?,
+                None => MockBackend::default(),
+            }),
+        };
+        self.inference_server = Some(Arc::new(Mutex::new(backend)));
         Ok(())
     }
 }
 
+/// Answers with `responses` in order and then with a description of the prompt, the same
+/// way every time
+#[derive(Default)]
+pub struct MockBackend {
+    responses: Vec<String>,
+    next: usize,
+    status: ServerStatus,
+}
+
+impl MockBackend {
+    pub fn new(responses: Vec<String>) -> Self {
+        Self {
+            responses,
+            ..Default::default()
+        }
+    }
+
+    pub fn from_file(path: &str) -> anyhow::Result<Self> {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("failed to read {path}"))?;
+        let responses = text
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .enumerate()
+            .map(|(i, line)| {
+                serde_json::from_str(line)
+                    .with_context(|| format!("line {} of {path} isn't a json string", i + 1))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self::new(responses))
+    }
+}
+
+impl InferenceBackend for MockBackend {
+    fn status(&mut self) -> anyhow::Result<&ServerStatus> {
+        Ok(&self.status)
+    }
+
+    /// The response comes a word at a time, like it would from a model. Tokens are
+    /// counted as words
+    fn infer(&mut self, body: InferReq) -> anyhow::Result<TokenStream> {
+        let text = match self.responses.get(self.next) {
+            Some(response) => response.clone(),
+            None => format!(
+                "mock response {} to a {} character prompt",
+                self.next + 1,
+                body.prompt.len()
+            ),
+        };
+        self.next += 1;
+        let (tx, rx) = std::sync::mpsc::channel();
+        for word in text.split_inclusive(' ') {
+            let _ = tx.send(InferEvent::Token {
+                text: word.to_string(),
+            });
+        }
+        let mut resp = InferResp {
+            job_id: format!("mock-{}", self.next),
+            prompt_tokens: body.prompt.split_whitespace().count(),
+            completion_tokens: text.split_whitespace().count(),
+            completion: text.clone(),
+            finish_reason: FinishReason::Eos,
+            duration_ms: 0,
+        };
+        trim_stop_sequences(&mut resp, &body.config.stop_sequences);
+        let _ = tx.send(InferEvent::Done(resp));
+        self.status = ServerStatus::DoneGenerating { text };
+        Ok(rx)
+    }
+
+    fn stop(&mut self) -> anyhow::Result<StopResp> {
+        self.status = ServerStatus::Ready {};
+        Ok(StopResp {})
+    }
+
+    fn name(&self) -> String {
+        "Mock".to_string()
+    }
+}
+
 pub struct InferenceServer {
     process_handle: std::process::Child,
     config: InferenceServerArgs,
@@ -118,6 +368,16 @@ pub struct InferenceServerArgs {
     pub image_name: String,
     pub port: usize,
 }
+
+impl Default for InferenceServerArgs {
+    fn default() -> Self {
+        Self {
+            model_config: ".".into(),
+            image_name: "fuck".into(),
+            port: 9090,
+        }
+    }
+}
 // The empty brackets are important so that serde includes them as an empty map
 #[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize, strum_macros::Display)]
 #[serde(tag = "status", content = "body")]
@@ -125,11 +385,40 @@ pub struct InferenceServerArgs {
 pub enum ServerStatus {
     Starting {},
     Loading {},
-    Generating { text: String },
-    DoneGenerating { text: String },
+    Generating {
+        text: String,
+    },
+    DoneGenerating {
+        text: String,
+    },
     Ready {},
     Busy {},
     Dead {},
+    /// The last generation didn't work, the backend can be used again
+    Failed {
+        error: String,
+    },
+}
+
+impl Default for ServerStatus {
+    fn default() -> Self {
+        ServerStatus::Ready {}
+    }
+}
+
+impl InferenceBackend for InferenceServer {
+    fn status(&mut self) -> anyhow::Result<&ServerStatus> {
+        InferenceServer::status(self)
+    }
+    fn infer(&mut self, body: InferReq) -> anyhow::Result<TokenStream> {
+        InferenceServer::infer(self, body)
+    }
+    fn stop(&mut self) -> anyhow::Result<StopResp> {
+        InferenceServer::stop(self)
+    }
+    fn name(&self) -> String {
+        format!("Inference server on port {}", self.config.port)
+    }
 }
 
 impl InferenceServer {
@@ -161,32 +450,52 @@ impl InferenceServer {
         }
         Ok(&self.status)
     }
-    pub fn infer(&mut self, body: InferReq) -> anyhow::Result<InferResp> {
-        let rt = tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()?;
-
-        // Call the asynchronous connect method using the runtime.
-        return rt.block_on(self.inferreq(body));
+    /// Streams from /infer_stream, see core/main.py
+    pub fn infer(&mut self, body: InferReq) -> anyhow::Result<TokenStream> {
+        let call = Call::Request {
+            route: "infer_stream".to_string(),
+            body: serde_json::to_value(&body)?,
+        };
+        let req = client()
+            .post(format!("{}/infer_stream", self.get_url()))
+            .json(&body);
+        let stop_sequences = body.config.stop_sequences;
+        self.status = ServerStatus::Generating {
+            text: String::new(),
+        };
+        let (tokens, _) = spawn_stream(call, |sink| {
+            stream_sse(req, move |event| {
+                let data: serde_json::Value = serde_json::from_str(&event.data)
+                    .with_context(|| format!("bad event {:?}", event.data))?;
+                Ok(match event.event.as_deref() {
+                    Some("done") => {
+                        let mut resp: InferResp = serde_json::from_value(data)?;
+                        trim_stop_sequences(&mut resp, &stop_sequences);
+                        let _ = sink.send(InferEvent::Done(resp));
+                        false
+                    }
+                    Some("error") => {
+                        let error = data["error"].as_str().unwrap_or("unknown error");
+                        let _ = sink.send(InferEvent::Failed {
+                            error: error.to_string(),
+                        });
+                        false
+                    }
+                    _ => sink.send(InferEvent::Token {
+                        text: data["text"].as_str().unwrap_or_default().to_string(),
+                    }),
+                })
+            })
+        });
+        Ok(tokens)
     }
 
     pub fn stop(&mut self) -> anyhow::Result<StopResp> {
-        let rt = tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()?;
-
-        // Call the asynchronous connect method using the runtime.
-        return rt.block_on(self.stop_req(StopReq {}));
+        runtime().block_on(self.stop_req(StopReq {}))
     }
     fn internal_get_status(&mut self) -> anyhow::Result<ServerStatus> {
         println!("checking status");
-
-        let rt = tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()?;
-
-        // Call the asynchronous connect method using the runtime.
-        let resp = rt.block_on(self.status_req());
+        let resp = runtime().block_on(self.status_req());
         if let Ok(resp) = resp {
             return Ok(resp.body);
         } else {
@@ -207,10 +516,6 @@ impl InferenceServer {
     pub async fn status_req(&mut self) -> anyhow::Result<StatusResp> {
         runreq(self.get_url(), "status", StatusReq {}).await
     }
-    pub async fn inferreq(&mut self, body: InferReq) -> anyhow::Result<InferResp> {
-        runreq(self.get_url(), "infer", body).await
-    }
-
     pub async fn stop_req(&mut self, body: StopReq) -> anyhow::Result<StopResp> {
         runreq(self.get_url(), "stop", body).await
     }
@@ -227,9 +532,47 @@ pub async fn runreq<S: AsRef<str>, B: serde::Serialize, T: for<'de> serde::Deser
     route: S,
     req: B,
 ) -> anyhow::Result<T> {
+    let call = Call::Request {
+        route: route.as_ref().to_string(),
+        body: serde_json::to_value(&req)?,
+    };
+    let value = recorded(call, send_req(url, route, req)).await?;
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Answers `call` from the installed cassette when replaying, otherwise runs `send` and
+/// records what came back if a cassette is recording
+pub async fn recorded(
+    call: Call,
+    send: impl Future<Output = anyhow::Result<serde_json::Value>>,
+) -> anyhow::Result<serde_json::Value> {
+    let cassette = cassette::current();
+    if let Some(ref cassette) = cassette {
+        if cassette.lock().unwrap().mode == cassette::Mode::Replay {
+            return cassette.lock().unwrap().play(&call);
+        }
+    }
+    let result = send.await;
+    if let Some(cassette) = cassette {
+        let outcome = match result {
+            Ok(ref value) => Ok(value.clone()),
+            Err(ref e) => Err(format!("{e:#}")),
+        };
+        cassette
+            .lock()
+            .unwrap()
+            .save(Interaction { call, outcome })?;
+    }
+    result
+}
+
+async fn send_req<S: AsRef<str>, B: serde::Serialize>(
+    url: String,
+    route: S,
+    req: B,
+) -> anyhow::Result<serde_json::Value> {
     println!("Running {}", route.as_ref());
-    let client = reqwest::Client::new();
-    let res = client
+    let res = client()
         .post(format!("{}/{}", url.clone(), route.as_ref()))
         .json(&req)
         .timeout(Duration::from_secs(1))
@@ -245,7 +588,7 @@ pub async fn runreq<S: AsRef<str>, B: serde::Serialize, T: for<'de> serde::Deser
     let full = res.bytes().await?;
 
     println!("unmarshalling data");
-    let json = serde_json::from_slice(&full).with_context(|| {
+    let json: serde_json::Value = serde_json::from_slice(&full).with_context(|| {
         format!(
             "failed to unmarshall {:?}",
             String::from_utf8(full.to_vec()).unwrap()
@@ -254,3 +597,243 @@ pub async fn runreq<S: AsRef<str>, B: serde::Serialize, T: for<'de> serde::Deser
     println!("done data");
     Ok(json)
 }
+
+/// Every request runs on this, rather than a runtime made for each call
+pub fn runtime() -> &'static tokio::runtime::Runtime {
+    static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("inference")
+            .enable_all()
+            .build()
+            .expect("failed to start the inference runtime")
+    })
+}
+
+/// Shared so connections to the server get reused
+pub fn client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(reqwest::Client::new)
+}
+
+/// Sends the events of a generation to its TokenStream and keeps them for the cassette
+#[derive(Clone)]
+pub struct EventSink {
+    tx: std::sync::mpsc::Sender<InferEvent>,
+    sent: Arc<Mutex<Vec<InferEvent>>>,
+}
+
+impl EventSink {
+    /// false once nobody is listening anymore, the generation can stop then
+    pub fn send(&self, event: InferEvent) -> bool {
+        self.sent.lock().unwrap().push(event.clone());
+        self.tx.send(event).is_ok()
+    }
+}
+
+/// Runs a generation on the shared runtime. `run` gets the sink its events go to, it
+/// has to end with a Done or Failed or a Failed is added. With a cassette installed the
+/// events are recorded as one interaction, or replayed without running anything.
+///
+/// The abort handle stops the generation, it is None when replaying
+pub fn spawn_stream<F, Fut>(call: Call, run: F) -> (TokenStream, Option<tokio::task::AbortHandle>)
+where
+    F: FnOnce(EventSink) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let (tx, rx) = std::sync::mpsc::channel();
+    let sink = EventSink {
+        tx,
+        sent: Default::default(),
+    };
+    let cassette = cassette::current();
+    if let Some(ref cassette) = cassette {
+        if cassette.lock().unwrap().mode == cassette::Mode::Replay {
+            let events = cassette
+                .lock()
+                .unwrap()
+                .play(&call)
+                .and_then(|value| Ok(serde_json::from_value::<Vec<InferEvent>>(value)?));
+            match events {
+                Ok(events) => {
+                    for event in events {
+                        sink.send(event);
+                    }
+                }
+                Err(e) => {
+                    sink.send(InferEvent::Failed {
+                        error: format!("{e:#}"),
+                    });
+                }
+            }
+            return (rx, None);
+        }
+    }
+    let generation = run(sink.clone());
+    let task = runtime().spawn(async move {
+        let result = generation.await;
+        let finished = matches!(
+            sink.sent.lock().unwrap().last(),
+            Some(InferEvent::Done(_) | InferEvent::Failed { .. })
+        );
+        if !finished {
+            let error = match result {
+                Ok(()) => "the response ended before the generation finished".to_string(),
+                Err(e) => format!("{e:#}"),
+            };
+            sink.send(InferEvent::Failed { error });
+        }
+        if let Some(cassette) = cassette {
+            let events = sink.sent.lock().unwrap().clone();
+            let outcome = serde_json::to_value(events).map_err(|e| e.to_string());
+            let saved = cassette.lock().unwrap().save(Interaction { call, outcome });
+            if let Err(e) = saved {
+                println!("failed to record the generation {e:#}");
+            }
+        }
+    });
+    (rx, Some(task.abort_handle()))
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SseEvent {
+    /// The `event:` line, None for plain messages
+    pub event: Option<String>,
+    pub data: String,
+}
+
+/// Splits a text/event-stream into events as chunks of it come in
+#[derive(Default)]
+pub struct SseParser {
+    buf: Vec<u8>,
+}
+
+impl SseParser {
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        // \r only shows up in line endings, json escapes it everywhere else
+        self.buf.extend(chunk.iter().filter(|&&b| b != b'\r'));
+        let mut events = Vec::new();
+        while let Some(end) = self.buf.windows(2).position(|w| w == b"\n\n") {
+            let block: Vec<u8> = self.buf.drain(..end + 2).collect();
+            let mut event = SseEvent::default();
+            let mut data = Vec::new();
+            for line in String::from_utf8_lossy(&block).lines() {
+                if let Some(value) = line.strip_prefix("data:") {
+                    data.push(value.strip_prefix(' ').unwrap_or(value).to_string());
+                } else if let Some(value) = line.strip_prefix("event:") {
+                    event.event = Some(value.trim().to_string());
+                }
+                // ids, retries and : comments aren't used
+            }
+            if data.is_empty() && event.event.is_none() {
+                continue;
+            }
+            event.data = data.join("\n");
+            events.push(event);
+        }
+        events
+    }
+}
+
+/// Sends `req` and hands each server-sent event of the response to `on_event`, until
+/// the response ends or `on_event` returns false
+pub async fn stream_sse(
+    req: reqwest::RequestBuilder,
+    mut on_event: impl FnMut(SseEvent) -> anyhow::Result<bool>,
+) -> anyhow::Result<()> {
+    let mut res = req.send().await.context("failed request")?;
+    let status = res.status();
+    if !status.is_success() {
+        let text = res.text().await.unwrap_or_default();
+        bail!("request failed with {status}: {text}");
+    }
+    let mut parser = SseParser::default();
+    while let Some(chunk) = res.chunk().await? {
+        for event in parser.push(&chunk) {
+            if !on_event(event)? {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sse_parser() {
+        let mut parser = SseParser::default();
+        assert_eq!(parser.push(b"data: {\"text\": \"he"), vec![]);
+        assert_eq!(
+            parser.push(
+                b"llo\"}\r\n\r\n: keepalive\n\nevent: done\ndata: {}\n\ndata: a\ndata: b\n\n"
+            ),
+            vec![
+                SseEvent {
+                    event: None,
+                    data: "{\"text\": \"hello\"}".to_string()
+                },
+                SseEvent {
+                    event: Some("done".to_string()),
+                    data: "{}".to_string()
+                },
+                SseEvent {
+                    event: None,
+                    data: "a\nb".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stop_sequences() {
+        let mut mock = MockBackend::new(vec!["sure\nZack: thanks\n[[meta]]\nMood: Direct".into()]);
+        let req = InferReq {
+            prompt: "jake:".to_string(),
+            config: GenerationConfig {
+                stop_sequences: vec!["[[history]]".into(), "\nZack:".into(), "[[meta]]".into()],
+                ..Default::default()
+            },
+        };
+        let resp = mock.infer_and_wait(req).unwrap();
+        assert_eq!(resp.completion, "sure");
+        assert_eq!(resp.finish_reason, FinishReason::StopSequence);
+    }
+
+    #[test]
+    fn test_mock_backend() {
+        let mut mock = MockBackend::new(vec!["[<ls>] done".to_string()]);
+        let req = InferReq {
+            prompt: "jake:".to_string(),
+            config: GenerationConfig::default(),
+        };
+        let events: Vec<_> = mock.infer(req.clone()).unwrap().iter().collect();
+        assert_eq!(
+            events[..2],
+            [
+                InferEvent::Token {
+                    text: "[<ls>] ".to_string()
+                },
+                InferEvent::Token {
+                    text: "done".to_string()
+                },
+            ]
+        );
+        let resp = mock.infer_and_wait(req).unwrap();
+        assert_eq!(resp.completion, "mock response 2 to a 5 character prompt");
+        assert_eq!(resp.prompt_tokens, 1);
+        assert_eq!(resp.completion_tokens, 8);
+        assert_eq!(resp.finish_reason, FinishReason::Eos);
+        // the done event is the response with the event name next to it
+        let done = serde_json::to_value(InferEvent::Done(resp.clone())).unwrap();
+        assert_eq!(done["event"], "done");
+        assert_eq!(done["finish_reason"], "eos");
+        assert_eq!(
+            serde_json::from_value::<InferEvent>(done).unwrap(),
+            InferEvent::Done(resp)
+        );
+    }
+}
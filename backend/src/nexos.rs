@@ -1,20 +1,33 @@
 extern crate regex;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
 
+use anyhow::{anyhow, bail, Context};
 use bollard::container::{
-    AttachContainerOptions, Config, CreateContainerOptions, ListContainersOptions, LogOutput,
-    LogsOptions, RemoveContainerOptions, StartContainerOptions,
+    Config, CreateContainerOptions, DownloadFromContainerOptions, LogOutput,
+    RemoveContainerOptions, UpdateContainerOptions, UploadToContainerOptions,
 };
 use bollard::Docker;
 use regex::Regex;
 
 use bollard::exec::{CreateExecOptions, StartExecResults};
-use bollard::image::CreateImageOptions;
+use bollard::image::{RemoveImageOptions, TagImageOptions};
+use bollard::models::BuildInfo;
 
 use futures_util::stream::StreamExt;
-use futures_util::TryStreamExt;
+use futures_util::Stream;
 use strum_macros::Display;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+
+use crate::cassette::{self, CassetteBackend};
+use crate::command_parser;
+use crate::sandbox::LocalBackend;
+use crate::templates::ToolResultTemplateData;
 // pub fn extract_commands(input: &str) -> Vec<String> {
 //     let pattern = r"(\[<)(?P<command>[^(>\])]*)(>\])";
 //     // let pattern = r"(\[<)(?P<command>.*?)(?=>\])(>\])";
@@ -34,47 +47,24 @@ pub enum Command {
     Nexos(String),
     System(String),
 }
+/// Every well formed command in `s`. Use `command_parser::parse_commands` to also get
+/// what was malformed
 pub fn extract_commands(s: &str) -> Vec<Command> {
-    let mut results = Vec::new();
-    let mut capture = false;
-    let mut capture_char = '0';
-    let mut buffer = String::new();
-    let chars: Vec<char> = s.chars().collect();
-    let mut i = 0;
-
-    while i < chars.len() {
-        if i < chars.len() - 2 && chars[i] == '[' && (chars[i + 1] == '<' || chars[i + 1] == '(') {
-            capture = true;
-            match chars[i + 1] {
-                '<' => capture_char = '>',
-                '(' => capture_char = ')',
-                _ => unreachable!(),
-            }
-            i += 1; // Skip the next character
-        } else if i < chars.len() - 1 && capture && chars[i] == capture_char && chars[i + 1] == ']'
-        {
-            capture = false;
-
-            let cmd = match capture_char {
-                '>' => Command::Nexos(buffer.clone()),
-                ')' => Command::System(buffer.clone()),
-                _ => unreachable!(),
-            };
-            results.push(cmd);
-            buffer.clear();
-        } else if capture {
-            buffer.push(chars[i]);
-        }
-        i += 1;
-    }
-    results
+    let (commands, _) = command_parser::parse_commands(s);
+    commands.into_iter().map(|c| c.command).collect()
 }
 
+pub const DEFAULT_IMAGE: &str = "nexos:latest";
+/// Mounted as /home/jake in every container unless configured otherwise
+pub const PERSIST_DIR: &str = "/home/zack/personal/jake/nexos/persist";
+
 pub struct NexosInstance {}
 #[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
 pub struct DockerResult {
     pub output: Vec<LogLine>,
     pub exit_code: i32,
+    #[serde(default)]
+    pub limits: Vec<LimitHit>,
 }
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
@@ -82,111 +72,195 @@ pub enum LogLine {
     StdOut { message: String },
     StdErr { message: String },
 }
-impl NexosInstance {
-    pub async fn rebuild(&mut self) -> anyhow::Result<DockerResult> {
-        let IMAGE = "nexos:latest";
-        let docker = Docker::connect_with_socket_defaults().unwrap();
-        let mut result = DockerResult::default();
-        let mut tar = tar::Builder::new(Vec::new());
-        tar.append_dir_all(".", "/home/zack/personal/jake/nexos/persist/System")?; // Tar up the current directory, which contains the Dockerfile
+/// What came out of `nexos rebuild`
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct BuildResult {
+    pub success: bool,
+    /// What the dockerfile steps printed
+    pub steps: String,
+    pub error: Option<String>,
+    pub image_id: Option<String>,
+    /// Where the image from before the build was kept, None if there wasn't one
+    pub previous_image: Option<String>,
+}
 
-        let tarball = tar.into_inner()?;
-        let mut image_build_stream = docker.build_image(
-            bollard::image::BuildImageOptions {
-                dockerfile: "Dockerfile.txt",
-                t: IMAGE,
-                rm: true,
-                ..Default::default()
-            },
-            None,
-            Some(tarball.into()),
-        );
-        while let Some(msg) = image_build_stream.next().await {
-            println!("Message: {msg:?}");
-            result.output.push(LogLine::StdErr {
-                message: format!("{msg:?}"),
-            })
+impl BuildResult {
+    /// What Jake gets to see, the steps are cut down to `max_output_bytes`
+    pub fn report(&self, image: &str, max_output_bytes: usize) -> String {
+        let mut steps = CappedOutput::new(max_output_bytes);
+        steps.push(self.steps.trim_end().as_bytes());
+        let mut report = steps.text();
+        if !report.is_empty() {
+            report += "\n";
         }
-        return Ok(result);
-    }
-    // This does not have to be mutable but I am using the borrow checker to ensure
-    // this isn't concurrently modified
-    pub async fn exec_simple(&mut self, command: &str) -> anyhow::Result<DockerResult> {
-        // Create a Docker connection
-        // Iterate over logs and print them based on their StreamType
-        //
-        //
-        let mut result = DockerResult::default();
-        let docker = Docker::connect_with_socket_defaults().unwrap();
-        let IMAGE = "nexos:latest";
-
-        let alpine_config = Config {
-            image: Some(IMAGE),
-            tty: Some(true),
-            host_config: Some(bollard::service::HostConfig {
-                binds: Some(vec![
-                    "/home/zack/personal/jake/nexos/persist:/home/jake".to_string()
-                ]),
-                ..Default::default()
-            }),
-            ..Default::default()
+        if self.success {
+            report += &format!("Built {image}");
+            if let Some(ref id) = self.image_id {
+                report += &format!(" ({id})");
+            }
+            report += ", [(nexos reset)] to start using it.";
+            if let Some(ref previous) = self.previous_image {
+                report +=
+                    &format!(" The old image is {previous}, [(nexos restore)] goes back to it.");
+            }
+        } else {
+            report += &format!(
+                "Build failed: {}\n{image} was not changed.",
+                self.error.as_deref().unwrap_or("unknown error")
+            );
+        }
+        report
+    }
+}
+
+/// Turns the build stream into the steps' output, the error and the id of the image
+pub fn parse_build(
+    infos: impl IntoIterator<Item = Result<BuildInfo, bollard::errors::Error>>,
+) -> BuildResult {
+    let mut result = BuildResult::default();
+    for info in infos {
+        let info = match info {
+            Ok(info) => info,
+            Err(e) => {
+                result.error = Some(e.to_string());
+                continue;
+            }
         };
+        if let Some(stream) = info.stream {
+            if let Some(id) = stream.trim().strip_prefix("Successfully built ") {
+                result.image_id.get_or_insert(id.to_string());
+            }
+            result.steps += &stream;
+        }
+        // pull progress comes as status with progress, only the final lines are worth keeping
+        if let (Some(status), None) = (info.status, info.progress) {
+            result.steps += &status;
+            result.steps += "\n";
+        }
+        if let Some(error) = info.error_detail.and_then(|d| d.message).or(info.error) {
+            result.error = Some(error);
+        }
+        if let Some(id) = info.aux.and_then(|aux| aux.id) {
+            result.image_id = Some(id);
+        }
+    }
+    result.success = result.error.is_none();
+    result
+}
 
-        let id = docker
-            .create_container::<&str, &str>(None, alpine_config)
-            .await?
-            .id;
-        docker.start_container::<String>(&id, None).await?;
+/// `nexos:latest` -> (`nexos`, `nexos:previous`)
+pub fn previous_image(image: &str) -> (String, String) {
+    let repo = match image.rsplit_once(':') {
+        // a colon before the last slash is a registry port, not a tag
+        Some((repo, tag)) if !tag.contains('/') => repo,
+        _ => image,
+    };
+    (repo.to_string(), format!("{repo}:previous"))
+}
 
-        // non interactive
-        let exec = docker
-            .create_exec(
-                &id,
-                CreateExecOptions {
-                    attach_stdout: Some(true),
-                    attach_stderr: Some(true),
-                    cmd: Some(vec!["zsh", "-c", &format!("source ~/.zshrc; {}", command)]),
+impl NexosInstance {
+    /// Builds `image` from `dockerfile`, with the directory it is in as the context. The
+    /// build goes to a temporary tag, only a successful one replaces `image`, and the image
+    /// it replaces is tagged as `<repo>:previous` so it can be restored
+    pub async fn rebuild(&mut self, dockerfile: &Path, image: &str) -> anyhow::Result<BuildResult> {
+        let docker = Docker::connect_with_socket_defaults()?;
+        let context = dockerfile
+            .parent()
+            .ok_or(anyhow!("{} has no directory", dockerfile.display()))?;
+        let dockerfile_name = dockerfile
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or(anyhow!("bad dockerfile path {}", dockerfile.display()))?;
+        let mut tar = tar::Builder::new(Vec::new());
+        tar.append_dir_all(".", context)
+            .with_context(|| format!("failed to read the build context {}", context.display()))?;
+        let tarball = tar.into_inner()?;
+
+        let (repo, previous) = previous_image(image);
+        let tag = image.strip_prefix(&format!("{repo}:")).unwrap_or("latest");
+        let building = format!("{repo}:building-{}", uuid::Uuid::new_v4().simple());
+        let infos: Vec<_> = docker
+            .build_image(
+                bollard::image::BuildImageOptions {
+                    dockerfile: dockerfile_name,
+                    t: building.as_str(),
+                    rm: true,
                     ..Default::default()
                 },
+                None,
+                Some(tarball.into()),
             )
-            .await?
-            .id;
-        if let StartExecResults::Attached { mut output, .. } =
-            docker.start_exec(&exec, None).await?
-        {
-            while let Some(Ok(msg)) = output.next().await {
-                match msg {
-                    LogOutput::StdOut { message } => {
-                        let message = String::from_utf8_lossy(&message);
-                        result.output.push(LogLine::StdOut {
-                            message: message.into(),
-                        });
-                    }
-                    LogOutput::StdErr { message } => {
-                        let message = String::from_utf8_lossy(&message);
-                        result.output.push(LogLine::StdErr {
-                            message: message.into(),
-                        });
-                    }
-                    _ => {}
-                }
-            }
-        } else {
-            unreachable!();
+            .collect()
+            .await;
+        let mut result = parse_build(infos);
+        if result.success {
+            result.previous_image = match docker
+                .tag_image(
+                    image,
+                    Some(TagImageOptions {
+                        repo: repo.as_str(),
+                        tag: "previous",
+                    }),
+                )
+                .await
+            {
+                Ok(()) => Some(previous),
+                Err(bollard::errors::Error::DockerResponseServerError {
+                    status_code: 404, ..
+                }) => None,
+                Err(e) => return Err(e.into()),
+            };
+            docker
+                .tag_image(
+                    &building,
+                    Some(TagImageOptions {
+                        repo: repo.as_str(),
+                        tag,
+                    }),
+                )
+                .await
+                .with_context(|| format!("failed to tag {building} as {image}"))?;
         }
-
-        docker
-            .remove_container(
-                &id,
-                Some(RemoveContainerOptions {
-                    force: true,
+        // only the tag goes, the image stays under `image` when the build worked
+        let untag = docker
+            .remove_image(
+                &building,
+                Some(RemoveImageOptions {
+                    noprune: true,
                     ..Default::default()
                 }),
+                None,
             )
-            .await?;
-
+            .await;
+        match untag {
+            Ok(_)
+            | Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => {}
+            Err(e) => println!("failed to remove the build tag {building}: {e}"),
+        }
         Ok(result)
     }
+
+    /// Points `image` back at what it was before the last rebuild
+    pub async fn restore(&mut self, image: &str) -> anyhow::Result<String> {
+        let docker = Docker::connect_with_socket_defaults()?;
+        let (repo, previous) = previous_image(image);
+        let tag = image.strip_prefix(&format!("{repo}:")).unwrap_or("latest");
+        docker
+            .tag_image(
+                &previous,
+                Some(TagImageOptions {
+                    repo,
+                    tag: tag.to_string(),
+                }),
+            )
+            .await
+            .with_context(|| format!("failed to tag {previous} as {image}"))?;
+        Ok(format!(
+            "{image} is back to {previous}, [(nexos reset)] to start using it."
+        ))
+    }
     pub async fn exec(&mut self, mut options: CreateExecOptions<String>) -> anyhow::Result<()> {
         options.attach_stdout = Some(true);
         options.attach_stderr = Some(true);
@@ -238,6 +312,898 @@ impl NexosInstance {
     }
 }
 
+/// One command's output kept apart instead of merged into the message text
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CommandResult {
+    pub command: String,
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: i32,
+    pub duration: Duration,
+    /// Some of the output was left out for being over `NexosLimits::max_output_bytes`
+    pub truncated: bool,
+    #[serde(default)]
+    pub limits: Vec<LimitHit>,
+}
+
+impl CommandResult {
+    pub fn new(command: &str, result: DockerResult, duration: Duration) -> Self {
+        let mut stdout = String::new();
+        let mut stderr = String::new();
+        for line in result.output {
+            match line {
+                LogLine::StdOut { message } => stdout += &message,
+                LogLine::StdErr { message } => stderr += &message,
+            }
+        }
+        Self {
+            command: command.to_string(),
+            stdout,
+            stderr,
+            exit_code: result.exit_code,
+            duration,
+            truncated: result
+                .limits
+                .iter()
+                .any(|l| matches!(l, LimitHit::Output { .. })),
+            limits: result.limits,
+        }
+    }
+
+    pub fn failed(&self) -> bool {
+        self.exit_code != 0
+    }
+
+    pub fn to_template_data(&self) -> ToolResultTemplateData {
+        ToolResultTemplateData {
+            command: self.command.clone(),
+            stdout: self.stdout.trim_end().to_string(),
+            stderr: self.stderr.trim_end().to_string(),
+            exit_code: self.exit_code,
+            duration: format!("{:.1}s", self.duration.as_secs_f64()),
+            notes: self.limits.iter().map(|l| l.to_string()).collect(),
+        }
+    }
+}
+
+/// `jake-nexos-<conversation id>`, one container per conversation
+pub fn container_name(conversation_id: &str) -> String {
+    format!("jake-nexos-{conversation_id}")
+}
+
+/// How long the shell gets to come back after the command was killed before it's killed too
+const KILL_GRACE: Duration = Duration::from_secs(5);
+
+/// What a conversation's commands are allowed to use. The cpu, memory and pids limits
+/// are docker's, 0 means no limit. Limits are updated on the running container but
+/// lifting one needs a `nexos reset`
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct NexosLimits {
+    /// Wall clock seconds a single command gets before it is killed
+    pub timeout_secs: u64,
+    /// Bytes kept of each of stdout and stderr, the middle is left out past that
+    pub max_output_bytes: usize,
+    /// Biggest file `file get/put/edit` will move
+    #[serde(default = "default_max_file_bytes")]
+    pub max_file_bytes: usize,
+    pub cpus: f64,
+    pub memory_mb: u64,
+    pub pids: i64,
+}
+
+impl Default for NexosLimits {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 120,
+            max_output_bytes: 16 * 1024,
+            max_file_bytes: default_max_file_bytes(),
+            cpus: 2.0,
+            memory_mb: 4096,
+            pids: 512,
+        }
+    }
+}
+
+fn default_max_file_bytes() -> usize {
+    1024 * 1024
+}
+
+impl NexosLimits {
+    fn host_config(&self, persist_dir: &str) -> bollard::service::HostConfig {
+        let memory = (self.memory_mb > 0).then_some((self.memory_mb * 1024 * 1024) as i64);
+        bollard::service::HostConfig {
+            binds: Some(vec![format!("{persist_dir}:/home/jake")]),
+            nano_cpus: (self.cpus > 0.0).then_some((self.cpus * 1e9) as i64),
+            memory,
+            // no swap, otherwise the memory limit just makes things slow
+            memory_swap: memory,
+            pids_limit: (self.pids > 0).then_some(self.pids),
+            ..Default::default()
+        }
+    }
+}
+
+/// A limit a command ran into, these are reported to Jake with the output
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum LimitHit {
+    Timeout {
+        secs: u64,
+    },
+    Output {
+        omitted_bytes: usize,
+        limit: usize,
+    },
+    /// Killed without timing out while there is a memory limit, which is most likely why
+    Memory {
+        limit_mb: u64,
+    },
+    /// The shell itself didn't come back after a timeout and had to go
+    ShellKilled,
+}
+
+impl std::fmt::Display for LimitHit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitHit::Timeout { secs } => {
+                write!(f, "timed out after {secs}s, the command was killed")
+            }
+            LimitHit::Output {
+                omitted_bytes,
+                limit,
+            } => write!(
+                f,
+                "output was over {limit} bytes, {omitted_bytes} bytes in the middle were left out"
+            ),
+            LimitHit::Memory { limit_mb } => {
+                write!(
+                    f,
+                    "killed, probably for going over the {limit_mb}MB memory limit"
+                )
+            }
+            LimitHit::ShellKilled => write!(
+                f,
+                "the shell was killed too, the working directory and environment are reset"
+            ),
+        }
+    }
+}
+
+/// `text` cut down the same way command output is
+pub fn cap_output(text: &str, limit: usize) -> String {
+    let mut output = CappedOutput::new(limit);
+    output.push(text.as_bytes());
+    output.text()
+}
+
+/// Keeps the first and last `limit / 2` bytes of a stream and counts what was in between
+struct CappedOutput {
+    half: usize,
+    head: Vec<u8>,
+    tail: VecDeque<u8>,
+    omitted: usize,
+}
+
+impl CappedOutput {
+    fn new(limit: usize) -> Self {
+        Self {
+            // the tail has to be able to hold the sentinel line
+            half: (limit / 2).max(128),
+            head: Vec::new(),
+            tail: VecDeque::new(),
+            omitted: 0,
+        }
+    }
+
+    fn push(&mut self, mut bytes: &[u8]) {
+        let head_room = self.half.saturating_sub(self.head.len());
+        let (head, rest) = bytes.split_at(head_room.min(bytes.len()));
+        self.head.extend_from_slice(head);
+        bytes = rest;
+        self.tail.extend(bytes);
+        let over = self.tail.len().saturating_sub(self.half);
+        self.tail.drain(..over);
+        self.omitted += over;
+    }
+
+    fn text(&self) -> String {
+        let mut text = String::from_utf8_lossy(&self.head).to_string();
+        if self.omitted > 0 {
+            text += &format!("\n[... {} bytes omitted ...]\n", self.omitted);
+        }
+        let (a, b) = self.tail.as_slices();
+        text += &String::from_utf8_lossy(&[a, b].concat());
+        text
+    }
+}
+
+pub type ShellOutput =
+    Pin<Box<dyn Stream<Item = Result<LogOutput, bollard::errors::Error>> + Send>>;
+
+/// A shell that commands get piped into, wherever it is running
+pub struct Shell {
+    pub input: Pin<Box<dyn AsyncWrite + Send>>,
+    pub output: ShellOutput,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadOutcome {
+    Done,
+    TimedOut,
+    /// The shell exited, usually because the command ran `exit`
+    Closed,
+}
+
+/// One command going through a `Shell`. Commands are wrapped so the shell prints a
+/// sentinel with the exit code after them, that's how we know where the output ends
+pub struct CommandRun {
+    sentinel: String,
+    stdout: CappedOutput,
+    stderr: CappedOutput,
+    exit_code: Option<i32>,
+    stderr_done: bool,
+    pub limits: Vec<LimitHit>,
+}
+
+impl CommandRun {
+    pub async fn start(
+        shell: &mut Shell,
+        command: &str,
+        limits: &NexosLimits,
+    ) -> anyhow::Result<Self> {
+        let sentinel = format!("__JAKE_DONE_{}__", uuid::Uuid::new_v4().simple());
+        shell
+            .input
+            .write_all(wrap_command(command, &sentinel).as_bytes())
+            .await?;
+        shell.input.flush().await?;
+        Ok(Self {
+            sentinel,
+            stdout: CappedOutput::new(limits.max_output_bytes),
+            stderr: CappedOutput::new(limits.max_output_bytes),
+            exit_code: None,
+            stderr_done: false,
+            limits: Vec::new(),
+        })
+    }
+
+    /// When the command has to be done by
+    pub fn deadline(limits: &NexosLimits) -> Instant {
+        Instant::now() + Duration::from_secs(limits.timeout_secs)
+    }
+
+    /// Reads output until the command is done, the shell exits or `deadline` passes
+    pub async fn read_until(
+        &mut self,
+        shell: &mut Shell,
+        deadline: Instant,
+    ) -> anyhow::Result<ReadOutcome> {
+        while self.exit_code.is_none() || !self.stderr_done {
+            match tokio::time::timeout_at(deadline, shell.output.next()).await {
+                Ok(Some(Ok(LogOutput::StdOut { message }))) => {
+                    self.stdout.push(&message);
+                    if let Some((_, code)) = split_sentinel(&self.stdout.text(), &self.sentinel) {
+                        self.exit_code = Some(code.trim().parse().unwrap_or(-1));
+                    }
+                }
+                Ok(Some(Ok(LogOutput::StdErr { message }))) => {
+                    self.stderr.push(&message);
+                    self.stderr_done =
+                        split_sentinel(&self.stderr.text(), &self.sentinel).is_some();
+                }
+                Ok(Some(Ok(_))) => {}
+                Ok(Some(Err(e))) => return Err(e.into()),
+                Ok(None) => return Ok(ReadOutcome::Closed),
+                Err(_) => return Ok(ReadOutcome::TimedOut),
+            }
+        }
+        Ok(ReadOutcome::Done)
+    }
+
+    pub fn timed_out(&self) -> bool {
+        self.limits
+            .iter()
+            .any(|l| matches!(l, LimitHit::Timeout { .. }))
+    }
+
+    /// `exit_code` is used when the shell didn't print one
+    pub fn finish(mut self, exit_code: i32, limits: &NexosLimits) -> DockerResult {
+        for output in [&self.stdout, &self.stderr] {
+            if output.omitted > 0 {
+                self.limits.push(LimitHit::Output {
+                    omitted_bytes: output.omitted,
+                    limit: limits.max_output_bytes,
+                });
+                break;
+            }
+        }
+        let strip = |text: String| match split_sentinel(&text, &self.sentinel) {
+            Some((before, _)) => before.to_string(),
+            None => text.clone(),
+        };
+        let stdout = strip(self.stdout.text());
+        let mut stderr = strip(self.stderr.text());
+        let exit_code = self.exit_code.unwrap_or(exit_code);
+        if self.exit_code.is_none() && !self.timed_out() {
+            stderr += "[shell exited, the next command starts a new one]\n";
+        }
+        // 128 + SIGKILL, and it wasn't us
+        if exit_code == 137 && !self.timed_out() && limits.memory_mb > 0 {
+            self.limits.push(LimitHit::Memory {
+                limit_mb: limits.memory_mb,
+            });
+        }
+        let mut result = DockerResult {
+            exit_code,
+            limits: self.limits,
+            ..Default::default()
+        };
+        if !stdout.is_empty() {
+            result.output.push(LogLine::StdOut { message: stdout });
+        }
+        if !stderr.is_empty() {
+            result.output.push(LogLine::StdErr { message: stderr });
+        }
+        result
+    }
+}
+
+/// Somewhere for a conversation's commands to run
+pub trait ExecBackend: Send {
+    /// Run `command` in the conversation's shell, starting things up first if needed
+    fn exec(&mut self, command: &str, limits: &NexosLimits) -> anyhow::Result<DockerResult>;
+    /// Make sure the shell is up without running anything, returns a note for Jake
+    fn attach(&mut self, limits: &NexosLimits) -> anyhow::Result<String>;
+    /// Rebuild the image the environment is made from
+    fn rebuild(&mut self) -> anyhow::Result<BuildResult>;
+    /// Go back to the image from before the last rebuild, returns a note for Jake
+    fn restore_image(&mut self) -> anyhow::Result<String>;
+    /// What `rebuild` builds, for reporting
+    fn image(&self) -> &str;
+    /// Jake's home directory on this machine, None if there isn't one here
+    fn home_dir(&self) -> Option<PathBuf>;
+    /// Throw away everything but the home directory
+    fn reset(&mut self) -> anyhow::Result<()>;
+    /// `path` is relative to the home directory. Files bigger than `max_bytes` are an
+    /// error, 0 is no limit
+    fn read_file(&mut self, path: &str, max_bytes: usize) -> anyhow::Result<Vec<u8>>;
+    /// `path` is relative to the home directory, missing directories are created
+    fn write_file(&mut self, path: &str, contents: &[u8]) -> anyhow::Result<()>;
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum BackendKind {
+    #[default]
+    Docker,
+    /// A bubblewrap sandbox around a temp directory, for machines without docker
+    Local,
+}
+
+#[derive(clap::Args, Clone, Debug, PartialEq)]
+pub struct NexosConfig {
+    /// Where Nexos commands run
+    #[arg(long, value_enum, default_value_t = BackendKind::Docker)]
+    pub nexos_backend: BackendKind,
+    /// Jake's home directory on the host. Docker defaults to the nexos/persist directory,
+    /// the local backend to a new temp directory per conversation
+    #[arg(long)]
+    pub nexos_persist: Option<String>,
+    /// The image containers are made from and `nexos rebuild` builds
+    #[arg(long, default_value = DEFAULT_IMAGE)]
+    pub nexos_image: String,
+    /// Defaults to System/Dockerfile.txt in Jake's home directory
+    #[arg(long)]
+    pub nexos_dockerfile: Option<String>,
+}
+
+impl Default for NexosConfig {
+    fn default() -> Self {
+        Self {
+            nexos_backend: BackendKind::default(),
+            nexos_persist: None,
+            nexos_image: DEFAULT_IMAGE.to_string(),
+            nexos_dockerfile: None,
+        }
+    }
+}
+
+static CONFIG: Mutex<Option<NexosConfig>> = Mutex::new(None);
+
+/// Pick the backend new sessions get, sessions that already started keep theirs
+pub fn configure(config: NexosConfig) {
+    *CONFIG.lock().unwrap() = Some(config);
+}
+
+pub fn open_backend(
+    config: &NexosConfig,
+    conversation_id: &str,
+) -> anyhow::Result<Box<dyn ExecBackend>> {
+    if let Some(cassette) = cassette::current() {
+        let replaying = cassette.lock().unwrap().mode == cassette::Mode::Replay;
+        let inner = match replaying {
+            true => None,
+            false => Some(open_real_backend(config, conversation_id)?),
+        };
+        return Ok(Box::new(CassetteBackend::new(
+            conversation_id,
+            cassette,
+            inner,
+        )));
+    }
+    open_real_backend(config, conversation_id)
+}
+
+fn open_real_backend(
+    config: &NexosConfig,
+    conversation_id: &str,
+) -> anyhow::Result<Box<dyn ExecBackend>> {
+    Ok(match config.nexos_backend {
+        BackendKind::Docker => Box::new(DockerBackend::new(conversation_id, config)?),
+        BackendKind::Local => Box::new(LocalBackend::new(config.nexos_persist.as_deref())?),
+    })
+}
+
+struct DockerShell {
+    exec_id: String,
+    /// Inside the container, so its children can be killed on a timeout
+    pid: u32,
+    shell: Shell,
+}
+
+/// The attached streams belong to the runtime that created them so every docker session
+/// uses this one instead of making one per command like everything else does
+fn runtime() -> &'static tokio::runtime::Runtime {
+    static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        // multi threaded so block_on still drives the io of the other sessions
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .expect("failed to start the nexos runtime")
+    })
+}
+
+/// A long lived container and shell for one conversation, so `cd`, exported variables
+/// and background processes are still there for the next command. The container is
+/// removed when the backend is dropped.
+pub struct DockerBackend {
+    pub container: String,
+    persist_dir: String,
+    image: String,
+    dockerfile: PathBuf,
+    shell: Option<DockerShell>,
+    /// What the running container was last configured with
+    applied_limits: Option<NexosLimits>,
+}
+
+impl DockerBackend {
+    pub fn new(conversation_id: &str, config: &NexosConfig) -> anyhow::Result<Self> {
+        let persist_dir = config.nexos_persist.as_deref().unwrap_or(PERSIST_DIR);
+        let dockerfile = match config.nexos_dockerfile {
+            Some(ref dockerfile) => PathBuf::from(dockerfile),
+            None => Path::new(persist_dir).join("System/Dockerfile.txt"),
+        };
+        Ok(Self {
+            container: container_name(conversation_id),
+            persist_dir: persist_dir.to_string(),
+            image: config.nexos_image.clone(),
+            dockerfile,
+            shell: None,
+            applied_limits: None,
+        })
+    }
+
+    /// Returns true when the container had to be created
+    async fn ensure_container(
+        &mut self,
+        docker: &Docker,
+        limits: &NexosLimits,
+    ) -> anyhow::Result<bool> {
+        let created = match docker.inspect_container(&self.container, None).await {
+            Ok(info) => {
+                if self.applied_limits.as_ref() != Some(limits) {
+                    self.update_limits(docker, limits).await?;
+                }
+                if info.state.and_then(|s| s.running) == Some(true) {
+                    return Ok(false);
+                }
+                false
+            }
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => {
+                let config = Config {
+                    image: Some(self.image.as_str()),
+                    cmd: Some(vec!["sleep", "infinity"]),
+                    host_config: Some(limits.host_config(&self.persist_dir)),
+                    ..Default::default()
+                };
+                docker
+                    .create_container(
+                        Some(CreateContainerOptions {
+                            name: self.container.as_str(),
+                            platform: None,
+                        }),
+                        config,
+                    )
+                    .await?;
+                self.applied_limits = Some(limits.clone());
+                true
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // whatever shell we had died with the container
+        self.shell = None;
+        docker
+            .start_container::<String>(&self.container, None)
+            .await?;
+        Ok(created)
+    }
+
+    async fn update_limits(&mut self, docker: &Docker, limits: &NexosLimits) -> anyhow::Result<()> {
+        let host_config = limits.host_config(&self.persist_dir);
+        docker
+            .update_container(
+                &self.container,
+                UpdateContainerOptions::<String> {
+                    nano_cp_us: host_config.nano_cpus,
+                    memory: host_config.memory,
+                    memory_swap: host_config.memory_swap,
+                    pids_limit: host_config.pids_limit,
+                    ..Default::default()
+                },
+            )
+            .await
+            .context("failed to update the container limits")?;
+        self.applied_limits = Some(limits.clone());
+        Ok(())
+    }
+
+    async fn spawn_shell(&mut self, docker: &Docker) -> anyhow::Result<()> {
+        let exec_id = docker
+            .create_exec(
+                &self.container,
+                CreateExecOptions {
+                    attach_stdin: Some(true),
+                    attach_stdout: Some(true),
+                    attach_stderr: Some(true),
+                    // exec keeps the pid, so this is the pid of zsh
+                    cmd: Some(vec!["sh", "-c", "echo $$; exec zsh"]),
+                    working_dir: Some("/home/jake"),
+                    ..Default::default()
+                },
+            )
+            .await?
+            .id;
+        let (mut output, mut input) = match docker.start_exec(&exec_id, None).await? {
+            StartExecResults::Attached { output, input } => (output, input),
+            StartExecResults::Detached => return Err(anyhow!("shell exec started detached")),
+        };
+        let mut first_line = Vec::new();
+        while !first_line.contains(&b'\n') {
+            match tokio::time::timeout(KILL_GRACE, output.next()).await {
+                Ok(Some(Ok(LogOutput::StdOut { message }))) => {
+                    first_line.extend_from_slice(&message)
+                }
+                Ok(Some(Ok(_))) => {}
+                Ok(Some(Err(e))) => return Err(e.into()),
+                Ok(None) | Err(_) => return Err(anyhow!("shell didn't start")),
+            }
+        }
+        let pid = String::from_utf8_lossy(&first_line)
+            .trim()
+            .parse()
+            .context("shell didn't report its pid")?;
+        input
+            .write_all(b"source ~/.zshrc > /dev/null 2>&1\n")
+            .await?;
+        self.shell = Some(DockerShell {
+            exec_id,
+            pid,
+            shell: Shell { input, output },
+        });
+        Ok(())
+    }
+
+    async fn exec_async(
+        &mut self,
+        command: &str,
+        limits: &NexosLimits,
+    ) -> anyhow::Result<DockerResult> {
+        let docker = Docker::connect_with_socket_defaults()?;
+        self.ensure_container(&docker, limits).await?;
+        if self.shell.is_none() {
+            self.spawn_shell(&docker).await?;
+        }
+        let DockerShell {
+            exec_id,
+            pid,
+            shell,
+        } = self.shell.as_mut().unwrap();
+        let pid = pid.to_string();
+
+        let mut run = CommandRun::start(shell, command, limits).await?;
+        let mut outcome = run.read_until(shell, CommandRun::deadline(limits)).await;
+        if let Ok(ReadOutcome::TimedOut) = outcome {
+            run.limits.push(LimitHit::Timeout {
+                secs: limits.timeout_secs,
+            });
+            // kill whatever the shell is running and give it a moment to print the sentinel
+            exec_and_wait(&docker, &self.container, vec!["pkill", "-KILL", "-P", &pid]).await?;
+            outcome = run.read_until(shell, Instant::now() + KILL_GRACE).await;
+            if let Ok(ReadOutcome::TimedOut) = outcome {
+                // a builtin or loop running in the shell itself, the shell has to go
+                exec_and_wait(&docker, &self.container, vec!["kill", "-KILL", &pid]).await?;
+                run.limits.push(LimitHit::ShellKilled);
+            }
+        }
+        match outcome {
+            Ok(ReadOutcome::Done) => Ok(run.finish(-1, limits)),
+            Ok(_) => {
+                let exec_id = exec_id.clone();
+                self.shell = None;
+                let exit_code = docker
+                    .inspect_exec(&exec_id)
+                    .await
+                    .ok()
+                    .and_then(|e| e.exit_code)
+                    .unwrap_or(-1);
+                Ok(run.finish(exit_code as i32, limits))
+            }
+            Err(e) => {
+                self.shell = None;
+                Err(e)
+            }
+        }
+    }
+
+    async fn remove_container(&mut self) -> anyhow::Result<()> {
+        let docker = Docker::connect_with_socket_defaults()?;
+        match docker
+            .remove_container(
+                &self.container,
+                Some(RemoveContainerOptions {
+                    force: true,
+                    ..Default::default()
+                }),
+            )
+            .await
+        {
+            Ok(())
+            | Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn download(&mut self, path: &str, max_bytes: usize) -> anyhow::Result<Vec<u8>> {
+        let docker = Docker::connect_with_socket_defaults()?;
+        let limits = self.applied_limits.clone().unwrap_or_default();
+        self.ensure_container(&docker, &limits).await?;
+        let full_path = format!("/home/jake/{path}");
+        let mut stream = docker.download_from_container(
+            &self.container,
+            Some(DownloadFromContainerOptions { path: full_path }),
+        );
+        let mut archive = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            archive
+                .extend_from_slice(&chunk.with_context(|| format!("failed to download {path}"))?);
+            // room for the tar headers, the exact size is checked below
+            if max_bytes > 0 && archive.len() > max_bytes + 64 * 1024 {
+                bail!("{path} is bigger than the {max_bytes} byte limit");
+            }
+        }
+        let mut archive = tar::Archive::new(archive.as_slice());
+        let mut entry = archive
+            .entries()?
+            .next()
+            .ok_or(anyhow!("{path} came back as an empty archive"))??;
+        if !entry.header().entry_type().is_file() {
+            bail!("{path} is not a file");
+        }
+        let size = entry.header().size()?;
+        if max_bytes > 0 && size > max_bytes as u64 {
+            bail!("{path} is {size} bytes, bigger than the {max_bytes} byte limit");
+        }
+        let mut contents = Vec::new();
+        std::io::Read::read_to_end(&mut entry, &mut contents)?;
+        Ok(contents)
+    }
+
+    async fn upload(&mut self, path: &str, contents: &[u8]) -> anyhow::Result<()> {
+        let docker = Docker::connect_with_socket_defaults()?;
+        let limits = self.applied_limits.clone().unwrap_or_default();
+        self.ensure_container(&docker, &limits).await?;
+        // the archive is extracted at /home/jake so the entry path takes care of directories
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_secs(),
+        );
+        let mut tar = tar::Builder::new(Vec::new());
+        tar.append_data(&mut header, path, contents)?;
+        let tarball = tar.into_inner()?;
+        docker
+            .upload_to_container(
+                &self.container,
+                Some(UploadToContainerOptions {
+                    path: "/home/jake",
+                    ..Default::default()
+                }),
+                tarball.into(),
+            )
+            .await
+            .with_context(|| format!("failed to upload {path}"))?;
+        Ok(())
+    }
+}
+
+impl Drop for DockerBackend {
+    fn drop(&mut self) {
+        self.shell = None;
+        if let Err(e) = runtime().block_on(self.remove_container()) {
+            println!("failed to remove {} {e:#}", self.container)
+        }
+    }
+}
+
+impl ExecBackend for DockerBackend {
+    fn exec(&mut self, command: &str, limits: &NexosLimits) -> anyhow::Result<DockerResult> {
+        runtime().block_on(self.exec_async(command, limits))
+    }
+
+    fn attach(&mut self, limits: &NexosLimits) -> anyhow::Result<String> {
+        runtime().block_on(async {
+            let docker = Docker::connect_with_socket_defaults()?;
+            let created = self.ensure_container(&docker, limits).await?;
+            let new_shell = self.shell.is_none();
+            if new_shell {
+                self.spawn_shell(&docker).await?;
+            }
+            let state = match (created, new_shell) {
+                (true, _) => "Started a new container",
+                (false, true) => "Reconnected to the existing container with a new shell",
+                (false, false) => "Already attached",
+            };
+            Ok(format!(
+                "{state} ({}). Attach a terminal with `docker exec -it {} zsh`",
+                self.container, self.container
+            ))
+        })
+    }
+
+    fn rebuild(&mut self) -> anyhow::Result<BuildResult> {
+        runtime().block_on(NexosInstance {}.rebuild(&self.dockerfile, &self.image))
+    }
+
+    fn restore_image(&mut self) -> anyhow::Result<String> {
+        runtime().block_on(NexosInstance {}.restore(&self.image))
+    }
+
+    fn image(&self) -> &str {
+        &self.image
+    }
+
+    fn home_dir(&self) -> Option<PathBuf> {
+        Some(PathBuf::from(&self.persist_dir))
+    }
+
+    /// The next command starts from a fresh image, only what is under /home/jake survives
+    fn reset(&mut self) -> anyhow::Result<()> {
+        self.shell = None;
+        self.applied_limits = None;
+        runtime().block_on(self.remove_container())
+    }
+
+    fn read_file(&mut self, path: &str, max_bytes: usize) -> anyhow::Result<Vec<u8>> {
+        runtime().block_on(self.download(path, max_bytes))
+    }
+
+    fn write_file(&mut self, path: &str, contents: &[u8]) -> anyhow::Result<()> {
+        runtime().block_on(self.upload(path, contents))
+    }
+}
+
+/// Run a one off command in the container and wait for it to finish
+async fn exec_and_wait(docker: &Docker, container: &str, cmd: Vec<&str>) -> anyhow::Result<()> {
+    let exec = docker
+        .create_exec(
+            container,
+            CreateExecOptions {
+                attach_stdout: Some(true),
+                attach_stderr: Some(true),
+                cmd: Some(cmd),
+                ..Default::default()
+            },
+        )
+        .await?
+        .id;
+    if let StartExecResults::Attached { mut output, .. } = docker.start_exec(&exec, None).await? {
+        while let Some(Ok(_)) = output.next().await {}
+    }
+    Ok(())
+}
+
+/// What gets written into the shell for one command. The command goes through a quoted
+/// heredoc so nothing in it needs escaping, and stdin is closed so it can't eat the
+/// commands that come after it.
+fn wrap_command(command: &str, sentinel: &str) -> String {
+    format!(
+        "eval \"$(cat <<'{sentinel}'\n{command}\n{sentinel}\n)\" < /dev/null\n\
+         printf '\\n{sentinel} %d\\n' $?\n\
+         printf '\\n{sentinel}\\n' >&2\n"
+    )
+}
+
+/// Splits `output` into what the command printed and the rest of the sentinel line.
+/// The sentinel is printed after a newline so it is on its own line, that newline
+/// isn't part of the output
+fn split_sentinel<'a>(output: &'a str, sentinel: &str) -> Option<(&'a str, &'a str)> {
+    let start = output.find(&format!("\n{sentinel}"))?;
+    let rest = &output[start + 1 + sentinel.len()..];
+    let line_end = rest.find('\n')?;
+    Some((&output[..start], &rest[..line_end]))
+}
+
+static SESSIONS: OnceLock<Mutex<HashMap<String, Box<dyn ExecBackend>>>> = OnceLock::new();
+
+/// Run `f` with the backend for `conversation_id`, opening it if this is the first time
+pub fn with_session<T>(
+    conversation_id: &str,
+    f: impl FnOnce(&mut dyn ExecBackend) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let mut sessions = SESSIONS
+        .get_or_init(Default::default)
+        .lock()
+        .map_err(|_| anyhow!("nexos sessions lock poisoned"))?;
+    if !sessions.contains_key(conversation_id) {
+        let config = CONFIG.lock().unwrap().clone().unwrap_or_default();
+        sessions.insert(
+            conversation_id.to_string(),
+            open_backend(&config, conversation_id)?,
+        );
+    }
+    f(sessions.get_mut(conversation_id).unwrap().as_mut())
+}
+
+/// Gives `conversation_id` this backend instead of one opened from the global config
+#[cfg(test)]
+pub fn insert_session(conversation_id: &str, backend: Box<dyn ExecBackend>) {
+    SESSIONS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .insert(conversation_id.to_string(), backend);
+}
+
+/// Drops the backend of `conversation_id`, and with it the container
+pub fn close_session(conversation_id: &str) {
+    if let Some(sessions) = SESSIONS.get() {
+        let session = sessions
+            .lock()
+            .ok()
+            .and_then(|mut s| s.remove(conversation_id));
+        drop(session);
+    }
+}
+
+/// Drops every backend. The sessions live in a static that is never dropped on its own, so
+/// this has to run before exiting or the containers are left behind
+pub fn close_sessions() {
+    if let Some(sessions) = SESSIONS.get() {
+        let all: Vec<_> = match sessions.lock() {
+            Ok(mut sessions) => sessions.drain().collect(),
+            Err(_) => return,
+        };
+        drop(all);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -255,6 +1221,101 @@ mod tests {
             ]
         );
 
-        // Additional test cases and assertions can be added here.
+        // used to underflow on anything shorter than two chars
+        assert!(extract_commands("").is_empty());
+        assert!(extract_commands("[").is_empty());
+    }
+
+    #[test]
+    fn test_split_sentinel() {
+        let s = "__JAKE_DONE_x__";
+        assert_eq!(
+            split_sentinel(&format!("hi\n\n{s} 0\n"), s),
+            Some(("hi\n", " 0"))
+        );
+        // no trailing newline from the command
+        assert_eq!(
+            split_sentinel(&format!("hi\n{s} 12\n"), s),
+            Some(("hi", " 12"))
+        );
+        // sentinel line hasn't fully arrived yet
+        assert_eq!(split_sentinel(&format!("hi\n{s} 1"), s), None);
+        assert_eq!(split_sentinel("hi\n", s), None);
+    }
+
+    #[test]
+    fn test_capped_output() {
+        let mut output = CappedOutput::new(256);
+        output.push(b"start ");
+        assert_eq!(output.text(), "start ");
+
+        for _ in 0..200 {
+            output.push(b"y\n");
+        }
+        output.push(b"end");
+        assert_eq!(output.omitted, 6 + 400 + 3 - 256);
+        let text = output.text();
+        assert!(text.starts_with("start y\n"));
+        assert!(text.contains("\n[... 153 bytes omitted ...]\n"));
+        assert!(text.ends_with("y\nend"));
+    }
+
+    #[test]
+    fn test_parse_build() {
+        let stream = |s: &str| {
+            Ok(BuildInfo {
+                stream: Some(s.to_string()),
+                ..Default::default()
+            })
+        };
+        let result = parse_build(vec![
+            stream("Step 1/2 : FROM ubuntu\n"),
+            Ok(BuildInfo {
+                status: Some("Downloading".to_string()),
+                progress: Some("[==>   ]".to_string()),
+                ..Default::default()
+            }),
+            stream("Step 2/2 : RUN true\n"),
+            stream("Successfully built 0123abcd\n"),
+        ]);
+        assert!(result.success);
+        assert_eq!(result.image_id.as_deref(), Some("0123abcd"));
+        assert_eq!(
+            result.steps,
+            "Step 1/2 : FROM ubuntu\nStep 2/2 : RUN true\nSuccessfully built 0123abcd\n"
+        );
+
+        let result = parse_build(vec![
+            stream("Step 1/1 : RUN false\n"),
+            Ok(BuildInfo {
+                error: Some("exit code 1".to_string()),
+                error_detail: Some(bollard::models::ErrorDetail {
+                    code: Some(1),
+                    message: Some("The command '/bin/sh -c false' returned 1".to_string()),
+                }),
+                ..Default::default()
+            }),
+        ]);
+        assert!(!result.success);
+        assert_eq!(
+            result.error.as_deref(),
+            Some("The command '/bin/sh -c false' returned 1")
+        );
+        assert_eq!(result.image_id, None);
+    }
+
+    #[test]
+    fn test_previous_image() {
+        assert_eq!(
+            previous_image("nexos:latest"),
+            ("nexos".to_string(), "nexos:previous".to_string())
+        );
+        assert_eq!(
+            previous_image("localhost:5000/nexos"),
+            (
+                "localhost:5000/nexos".to_string(),
+                "localhost:5000/nexos:previous".to_string()
+            )
+        );
     }
 }
@@ -1,28 +1,242 @@
-use openai_api_rust::chat::{ChatBody, ChatApi};
-use openai_api_rust::{Auth, Message, OpenAI, Role};
-
-pub fn complete<S: AsRef<str>>(s: S) -> String {
-    let auth = Auth::from_env().unwrap();
-    let openai = OpenAI::new(auth, "https://api.openai.com/v1/");
-    let body = ChatBody {
-        model: "gpt-3.5-turbo".to_string(),
-        max_tokens: None,
-        temperature: Some(0_f32),
-        top_p: Some(0_f32),
-        n: Some(1),
-        stream: Some(false),
-        stop: None,
-        presence_penalty: None,
-        frequency_penalty: None,
-        logit_bias: None,
-        user: None,
-        messages: vec![Message {
-            role: Role::User,
-            content: s.as_ref().into(),
-        }],
-    };
-    let rs = openai.chat_completion_create(&body);
-    let choice = rs.unwrap().choices;
-    let message = &choice[0].message.as_ref().unwrap();
-    message.content.clone()
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail};
+
+use crate::cassette::Call;
+use crate::model_server::{
+    client, spawn_stream, stream_sse, trim_stop_sequences, FinishReason, InferEvent, InferReq,
+    InferResp, InferenceBackend, ServerStatus, StopResp, TokenStream,
+};
+use crate::token::{self, TokenCounter};
+
+/// Generation can take a while on a local model, this is only there so a dead server
+/// doesn't hang forever
+const COMPLETION_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// A server with an OpenAI style `/v1/completions`, llama.cpp's server, vLLM, text
+/// generation inference and so on. Completions are streamed
+pub struct OpenAiBackend {
+    url: String,
+    model: String,
+    api_key: Option<String>,
+    /// The generation running now, aborted by stop or the next infer
+    running: Option<tokio::task::AbortHandle>,
+    status: ServerStatus,
+}
+
+impl OpenAiBackend {
+    pub fn new(url: &str, model: &str, api_key: Option<String>) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            model: model.to_string(),
+            api_key,
+            running: None,
+            status: ServerStatus::default(),
+        }
+    }
+}
+
+/// The request body, GenerationConfig's names mapped to OpenAI's. top_k and
+/// repetition_penalty aren't OpenAI's but llama.cpp and vLLM take them
+pub fn completion_body(model: &str, req: &InferReq) -> serde_json::Value {
+    let config = &req.config;
+    serde_json::json!({
+        "model": model,
+        "prompt": req.prompt,
+        "max_tokens": config.max_new_tokens,
+        "temperature": if config.do_sample { config.temperature } else { 0.0 },
+        "top_p": config.top_p,
+        "top_k": config.top_k,
+        "repetition_penalty": config.repetition_penalty,
+        // OpenAI takes at most 4, the rest are only trimmed after
+        "stop": config.stop_sequences.iter().take(4).collect::<Vec<_>>(),
+        "stream": true,
+        // the token counts come in a last chunk of their own
+        "stream_options": {"include_usage": true},
+    })
+}
+
+/// The generated text out of a completion response, or a chunk of a streamed one
+pub fn completion_text(resp: &serde_json::Value) -> anyhow::Result<String> {
+    if let Some(error) = resp.get("error") {
+        bail!("the server returned an error: {error}");
+    }
+    resp.pointer("/choices/0/text")
+        .and_then(|text| text.as_str())
+        .map(String::from)
+        .ok_or(anyhow!("no choices[0].text in the response: {resp}"))
+}
+
+/// What the chunks of a streamed completion add up to
+#[derive(Default)]
+pub struct Completion {
+    id: String,
+    text: String,
+    chunks: usize,
+    finish_reason: Option<String>,
+    /// (prompt, completion) tokens, not every server sends them
+    usage: Option<(usize, usize)>,
+}
+
+impl Completion {
+    /// Takes in a chunk, returns the text it adds
+    pub fn push(&mut self, chunk: &serde_json::Value) -> anyhow::Result<String> {
+        if let Some(id) = chunk["id"].as_str() {
+            self.id = id.to_string();
+        }
+        if let Some(usage) = chunk.get("usage").filter(|u| !u.is_null()) {
+            let count = |name: &str| usage[name].as_u64().unwrap_or_default() as usize;
+            self.usage = Some((count("prompt_tokens"), count("completion_tokens")));
+        }
+        if let Some(reason) = chunk.pointer("/choices/0/finish_reason") {
+            self.finish_reason = reason
+                .as_str()
+                .map(String::from)
+                .or(self.finish_reason.take());
+        }
+        // the usage chunk has no choices
+        if chunk["choices"].as_array().is_some_and(|c| c.is_empty()) {
+            return Ok(String::new());
+        }
+        let text = completion_text(chunk)?;
+        self.chunks += 1;
+        self.text.push_str(&text);
+        Ok(text)
+    }
+
+    /// Without usage each chunk is taken as a token and the prompt is counted with the
+    /// mistral tokenizer if it is around
+    pub fn finish(self, req: &InferReq, started: Instant) -> InferResp {
+        let (prompt_tokens, completion_tokens) = self.usage.unwrap_or_else(|| {
+            let prompt_tokens = token::mistral()
+                .and_then(|t| t.count(&req.prompt))
+                .unwrap_or_default();
+            (prompt_tokens, self.chunks)
+        });
+        let mut resp = InferResp {
+            job_id: self.id,
+            completion: self.text,
+            prompt_tokens,
+            completion_tokens,
+            finish_reason: match self.finish_reason.as_deref() {
+                Some("length") => FinishReason::MaxNewTokens,
+                // "stop" is the end of text and the server matching a stop sequence alike
+                Some(_) => FinishReason::Eos,
+                None => FinishReason::Stopped,
+            },
+            duration_ms: started.elapsed().as_millis() as u64,
+        };
+        trim_stop_sequences(&mut resp, &req.config.stop_sequences);
+        resp
+    }
+}
+
+impl InferenceBackend for OpenAiBackend {
+    /// Only whether it is running, the text goes to the TokenStream
+    fn status(&mut self) -> anyhow::Result<&ServerStatus> {
+        if matches!(self.running, Some(ref task) if task.is_finished()) {
+            self.running = None;
+        }
+        self.status = match self.running {
+            Some(_) => ServerStatus::Generating {
+                text: String::new(),
+            },
+            None => ServerStatus::Ready {},
+        };
+        Ok(&self.status)
+    }
+
+    fn infer(&mut self, body: InferReq) -> anyhow::Result<TokenStream> {
+        self.stop()?;
+        let request = completion_body(&self.model, &body);
+        let call = Call::Request {
+            route: format!("{}/v1/completions", self.url),
+            body: request.clone(),
+        };
+        let mut req = client()
+            .post(format!("{}/v1/completions", self.url))
+            .json(&request)
+            .timeout(COMPLETION_TIMEOUT);
+        if let Some(ref key) = self.api_key {
+            req = req.bearer_auth(key);
+        }
+        let started = Instant::now();
+        let mut completion = Completion::default();
+        let (tokens, running) = spawn_stream(call, |sink| {
+            stream_sse(req, move |event| {
+                if event.data.trim() == "[DONE]" {
+                    let resp = std::mem::take(&mut completion).finish(&body, started);
+                    let _ = sink.send(InferEvent::Done(resp));
+                    return Ok(false);
+                }
+                let chunk: serde_json::Value = serde_json::from_str(&event.data)?;
+                let text = completion.push(&chunk)?;
+                Ok(text.is_empty() || sink.send(InferEvent::Token { text }))
+            })
+        });
+        self.running = running;
+        Ok(tokens)
+    }
+
+    /// Drops the connection, the server stops generating when it notices
+    fn stop(&mut self) -> anyhow::Result<StopResp> {
+        if let Some(task) = self.running.take() {
+            task.abort();
+        }
+        Ok(StopResp {})
+    }
+
+    fn name(&self) -> String {
+        format!("{} at {}", self.model, self.url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model_server::GenerationConfig;
+
+    #[test]
+    fn test_completion_body_and_text() {
+        let req = InferReq {
+            prompt: "jake:".into(),
+            config: GenerationConfig {
+                do_sample: false,
+                ..Default::default()
+            },
+        };
+        let body = completion_body("llama", &req);
+        assert_eq!(body["model"], "llama");
+        assert_eq!(body["temperature"], 0.0);
+        assert_eq!(body["max_tokens"], 2000);
+
+        let resp = serde_json::json!({"choices": [{"text": " hello", "index": 0}]});
+        assert_eq!(completion_text(&resp).unwrap(), " hello");
+        let resp = serde_json::json!({"error": {"message": "model not found"}});
+        assert!(completion_text(&resp).is_err());
+    }
+
+    #[test]
+    fn test_completion_chunks() {
+        let mut completion = Completion::default();
+        let chunks = [
+            serde_json::json!({"id": "cmpl-1", "choices": [{"text": "hel", "finish_reason": null}]}),
+            serde_json::json!({"id": "cmpl-1", "choices": [{"text": "lo", "finish_reason": "length"}]}),
+            serde_json::json!({"id": "cmpl-1", "choices": [], "usage": {"prompt_tokens": 12, "completion_tokens": 2}}),
+        ];
+        let texts: Vec<String> = chunks.iter().map(|c| completion.push(c).unwrap()).collect();
+        assert_eq!(texts, ["hel", "lo", ""]);
+        let req = InferReq {
+            prompt: "jake:".into(),
+            config: GenerationConfig {
+                stop_sequences: vec!["[[meta]]".into()],
+                ..Default::default()
+            },
+        };
+        let resp = completion.finish(&req, Instant::now());
+        assert_eq!(resp.job_id, "cmpl-1");
+        assert_eq!(resp.completion, "hello");
+        assert_eq!((resp.prompt_tokens, resp.completion_tokens), (12, 2));
+        assert_eq!(resp.finish_reason, FinishReason::MaxNewTokens);
+    }
 }
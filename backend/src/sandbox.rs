@@ -0,0 +1,296 @@
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use anyhow::{anyhow, bail, Context};
+use bollard::container::LogOutput;
+use futures_util::stream;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::Child;
+
+use crate::nexos::{
+    BuildResult, CommandRun, DockerResult, ExecBackend, LimitHit, NexosLimits, ReadOutcome, Shell,
+};
+
+/// Runs commands in a bubblewrap sandbox on this machine instead of docker. Only the
+/// system directories (read only) and the home directory are visible in it.
+///
+/// The cpu, memory and pids limits aren't enforced here, only the timeout and output cap
+pub struct LocalBackend {
+    home: PathBuf,
+    /// Deleted with the backend when we made the home directory ourselves
+    _tempdir: Option<tempfile::TempDir>,
+    rt: tokio::runtime::Runtime,
+    shell: Option<(Child, Shell)>,
+}
+
+impl LocalBackend {
+    /// `home` is used as /home/jake, a temp directory if there isn't one
+    pub fn new(home: Option<&str>) -> anyhow::Result<Self> {
+        let (home, tempdir) = match home {
+            Some(home) => {
+                std::fs::create_dir_all(home)?;
+                (PathBuf::from(home), None)
+            }
+            None => {
+                // a directory inside it so the snapshots next to it go away with it too
+                let tempdir = tempfile::Builder::new().prefix("nexos-").tempdir()?;
+                let home = tempdir.path().join("home");
+                std::fs::create_dir(&home)?;
+                (home, Some(tempdir))
+            }
+        };
+        Ok(Self {
+            home,
+            _tempdir: tempdir,
+            rt: tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(1)
+                .enable_all()
+                .build()?,
+            shell: None,
+        })
+    }
+
+    fn spawn_shell(&mut self) -> anyhow::Result<()> {
+        let _guard = self.rt.enter();
+        let mut child = sandbox_command(&self.home)
+            .spawn()
+            .context("failed to start bwrap, is bubblewrap installed?")?;
+        let input = child.stdin.take().ok_or(anyhow!("no stdin"))?;
+        let stdout = child.stdout.take().ok_or(anyhow!("no stdout"))?;
+        let stderr = child.stderr.take().ok_or(anyhow!("no stderr"))?;
+        let output = stream::select(
+            chunks(stdout, |message| LogOutput::StdOut {
+                message: message.into(),
+            }),
+            chunks(stderr, |message| LogOutput::StdErr {
+                message: message.into(),
+            }),
+        );
+        self.shell = Some((
+            child,
+            Shell {
+                input: Box::pin(input),
+                output: Box::pin(output),
+            },
+        ));
+        Ok(())
+    }
+
+    /// The file on the host. Jake can make symlinks to anywhere in the sandbox, so the
+    /// part of the path that exists has to resolve to somewhere in the home directory
+    fn path(&self, path: &str) -> anyhow::Result<PathBuf> {
+        let full = self.home.join(path.trim_start_matches('/'));
+        let mut existing = full.as_path();
+        while existing.symlink_metadata().is_err() {
+            existing = existing
+                .parent()
+                .ok_or(anyhow!("nothing of {path} exists"))?;
+        }
+        let resolved = existing
+            .canonicalize()
+            .with_context(|| format!("failed to resolve {path}"))?;
+        if !resolved.starts_with(self.home.canonicalize()?) {
+            bail!("{path} leads outside of the home directory");
+        }
+        Ok(full)
+    }
+}
+
+impl ExecBackend for LocalBackend {
+    fn exec(&mut self, command: &str, limits: &NexosLimits) -> anyhow::Result<DockerResult> {
+        if self.shell.is_none() {
+            self.spawn_shell()?;
+        }
+        let rt = self.rt.handle().clone();
+        rt.block_on(async {
+            let (child, shell) = self.shell.as_mut().unwrap();
+            let mut run = CommandRun::start(shell, command, limits).await?;
+            let outcome = run.read_until(shell, CommandRun::deadline(limits)).await;
+            match outcome {
+                Ok(ReadOutcome::Done) => Ok(run.finish(-1, limits)),
+                // without a way into the sandbox's pid namespace the whole shell goes
+                Ok(ReadOutcome::TimedOut) => {
+                    run.limits.push(LimitHit::Timeout {
+                        secs: limits.timeout_secs,
+                    });
+                    run.limits.push(LimitHit::ShellKilled);
+                    child.kill().await?;
+                    self.shell = None;
+                    Ok(run.finish(137, limits))
+                }
+                Ok(ReadOutcome::Closed) => {
+                    let status = child.wait().await?;
+                    self.shell = None;
+                    Ok(run.finish(status.code().unwrap_or(-1), limits))
+                }
+                Err(e) => {
+                    self.shell = None;
+                    Err(e)
+                }
+            }
+        })
+    }
+
+    fn attach(&mut self, _limits: &NexosLimits) -> anyhow::Result<String> {
+        if self.shell.is_some() {
+            return Ok("Already attached".to_string());
+        }
+        self.spawn_shell()?;
+        Ok(format!(
+            "Started a sandboxed shell, ~ is {} on the host",
+            self.home.display()
+        ))
+    }
+
+    fn rebuild(&mut self) -> anyhow::Result<BuildResult> {
+        Ok(BuildResult {
+            error: Some("Nexos is running in a local sandbox, there is no image to rebuild".into()),
+            ..Default::default()
+        })
+    }
+
+    fn restore_image(&mut self) -> anyhow::Result<String> {
+        Ok("Nexos is running in a local sandbox, there is no image to restore".to_string())
+    }
+
+    fn image(&self) -> &str {
+        "the local sandbox"
+    }
+
+    fn home_dir(&self) -> Option<PathBuf> {
+        Some(self.home.clone())
+    }
+
+    fn reset(&mut self) -> anyhow::Result<()> {
+        // kill_on_drop takes care of the process
+        self.shell = None;
+        Ok(())
+    }
+
+    fn read_file(&mut self, path: &str, max_bytes: usize) -> anyhow::Result<Vec<u8>> {
+        let full = self.path(path)?;
+        let size = std::fs::metadata(&full)
+            .with_context(|| format!("failed to read {path}"))?
+            .len();
+        if max_bytes > 0 && size > max_bytes as u64 {
+            bail!("{path} is {size} bytes, bigger than the {max_bytes} byte limit");
+        }
+        std::fs::read(&full).with_context(|| format!("failed to read {path}"))
+    }
+
+    fn write_file(&mut self, path: &str, contents: &[u8]) -> anyhow::Result<()> {
+        let full = self.path(path)?;
+        if let Some(parent) = full.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&full, contents).with_context(|| format!("failed to write {path}"))
+    }
+}
+
+fn sandbox_command(home: &Path) -> tokio::process::Command {
+    let mut command = tokio::process::Command::new("bwrap");
+    for dir in ["/usr", "/bin", "/lib", "/lib64", "/sbin", "/etc"] {
+        command.args(["--ro-bind-try", dir, dir]);
+    }
+    command
+        .args(["--dev", "/dev", "--proc", "/proc", "--tmpfs", "/tmp"])
+        // /home/jake has to be made somewhere writable
+        .args(["--tmpfs", "/home", "--bind"])
+        .arg(home)
+        .arg("/home/jake")
+        .args(["--setenv", "HOME", "/home/jake", "--chdir", "/home/jake"])
+        .args([
+            "--unshare-all",
+            "--share-net",
+            "--die-with-parent",
+            "--new-session",
+        ])
+        .arg("sh")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    command
+}
+
+/// Turns a pipe into the same kind of stream docker gives us for an attached exec
+fn chunks<R>(
+    reader: R,
+    wrap: fn(Vec<u8>) -> LogOutput,
+) -> impl futures_util::Stream<Item = Result<LogOutput, bollard::errors::Error>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    stream::unfold(reader, move |mut reader| async move {
+        let mut buf = vec![0; 8192];
+        match reader.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(wrap(buf)), reader))
+            }
+            Err(err) => Some((Err(bollard::errors::Error::IOError { err }), reader)),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversation::{Conversation, ConversationAction, Message, User};
+    use crate::nexos::{self, BackendKind, LogLine, NexosConfig};
+
+    #[test]
+    #[ignore = "needs bwrap, run with --ignored"]
+    fn test_local_backend_keeps_state() {
+        let mut backend = LocalBackend::new(None).unwrap();
+        let limits = NexosLimits::default();
+        let result = backend.exec("mkdir -p a && cd a && pwd", &limits).unwrap();
+        assert_eq!(result.exit_code, 0);
+        let result = backend.exec("pwd; echo oops >&2; false", &limits).unwrap();
+        assert_eq!(result.exit_code, 1);
+        assert!(matches!(
+            &result.output[..],
+            [LogLine::StdOut { message: out }, LogLine::StdErr { message: err }]
+                if out == "/home/jake/a\n" && err == "oops\n"
+        ));
+        backend.write_file("a/b.txt", b"hi").unwrap();
+        let result = backend.exec("cat b.txt", &limits).unwrap();
+        assert!(matches!(&result.output[..], [LogLine::StdOut { message }] if message == "hi"));
+
+        let short = NexosLimits {
+            timeout_secs: 1,
+            ..Default::default()
+        };
+        let result = backend.exec("sleep 10", &short).unwrap();
+        assert!(result.limits.contains(&LimitHit::Timeout { secs: 1 }));
+        // a new shell, back in ~
+        let result = backend.exec("pwd", &limits).unwrap();
+        assert!(
+            matches!(&result.output[..], [LogLine::StdOut { message }] if message == "/home/jake\n")
+        );
+    }
+
+    #[test]
+    #[ignore = "needs bwrap, run with --ignored"]
+    fn test_eval_end_to_end() {
+        nexos::configure(NexosConfig {
+            nexos_backend: BackendKind::Local,
+            ..Default::default()
+        });
+        let mut conversation = Conversation {
+            id: Some(uuid::Uuid::new_v4().to_string()),
+            ..Default::default()
+        };
+        let msg = Message::new_with_msg(User::Jake, "[<cd /tmp>] [<pwd>]".to_string());
+        let id = msg.id.clone();
+        conversation.messages.push(msg);
+        conversation
+            .apply(ConversationAction::EvalMessage { id: id.clone() })
+            .unwrap();
+        let results = conversation.command_results(&id);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[1].stdout, "/tmp");
+        assert!(!conversation.commands_failed(&id));
+    }
+}
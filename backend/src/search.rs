@@ -0,0 +1,403 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+
+use crate::conversation::{Conversation, ConversationAction, TaskAction, User};
+use crate::history::HistoryEntry;
+use crate::store::{ConversationStore, RevisionUpdate};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DocKind {
+    Message {
+        user: User,
+    },
+    InjectedFile {
+        filename: String,
+    },
+    /// The name of a task, the doc id is the message that created it
+    Task,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Doc {
+    pub conversation_id: String,
+    /// Id of the message or injected file this came from
+    pub id: String,
+    pub kind: DocKind,
+    pub text: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchHit {
+    pub conversation_id: String,
+    pub id: String,
+    pub kind: DocKind,
+    pub snippet: String,
+    pub score: usize,
+}
+
+/// A parsed search. `foo "bar baz" user:docker` matches docs containing foo and the
+/// exact phrase "bar baz" that were written by Docker
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Query {
+    pub terms: Vec<String>,
+    pub phrases: Vec<String>,
+    pub users: Vec<String>,
+}
+
+impl Query {
+    pub fn parse(query: &str) -> Self {
+        let mut result = Query::default();
+        let mut rest = query;
+        while let Some(start) = rest.find('"') {
+            result.add_words(&rest[..start]);
+            let after = &rest[start + 1..];
+            match after.find('"') {
+                Some(end) => {
+                    let phrase = after[..end].trim().to_lowercase();
+                    if !phrase.is_empty() {
+                        result.phrases.push(phrase);
+                    }
+                    rest = &after[end + 1..];
+                }
+                // an unclosed quote is just treated as more words
+                None => rest = after,
+            }
+        }
+        result.add_words(rest);
+        result
+    }
+    fn add_words(&mut self, words: &str) {
+        for word in words.split_whitespace() {
+            if let Some(user) = word.strip_prefix("user:") {
+                self.users.push(user.to_lowercase());
+            } else {
+                self.terms.extend(tokenize(word));
+            }
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty() && self.phrases.is_empty()
+    }
+}
+
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect()
+}
+
+type DocKey = (String, String);
+
+/// An inverted index over every message, injected file and task name
+#[derive(Default)]
+pub struct SearchIndex {
+    docs: HashMap<DocKey, Doc>,
+    postings: HashMap<String, HashSet<DocKey>>,
+    by_conversation: HashMap<String, Vec<DocKey>>,
+}
+
+impl SearchIndex {
+    pub fn docs_for(conversation: &Conversation) -> Vec<Doc> {
+        let conversation_id = conversation.id.clone().unwrap_or_default();
+        let mut docs = Vec::new();
+        for msg in &conversation.messages {
+            docs.push(Doc {
+                conversation_id: conversation_id.clone(),
+                id: msg.id.clone(),
+                kind: DocKind::Message {
+                    user: msg.user.clone(),
+                },
+                text: msg.msg.clone(),
+            });
+            for action in &msg.meta.task_actions {
+                if let TaskAction::Create { name, .. } = action {
+                    docs.push(Doc {
+                        conversation_id: conversation_id.clone(),
+                        id: msg.id.clone(),
+                        kind: DocKind::Task,
+                        text: name.clone(),
+                    });
+                }
+            }
+        }
+        for file in &conversation.injected_files {
+            docs.push(Doc {
+                conversation_id: conversation_id.clone(),
+                id: file.id.clone(),
+                kind: DocKind::InjectedFile {
+                    filename: file.filename.clone(),
+                },
+                text: file.filetext.clone(),
+            });
+        }
+        docs
+    }
+
+    /// (Re)indexes the conversation, replacing whatever was indexed for it before
+    pub fn index_conversation(&mut self, conversation: &Conversation) {
+        let Some(ref conversation_id) = conversation.id else {
+            return;
+        };
+        self.remove_conversation(conversation_id);
+        let mut keys = Vec::new();
+        for doc in Self::docs_for(conversation) {
+            // tasks share the id of the message that created them
+            let key = match doc.kind {
+                DocKind::Task => (doc.conversation_id.clone(), format!("task:{}", doc.id)),
+                _ => (doc.conversation_id.clone(), doc.id.clone()),
+            };
+            for token in tokenize(&doc.text) {
+                self.postings.entry(token).or_default().insert(key.clone());
+            }
+            keys.push(key.clone());
+            self.docs.insert(key, doc);
+        }
+        self.by_conversation.insert(conversation_id.clone(), keys);
+    }
+
+    pub fn remove_conversation(&mut self, conversation_id: &str) {
+        let Some(keys) = self.by_conversation.remove(conversation_id) else {
+            return;
+        };
+        for key in keys {
+            if let Some(doc) = self.docs.remove(&key) {
+                for token in tokenize(&doc.text) {
+                    if let Some(posting) = self.postings.get_mut(&token) {
+                        posting.remove(&key);
+                        if posting.is_empty() {
+                            self.postings.remove(&token);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn search(&self, query: &Query) -> Vec<SearchHit> {
+        if query.is_empty() {
+            return Vec::new();
+        }
+        // every word has to be present, phrase words included
+        let mut words: Vec<String> = query.terms.clone();
+        for phrase in &query.phrases {
+            words.extend(tokenize(phrase));
+        }
+        let mut candidates: Option<HashSet<&DocKey>> = None;
+        for word in &words {
+            let posting: HashSet<&DocKey> = match self.postings.get(word) {
+                Some(posting) => posting.iter().collect(),
+                None => return Vec::new(),
+            };
+            candidates = Some(match candidates {
+                Some(c) => c.intersection(&posting).copied().collect(),
+                None => posting,
+            });
+        }
+        let candidates = match candidates {
+            Some(c) => c,
+            // phrases made only of punctuation, fall back to scanning everything
+            None => self.docs.keys().collect(),
+        };
+
+        let mut hits = Vec::new();
+        for key in candidates {
+            let doc = &self.docs[key];
+            if !query.users.is_empty() {
+                let DocKind::Message { ref user } = doc.kind else {
+                    continue;
+                };
+                if !query.users.iter().any(|u| u == user.key()) {
+                    continue;
+                }
+            }
+            let lower = doc.text.to_lowercase();
+            if !query.phrases.iter().all(|p| lower.contains(p.as_str())) {
+                continue;
+            }
+            let tokens = tokenize(&doc.text);
+            let score = tokens.iter().filter(|t| words.contains(t)).count()
+                + query
+                    .phrases
+                    .iter()
+                    .map(|p| lower.matches(p.as_str()).count() * 2)
+                    .sum::<usize>();
+            let needle = query
+                .phrases
+                .first()
+                .or(query.terms.first())
+                .cloned()
+                .unwrap_or_default();
+            hits.push(SearchHit {
+                conversation_id: doc.conversation_id.clone(),
+                id: doc.id.clone(),
+                kind: doc.kind.clone(),
+                snippet: snippet(&doc.text, &needle, 60),
+                score,
+            });
+        }
+        hits.sort_by(|a, b| b.score.cmp(&a.score).then(a.id.cmp(&b.id)));
+        hits
+    }
+}
+
+/// Up to `radius` chars either side of the first occurrence of `needle`, on one line
+pub fn snippet(text: &str, needle: &str, radius: usize) -> String {
+    let lower = text.to_lowercase();
+    // lowercasing can change byte lengths so only trust the position if it maps back cleanly
+    let pos = lower
+        .find(needle)
+        .filter(|p| text.is_char_boundary(*p))
+        .unwrap_or(0);
+    let start = text[..pos]
+        .char_indices()
+        .rev()
+        .take(radius)
+        .last()
+        .map(|(i, _)| i)
+        .unwrap_or(pos);
+    let end = text[pos..]
+        .char_indices()
+        .nth(radius + needle.chars().count())
+        .map(|(i, _)| pos + i)
+        .unwrap_or(text.len());
+    let mut result = text[start..end].replace('\n', " ");
+    if start > 0 {
+        result = format!("...{result}");
+    }
+    if end < text.len() {
+        result.push_str("...");
+    }
+    result
+}
+
+/// A store that keeps a `SearchIndex` in sync with everything written through it
+pub struct IndexedStore {
+    inner: Box<dyn ConversationStore>,
+    pub index: SearchIndex,
+}
+
+impl IndexedStore {
+    pub fn new(inner: Box<dyn ConversationStore>) -> Self {
+        let mut index = SearchIndex::default();
+        for (_, conversation) in inner.iter() {
+            index.index_conversation(&conversation);
+        }
+        Self { inner, index }
+    }
+    pub fn search(&self, query: &str) -> Vec<SearchHit> {
+        self.index.search(&Query::parse(query))
+    }
+}
+
+impl ConversationStore for IndexedStore {
+    fn get(&self, uuid: &str) -> Result<Option<Conversation>> {
+        self.inner.get(uuid)
+    }
+    fn insert(&mut self, to_insert: &mut Conversation) -> Result<String> {
+        let uuid = self.inner.insert(to_insert)?;
+        self.index.index_conversation(to_insert);
+        Ok(uuid)
+    }
+    fn delete(&mut self, uuid: &str) -> Result<()> {
+        self.inner.delete(uuid)?;
+        self.index.remove_conversation(uuid);
+        Ok(())
+    }
+    fn try_iter(&self) -> Result<Vec<(String, Result<Conversation>)>> {
+        self.inner.try_iter()
+    }
+    fn schema_version(&self) -> Result<Option<u32>> {
+        self.inner.schema_version()
+    }
+    fn raw_records(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        self.inner.raw_records()
+    }
+    fn raw_history(&self, uuid: &str) -> Result<Vec<Vec<u8>>> {
+        self.inner.raw_history(uuid)
+    }
+    fn write_migrated(
+        &mut self,
+        records: Vec<(String, Vec<u8>)>,
+        history: Vec<(String, Vec<HistoryEntry>)>,
+        version: u32,
+    ) -> Result<()> {
+        let ids: Vec<String> = records.iter().map(|(id, _)| id.clone()).collect();
+        self.inner.write_migrated(records, history, version)?;
+        for id in ids {
+            if let Some(conversation) = self.inner.get(&id)? {
+                self.index.index_conversation(&conversation);
+            }
+        }
+        Ok(())
+    }
+    fn history(&self, uuid: &str) -> Result<Vec<HistoryEntry>> {
+        self.inner.history(uuid)
+    }
+    fn stored_history_head(&self, uuid: &str) -> Result<Option<u64>> {
+        self.inner.stored_history_head(uuid)
+    }
+    fn write_revision(&mut self, uuid: &str, update: RevisionUpdate) -> Result<()> {
+        let conversation = update.conversation.clone();
+        self.inner.write_revision(uuid, update)?;
+        self.index.index_conversation(&conversation);
+        Ok(())
+    }
+    fn apply(&mut self, uuid: &str, action: ConversationAction) -> Result<Conversation> {
+        self.inner
+            .apply(uuid, action)
+            .inspect(|conversation| self.index.index_conversation(conversation))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversation::Message;
+
+    fn conversation() -> Conversation {
+        Conversation {
+            id: Some("c1".into()),
+            messages: vec![
+                Message::new_with_msg(User::Zack, "Can you build the rust project?".into()),
+                Message::new_with_msg(User::Docker, "error: could not compile `sqrt2`".into()),
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let query = Query::parse(r#"build "could not compile" user:Docker"#);
+        assert_eq!(query.terms, vec!["build"]);
+        assert_eq!(query.phrases, vec!["could not compile"]);
+        assert_eq!(query.users, vec!["docker"]);
+    }
+
+    #[test]
+    fn test_search() {
+        let mut index = SearchIndex::default();
+        let conversation = conversation();
+        index.index_conversation(&conversation);
+
+        let hits = index.search(&Query::parse("compile"));
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].id, conversation.messages[1].id);
+
+        assert_eq!(index.search(&Query::parse("\"rust project\"")).len(), 1);
+        assert!(index.search(&Query::parse("\"project rust\"")).is_empty());
+        assert!(index.search(&Query::parse("rust user:docker")).is_empty());
+        assert_eq!(index.search(&Query::parse("sqrt2 user:docker")).len(), 1);
+
+        index.remove_conversation("c1");
+        assert!(index.search(&Query::parse("compile")).is_empty());
+    }
+
+    #[test]
+    fn test_snippet() {
+        let text = "a".repeat(100) + " needle " + &"b".repeat(100);
+        let snip = snippet(&text, "needle", 5);
+        assert_eq!(snip, "...aaaa needle bbbb...");
+    }
+}
@@ -0,0 +1,185 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::nexos;
+
+/// Git history of Jake's home directory, one commit per evaluated message.
+///
+/// The repository is bare and kept next to the home directory (`persist` ->
+/// `persist.snapshots`) so nothing Jake runs can get at it
+pub struct Snapshots {
+    git_dir: PathBuf,
+    work_tree: PathBuf,
+}
+
+impl Snapshots {
+    pub fn open(home: &Path) -> Result<Self> {
+        let name = home.file_name().ok_or(anyhow!(
+            "{} has no name to put snapshots next to",
+            home.display()
+        ))?;
+        let mut snapshots_name = name.to_os_string();
+        snapshots_name.push(".snapshots");
+        let snapshots = Self {
+            git_dir: home.with_file_name(snapshots_name),
+            work_tree: home.to_path_buf(),
+        };
+        if !snapshots.git_dir.exists() {
+            let output = std::process::Command::new("git")
+                .args(["init", "--quiet", "--bare"])
+                .arg(&snapshots.git_dir)
+                .output()
+                .context("failed to run git, is it installed?")?;
+            if !output.status.success() {
+                bail!(
+                    "git init failed: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+        }
+        Ok(snapshots)
+    }
+
+    fn git(&self, args: &[&str]) -> Result<String> {
+        let output = std::process::Command::new("git")
+            .arg("--git-dir")
+            .arg(&self.git_dir)
+            .arg("--work-tree")
+            .arg(&self.work_tree)
+            // the user's global config shouldn't decide whether a snapshot works
+            .args(["-c", "user.name=nexos", "-c", "user.email=nexos@localhost"])
+            .args(["-c", "commit.gpgsign=false"])
+            .args(args)
+            .output()
+            .context("failed to run git, is it installed?")?;
+        if !output.status.success() {
+            bail!(
+                "git {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    /// Commits everything in the home directory, returns the commit
+    pub fn snapshot(&self, message: &str) -> Result<String> {
+        self.git(&["add", "--all"])?;
+        self.git(&[
+            "commit",
+            "--quiet",
+            "--allow-empty",
+            "--no-verify",
+            "-m",
+            message,
+        ])?;
+        Ok(self.git(&["rev-parse", "HEAD"])?.trim().to_string())
+    }
+
+    /// Puts the home directory back the way it was at `commit`. What was there before is
+    /// snapshotted first, that commit is returned so the restore can be undone
+    pub fn restore(&self, commit: &str) -> Result<String> {
+        let before = self.snapshot(&format!("before restoring {commit}"))?;
+        // everything is in the index after the snapshot, so files that didn't exist at
+        // `commit` are removed too
+        self.git(&["read-tree", "-u", "--reset", commit])?;
+        self.snapshot(&format!("restored {commit}"))?;
+        Ok(before)
+    }
+
+    pub fn diff(&self, from: &str, to: &str) -> Result<String> {
+        self.git(&[
+            "diff",
+            "--no-color",
+            "--no-ext-diff",
+            "--stat",
+            "--patch",
+            from,
+            to,
+        ])
+    }
+}
+
+fn session_snapshots(session_id: &str) -> Result<Option<Snapshots>> {
+    let home = nexos::with_session(session_id, |session| Ok(session.home_dir()))?;
+    home.map(|home| Snapshots::open(&home)).transpose()
+}
+
+/// Snapshots the home directory of a Nexos session after `message_id` was evaluated.
+/// None when the session has no home directory on this machine (replaying a cassette)
+pub fn take(session_id: &str, message_id: &str) -> Result<Option<String>> {
+    match session_snapshots(session_id)? {
+        Some(snapshots) => Ok(Some(
+            snapshots.snapshot(&format!("{message_id} in {session_id}"))?,
+        )),
+        None => Ok(None),
+    }
+}
+
+pub fn restore(session_id: &str, commit: &str) -> Result<String> {
+    session_snapshots(session_id)?
+        .ok_or(anyhow!("nexos has no home directory to restore"))?
+        .restore(commit)
+}
+
+pub fn diff(session_id: &str, from: &str, to: &str) -> Result<String> {
+    session_snapshots(session_id)?
+        .ok_or(anyhow!("nexos has no home directory to diff"))?
+        .diff(from, to)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn has_git() -> bool {
+        std::process::Command::new("git")
+            .arg("--version")
+            .output()
+            .map(|o| o.status.success())
+            .unwrap_or(false)
+    }
+
+    #[test]
+    fn test_snapshot_restore_and_diff() {
+        if !has_git() {
+            println!("git isn't installed, skipping");
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path().join("home");
+        std::fs::create_dir(&home).unwrap();
+        let snapshots = Snapshots::open(&home).unwrap();
+
+        std::fs::write(home.join("notes.txt"), "one\n").unwrap();
+        let first = snapshots.snapshot("first").unwrap();
+        std::fs::write(home.join("notes.txt"), "two\n").unwrap();
+        std::fs::write(home.join("new.txt"), "new\n").unwrap();
+        let second = snapshots.snapshot("second").unwrap();
+        // nothing changed, still a snapshot of its own
+        let third = snapshots.snapshot("third").unwrap();
+        assert_ne!(second, third);
+
+        let diff = snapshots.diff(&first, &second).unwrap();
+        assert!(diff.contains("-one"));
+        assert!(diff.contains("+two"));
+        assert!(diff.contains("new.txt"));
+        assert!(snapshots.diff(&second, &third).unwrap().is_empty());
+
+        std::fs::write(home.join("scratch.txt"), "unsaved\n").unwrap();
+        let before = snapshots.restore(&first).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(home.join("notes.txt")).unwrap(),
+            "one\n"
+        );
+        assert!(!home.join("new.txt").exists());
+        assert!(!home.join("scratch.txt").exists());
+        // and back again
+        snapshots.restore(&before).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(home.join("scratch.txt")).unwrap(),
+            "unsaved\n"
+        );
+    }
+}
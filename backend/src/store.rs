@@ -0,0 +1,520 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use uuid::Uuid;
+
+use crate::conversation::{Conversation, ConversationAction, Conversations};
+use crate::history::{self, HistoryEntry};
+use crate::migrations;
+
+/// Everything `ConversationStore::write_revision` has to persist in one go
+#[derive(Clone, Debug)]
+pub struct RevisionUpdate {
+    /// The new current state of the conversation
+    pub conversation: Conversation,
+    /// History entries to write (replacing any entry with the same revision)
+    pub entries: Vec<HistoryEntry>,
+    /// Drop every history entry above this revision
+    pub truncate_after: Option<u64>,
+    pub head: u64,
+}
+
+/// Somewhere to keep conversations.
+///
+/// Conversations are stored as named msgpack records (see `migrations`) so the
+/// raw record methods mean the same thing for every implementation.
+pub trait ConversationStore {
+    fn get(&self, uuid: &str) -> Result<Option<Conversation>>;
+    fn insert(&mut self, to_insert: &mut Conversation) -> Result<String>;
+    /// Deletes the conversation and its history
+    fn delete(&mut self, uuid: &str) -> Result<()>;
+    /// Every conversation, including the records that failed to decode
+    fn try_iter(&self) -> Result<Vec<(String, Result<Conversation>)>>;
+
+    /// None when the store was written before schema versions existed
+    fn schema_version(&self) -> Result<Option<u32>>;
+    fn raw_records(&self) -> Result<Vec<(String, Vec<u8>)>>;
+    /// The history entries of the conversation as they are stored, oldest first
+    fn raw_history(&self, uuid: &str) -> Result<Vec<Vec<u8>>>;
+    /// Overwrites `records` and the history entries in `history` and sets the schema
+    /// version, all or nothing
+    fn write_migrated(
+        &mut self,
+        records: Vec<(String, Vec<u8>)>,
+        history: Vec<(String, Vec<HistoryEntry>)>,
+        version: u32,
+    ) -> Result<()>;
+
+    /// Every recorded revision of the conversation, oldest first
+    fn history(&self, uuid: &str) -> Result<Vec<HistoryEntry>>;
+    /// The head as stored, use `history_head` for the effective one
+    fn stored_history_head(&self, uuid: &str) -> Result<Option<u64>>;
+    fn write_revision(&mut self, uuid: &str, update: RevisionUpdate) -> Result<()>;
+
+    fn iter(&self) -> Vec<(String, Conversation)> {
+        let records = match self.try_iter() {
+            Ok(records) => records,
+            Err(err) => {
+                eprintln!("{err:?}");
+                return Vec::new();
+            }
+        };
+        let mut data = Vec::new();
+        for (uuid, conv) in records {
+            match conv {
+                Ok(conv) => data.push((uuid, conv)),
+                Err(err) => {
+                    eprintln!("{err:?}")
+                }
+            }
+        }
+        data
+    }
+    /// Errors if the store was written by an older schema and needs `migrate` first
+    fn check_schema(&self) -> Result<()> {
+        let version = self.schema_version()?.unwrap_or(0);
+        let current = migrations::current_version();
+        if version != current {
+            bail!("db is at schema v{version} but v{current} is required, run `migrate` first");
+        }
+        Ok(())
+    }
+    /// Applies `action` to the stored conversation and records it in the conversation's history
+    fn apply(&mut self, uuid: &str, action: ConversationAction) -> Result<Conversation> {
+        history::apply(self, uuid, action)
+    }
+    /// Records `after` as what `action` did to the stored conversation, when the action
+    /// was applied to a copy of it
+    fn record(
+        &mut self,
+        uuid: &str,
+        action: ConversationAction,
+        after: Conversation,
+    ) -> Result<Conversation> {
+        let before = self
+            .get(uuid)?
+            .ok_or(anyhow!("no conversation with id {uuid}"))?;
+        history::record(self, uuid, before, action, after)
+    }
+    /// Applies `action` without recording it in the history, for actions that only change
+    /// what is being looked at
+    fn apply_unrecorded(&mut self, uuid: &str, action: ConversationAction) -> Result<Conversation> {
+        history::apply_unrecorded(self, uuid, action)
+    }
+    /// The revision the stored conversation currently reflects
+    fn history_head(&self, uuid: &str) -> Result<Option<u64>> {
+        history::head(self, uuid)
+    }
+    fn undo(&mut self, uuid: &str) -> Result<Conversation> {
+        history::undo(self, uuid)
+    }
+    fn redo(&mut self, uuid: &str) -> Result<Conversation> {
+        history::redo(self, uuid)
+    }
+    /// Brings back an earlier revision as a new revision so nothing in between is lost
+    fn restore(&mut self, uuid: &str, revision: u64) -> Result<Conversation> {
+        history::restore(self, uuid, revision)
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum StoreKind {
+    Jammdb,
+    Sqlite,
+    /// Nothing is saved, handy for trying things out
+    Memory,
+}
+
+pub fn open_store(kind: StoreKind, path: &str) -> Result<Box<dyn ConversationStore>> {
+    Ok(match kind {
+        StoreKind::Jammdb => {
+            let db = jammdb::DB::open(path).context("failed to open jammdb")?;
+            Box::new(Conversations::new(Arc::new(db), None)?)
+        }
+        StoreKind::Sqlite => Box::new(SqliteStore::open(path)?),
+        StoreKind::Memory => Box::new(MemoryStore::default()),
+    })
+}
+
+fn assign_id(to_insert: &mut Conversation) -> String {
+    match to_insert.id {
+        Some(ref id) => id.clone(),
+        None => {
+            let new_uuid = Uuid::new_v4().to_string();
+            to_insert.id = Some(new_uuid.clone());
+            new_uuid
+        }
+    }
+}
+
+fn encode(conversation: &Conversation) -> Result<Vec<u8>> {
+    rmp_serde::to_vec_named(conversation).context("Failed to serialize conversation data")
+}
+
+fn decode(uuid: &str, data: &[u8]) -> Result<Conversation> {
+    rmp_serde::from_slice(data).with_context(|| format!("failed to decode conversation {uuid}"))
+}
+
+/// Keeps everything in memory, mostly for tests
+pub struct MemoryStore {
+    records: HashMap<String, Vec<u8>>,
+    history: HashMap<String, Vec<HistoryEntry>>,
+    heads: HashMap<String, u64>,
+    version: Option<u32>,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self {
+            records: HashMap::new(),
+            history: HashMap::new(),
+            heads: HashMap::new(),
+            version: Some(migrations::current_version()),
+        }
+    }
+}
+
+impl ConversationStore for MemoryStore {
+    fn get(&self, uuid: &str) -> Result<Option<Conversation>> {
+        self.records
+            .get(uuid)
+            .map(|data| decode(uuid, data))
+            .transpose()
+    }
+    fn insert(&mut self, to_insert: &mut Conversation) -> Result<String> {
+        let uuid = assign_id(to_insert);
+        self.records.insert(uuid.clone(), encode(to_insert)?);
+        Ok(uuid)
+    }
+    fn delete(&mut self, uuid: &str) -> Result<()> {
+        self.records.remove(uuid);
+        self.history.remove(uuid);
+        self.heads.remove(uuid);
+        Ok(())
+    }
+    fn try_iter(&self) -> Result<Vec<(String, Result<Conversation>)>> {
+        Ok(self
+            .records
+            .iter()
+            .map(|(uuid, data)| (uuid.clone(), decode(uuid, data)))
+            .collect())
+    }
+    fn schema_version(&self) -> Result<Option<u32>> {
+        Ok(self.version)
+    }
+    fn raw_records(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        Ok(self
+            .records
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
+    }
+    fn raw_history(&self, uuid: &str) -> Result<Vec<Vec<u8>>> {
+        self.history(uuid)?
+            .iter()
+            .map(|entry| {
+                rmp_serde::to_vec_named(entry).context("Failed to serialize history entry")
+            })
+            .collect()
+    }
+    fn write_migrated(
+        &mut self,
+        records: Vec<(String, Vec<u8>)>,
+        history: Vec<(String, Vec<HistoryEntry>)>,
+        version: u32,
+    ) -> Result<()> {
+        self.records.extend(records);
+        self.history.extend(history);
+        self.version = Some(version);
+        Ok(())
+    }
+    fn history(&self, uuid: &str) -> Result<Vec<HistoryEntry>> {
+        Ok(self.history.get(uuid).cloned().unwrap_or_default())
+    }
+    fn stored_history_head(&self, uuid: &str) -> Result<Option<u64>> {
+        Ok(self.heads.get(uuid).copied())
+    }
+    fn write_revision(&mut self, uuid: &str, update: RevisionUpdate) -> Result<()> {
+        let entries = self.history.entry(uuid.to_string()).or_default();
+        if let Some(truncate_after) = update.truncate_after {
+            entries.retain(|e| e.revision <= truncate_after);
+        }
+        for entry in update.entries {
+            entries.retain(|e| e.revision != entry.revision);
+            entries.push(entry);
+        }
+        entries.sort_by_key(|e| e.revision);
+        self.heads.insert(uuid.to_string(), update.head);
+        self.records
+            .insert(uuid.to_string(), encode(&update.conversation)?);
+        Ok(())
+    }
+}
+
+pub struct SqliteStore {
+    conn: Connection,
+}
+
+impl SqliteStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let conn = Connection::open(path).context("failed to open sqlite db")?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS conversations (id TEXT PRIMARY KEY, data BLOB NOT NULL);
+             CREATE TABLE IF NOT EXISTS history (
+                 conversation_id TEXT NOT NULL,
+                 revision INTEGER NOT NULL,
+                 data BLOB NOT NULL,
+                 PRIMARY KEY (conversation_id, revision)
+             );
+             CREATE TABLE IF NOT EXISTS history_heads (
+                 conversation_id TEXT PRIMARY KEY,
+                 head INTEGER NOT NULL
+             );",
+        )?;
+        let store = Self { conn };
+        // a db this build created has nothing to migrate
+        if store.schema_version()?.is_none() {
+            store.conn.execute(
+                "INSERT INTO meta (key, value) VALUES (?1, ?2)",
+                params![
+                    migrations::SCHEMA_VERSION_KEY,
+                    migrations::current_version().to_string()
+                ],
+            )?;
+        }
+        Ok(store)
+    }
+}
+
+impl ConversationStore for SqliteStore {
+    fn get(&self, uuid: &str) -> Result<Option<Conversation>> {
+        let data: Option<Vec<u8>> = self
+            .conn
+            .query_row(
+                "SELECT data FROM conversations WHERE id = ?1",
+                params![uuid],
+                |row| row.get(0),
+            )
+            .optional()?;
+        data.map(|data| decode(uuid, &data)).transpose()
+    }
+    fn insert(&mut self, to_insert: &mut Conversation) -> Result<String> {
+        let uuid = assign_id(to_insert);
+        self.conn.execute(
+            "INSERT OR REPLACE INTO conversations (id, data) VALUES (?1, ?2)",
+            params![uuid, encode(to_insert)?],
+        )?;
+        Ok(uuid)
+    }
+    fn delete(&mut self, uuid: &str) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute("DELETE FROM conversations WHERE id = ?1", params![uuid])?;
+        tx.execute(
+            "DELETE FROM history WHERE conversation_id = ?1",
+            params![uuid],
+        )?;
+        tx.execute(
+            "DELETE FROM history_heads WHERE conversation_id = ?1",
+            params![uuid],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+    fn try_iter(&self) -> Result<Vec<(String, Result<Conversation>)>> {
+        Ok(self
+            .raw_records()?
+            .into_iter()
+            .map(|(uuid, data)| {
+                let conv = decode(&uuid, &data);
+                (uuid, conv)
+            })
+            .collect())
+    }
+    fn schema_version(&self) -> Result<Option<u32>> {
+        let version: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT value FROM meta WHERE key = ?1",
+                params![migrations::SCHEMA_VERSION_KEY],
+                |row| row.get(0),
+            )
+            .optional()?;
+        version
+            .map(|v| v.parse::<u32>().context("schema version is not a number"))
+            .transpose()
+    }
+    fn raw_records(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut stmt = self.conn.prepare("SELECT id, data FROM conversations")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        let mut records = Vec::new();
+        for row in rows {
+            records.push(row?);
+        }
+        Ok(records)
+    }
+    fn raw_history(&self, uuid: &str) -> Result<Vec<Vec<u8>>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT data FROM history WHERE conversation_id = ?1 ORDER BY revision ASC")?;
+        let rows = stmt.query_map(params![uuid], |row| row.get::<_, Vec<u8>>(0))?;
+        let mut entries = Vec::new();
+        for row in rows {
+            entries.push(row?);
+        }
+        Ok(entries)
+    }
+    fn write_migrated(
+        &mut self,
+        records: Vec<(String, Vec<u8>)>,
+        history: Vec<(String, Vec<HistoryEntry>)>,
+        version: u32,
+    ) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        for (uuid, data) in records {
+            tx.execute(
+                "INSERT OR REPLACE INTO conversations (id, data) VALUES (?1, ?2)",
+                params![uuid, data],
+            )?;
+        }
+        for (uuid, entries) in history {
+            for entry in entries {
+                let data =
+                    rmp_serde::to_vec_named(&entry).context("Failed to serialize history entry")?;
+                tx.execute(
+                    "INSERT OR REPLACE INTO history (conversation_id, revision, data) VALUES (?1, ?2, ?3)",
+                    params![uuid, entry.revision as i64, data],
+                )?;
+            }
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)",
+            params![migrations::SCHEMA_VERSION_KEY, version.to_string()],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+    fn history(&self, uuid: &str) -> Result<Vec<HistoryEntry>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT data FROM history WHERE conversation_id = ?1 ORDER BY revision ASC")?;
+        let rows = stmt.query_map(params![uuid], |row| row.get::<_, Vec<u8>>(0))?;
+        let mut entries = Vec::new();
+        for row in rows {
+            entries
+                .push(rmp_serde::from_slice(&row?).context("Failed to deserialize history entry")?);
+        }
+        Ok(entries)
+    }
+    fn stored_history_head(&self, uuid: &str) -> Result<Option<u64>> {
+        let head: Option<i64> = self
+            .conn
+            .query_row(
+                "SELECT head FROM history_heads WHERE conversation_id = ?1",
+                params![uuid],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(head.map(|h| h as u64))
+    }
+    fn write_revision(&mut self, uuid: &str, update: RevisionUpdate) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        if let Some(truncate_after) = update.truncate_after {
+            tx.execute(
+                "DELETE FROM history WHERE conversation_id = ?1 AND revision > ?2",
+                params![uuid, truncate_after as i64],
+            )?;
+        }
+        for entry in &update.entries {
+            let data =
+                rmp_serde::to_vec_named(entry).context("Failed to serialize history entry")?;
+            tx.execute(
+                "INSERT OR REPLACE INTO history (conversation_id, revision, data) VALUES (?1, ?2, ?3)",
+                params![uuid, entry.revision as i64, data],
+            )?;
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO history_heads (conversation_id, head) VALUES (?1, ?2)",
+            params![uuid, update.head as i64],
+        )?;
+        tx.execute(
+            "INSERT OR REPLACE INTO conversations (id, data) VALUES (?1, ?2)",
+            params![uuid, encode(&update.conversation)?],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversation::User;
+
+    fn new_conversation(store: &mut dyn ConversationStore) -> String {
+        store.insert(&mut Conversation::default()).unwrap()
+    }
+
+    fn add(store: &mut dyn ConversationStore, uuid: &str, user: User, text: &str) -> Conversation {
+        let conversation = store
+            .apply(uuid, ConversationAction::AddMessage { index: None, user })
+            .unwrap();
+        let mut msg = conversation.active_messages().last().unwrap().clone();
+        msg.msg = text.to_string();
+        store
+            .apply(uuid, ConversationAction::MutateMessage { new_message: msg })
+            .unwrap()
+    }
+
+    fn round_trip(store: &mut dyn ConversationStore) {
+        let uuid = new_conversation(store);
+        add(store, &uuid, User::Zack, "hello");
+        let conversation = add(store, &uuid, User::Jake, "hi");
+        assert_eq!(store.get(&uuid).unwrap(), Some(conversation.clone()));
+        let history = store.history(&uuid).unwrap();
+        assert_eq!(history.last().unwrap().conversation, conversation);
+        assert_eq!(
+            store.history_head(&uuid).unwrap(),
+            Some(history.last().unwrap().revision)
+        );
+
+        // migrating writes the records and history back as they came out
+        let records = store.raw_records().unwrap();
+        let version = migrations::current_version();
+        store
+            .write_migrated(records, vec![(uuid.clone(), history.clone())], version)
+            .unwrap();
+        assert_eq!(store.schema_version().unwrap(), Some(version));
+        assert_eq!(store.get(&uuid).unwrap(), Some(conversation));
+        assert_eq!(store.history(&uuid).unwrap().len(), history.len());
+
+        store.delete(&uuid).unwrap();
+        assert!(store.get(&uuid).unwrap().is_none());
+        assert!(store.history(&uuid).unwrap().is_empty());
+        assert!(store.stored_history_head(&uuid).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_memory_round_trip() {
+        round_trip(&mut MemoryStore::default());
+    }
+
+    #[test]
+    fn test_sqlite_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        round_trip(&mut SqliteStore::open(dir.path().join("conversations.sqlite")).unwrap());
+    }
+
+    #[test]
+    fn test_delete_removes_history() {
+        let mut store = MemoryStore::default();
+        let uuid = new_conversation(&mut store);
+        add(&mut store, &uuid, User::Zack, "hello");
+        store.delete(&uuid).unwrap();
+        assert!(store.get(&uuid).unwrap().is_none());
+        assert!(store.history(&uuid).unwrap().is_empty());
+    }
+}
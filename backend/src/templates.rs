@@ -1,6 +1,10 @@
+use std::sync::OnceLock;
+
+use anyhow::Context;
 use tera::Tera;
 
 use crate::{
+    conversation::User,
     model_server,
 };
 
@@ -15,6 +19,9 @@ pub struct MetadataPromptTemplateEntry {
 pub struct MessagePromptTemplateEntry {
     pub author: String,
     pub value: String,
+    /// The message this entry came from, None for notes that were added to the prompt
+    #[serde(default)]
+    pub id: Option<String>,
 }
 
 #[derive(Clone, PartialEq, Default, Debug, serde::Serialize, serde::Deserialize)]
@@ -30,8 +37,27 @@ pub struct InjectedFileTemplateData {
     pub filetext: String
 }
 
+#[derive(Clone, PartialEq, Default, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ToolResultTemplateData {
+    pub command: String,
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: i32,
+    pub duration: String,
+    /// Limits that were hit, one line each
+    pub notes: Vec<String>,
+}
+
+pub const TEMPLATE_DIR: &str = "./templates";
+
+/// Tests run from backend/ but the templates are next to it
+#[cfg(test)]
+pub fn use_repo_templates() {
+    std::env::set_current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/..")).unwrap();
+}
+
 pub fn get_tera() -> anyhow::Result<Tera> {
-    Ok(Tera::new("./templates/*.template")?)
+    Ok(Tera::new(&format!("{TEMPLATE_DIR}/*.template"))?)
 }
 pub fn start_inference(config: &model_server::InferenceServerArgs) -> anyhow::Result<String> {
     let tera = get_tera()?;
@@ -41,6 +67,36 @@ pub fn start_inference(config: &model_server::InferenceServerArgs) -> anyhow::Re
     )?;
     Ok(result)
 }
+/// The `[[section]]` lines of a template
+pub fn section_markers(template: &str) -> Vec<String> {
+    let mut markers: Vec<String> = Vec::new();
+    for line in template.lines().map(str::trim) {
+        let is_marker = line.len() > 4 && line.starts_with("[[") && line.ends_with("]]");
+        if is_marker && !markers.iter().any(|m| m == line) {
+            markers.push(line.to_string());
+        }
+    }
+    markers
+}
+
+/// Where the model has gone past its turn: the line a message of somebody else would
+/// start with, or one of the sections of prompt.template (the response is the last one).
+/// The author lines go first, OpenAI only takes the first few
+pub fn default_stop_sequences() -> anyhow::Result<Vec<String>> {
+    static STOPS: OnceLock<Vec<String>> = OnceLock::new();
+    if let Some(stops) = STOPS.get() {
+        return Ok(stops.clone());
+    }
+    let template = std::fs::read_to_string(format!("{TEMPLATE_DIR}/prompt.template"))
+        .context("failed to read prompt.template for the stop sequences")?;
+    let authors = [User::Zack, User::Docker, User::System, User::Jake];
+    let mut stops: Vec<String> = authors
+        .iter()
+        .map(|user| format!("\n{user}:"))
+        .collect();
+    stops.extend(section_markers(&template));
+    Ok(STOPS.get_or_init(|| stops).clone())
+}
 pub fn prompt(details: &PromptTemplateData) -> anyhow::Result<String> {
     let tera = get_tera()?;
     let result = tera.render("prompt.template", &tera::Context::from_serialize(details)?)?;
@@ -52,3 +108,35 @@ pub fn injested_file(details: &InjectedFileTemplateData) -> anyhow::Result<Strin
     let result = tera.render("injested_file.template", &tera::Context::from_serialize(details)?)?;
     Ok(result)
 }
+
+pub fn summary(details: &PromptTemplateData) -> anyhow::Result<String> {
+    let tera = get_tera()?;
+    let result = tera.render("get_summary.template", &tera::Context::from_serialize(details)?)?;
+    Ok(result)
+}
+
+pub fn tool_result(details: &ToolResultTemplateData) -> anyhow::Result<String> {
+    let tera = get_tera()?;
+    let result = tera.render("tool_result.template", &tera::Context::from_serialize(details)?)?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_section_markers() {
+        let template = "[[meta]]\n{{meta}}\n[[history]]\n{{msgs}} [[not]] a marker\n[[response]]\n[[meta]]\n[[]]\n";
+        assert_eq!(section_markers(template), vec!["[[meta]]", "[[history]]", "[[response]]"]);
+    }
+
+    #[test]
+    fn test_default_stop_sequences() {
+        use_repo_templates();
+        let stops = default_stop_sequences().unwrap();
+        assert_eq!(&stops[..2], ["\nZack:", "\nDocker:"]);
+        assert!(stops.contains(&"\nMe:".to_string()));
+        assert!(stops.contains(&"[[history]]".to_string()));
+    }
+}
@@ -1,15 +1,230 @@
-use tokenizers::tokenizer::{Result, Tokenizer};
+use std::sync::{Mutex, OnceLock};
 
-const PATH_TO_TOKENIZER: &'static str = "/home/zack/personal/jake/core/mistral/tokenizer.json";
+use anyhow::anyhow;
+use tokenizers::tokenizer::Tokenizer;
+
+use crate::templates::{MessagePromptTemplateEntry, PromptTemplateData};
+
+/// `sequence_len` in core/mistralif.yml
+pub const DEFAULT_TOKEN_BUDGET: usize = 4096;
+
+/// Where `--tokenizer` is taken from when it isn't passed
+pub const TOKENIZER_ENV: &str = "JAKE_TOKENIZER";
+
+#[derive(clap::Args, Clone, Debug, Default, PartialEq)]
+pub struct TokenizerConfig {
+    /// The tokenizer.json of the model (e.g. core/mistral/tokenizer.json), defaults to
+    /// JAKE_TOKENIZER. Without one prompts aren't cut down to the token budget
+    #[arg(long)]
+    pub tokenizer: Option<String>,
+}
+
+static CONFIG: Mutex<Option<TokenizerConfig>> = Mutex::new(None);
+
+/// Pick the tokenizer, before anything is tokenized
+pub fn configure(config: TokenizerConfig) {
+    *CONFIG.lock().unwrap() = Some(config);
+}
+
+pub fn tokenizer_path() -> Option<String> {
+    let configured = CONFIG
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|c| c.tokenizer.clone());
+    configured.or_else(|| std::env::var(TOKENIZER_ENV).ok())
+}
+
+/// How many tokens a prompt can have and leave room for the response. None without a
+/// tokenizer, the prompt goes as it is then
+pub fn prompt_budget(max_new_tokens: usize) -> Option<usize> {
+    tokenizer_path().map(|_| DEFAULT_TOKEN_BUDGET.saturating_sub(max_new_tokens))
+}
+
+pub trait TokenCounter {
+    fn count(&self, text: &str) -> anyhow::Result<usize>;
+}
+
+impl TokenCounter for Tokenizer {
+    fn count(&self, text: &str) -> anyhow::Result<usize> {
+        let encoding = self
+            .encode(text, true)
+            .map_err(|e| anyhow!("failed to tokenize {e}"))?;
+        Ok(encoding.len())
+    }
+}
+
+static MISTRAL: OnceLock<Tokenizer> = OnceLock::new();
+
+/// The tokenizer the model is trained with, loaded once
+pub fn mistral() -> anyhow::Result<&'static Tokenizer> {
+    if let Some(tokenizer) = MISTRAL.get() {
+        return Ok(tokenizer);
+    }
+    let path = tokenizer_path().ok_or(anyhow!(
+        "no tokenizer, pass --tokenizer or set {TOKENIZER_ENV}"
+    ))?;
+    let tokenizer = Tokenizer::from_file(&path)
+        .map_err(|e| anyhow!("failed to load tokenizer from {path} {e}"))?;
+    Ok(MISTRAL.get_or_init(|| tokenizer))
+}
+
+/// A prompt that had to lose some of its history to fit the budget
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Truncation {
+    /// The message the prompt was built for
+    pub message_id: String,
+    /// How many of the oldest history messages were left out
+    pub dropped: usize,
+    pub tokens_before: usize,
+    pub tokens_after: usize,
+    pub budget: usize,
+}
+
+impl Truncation {
+    /// False when even dropping everything we're allowed to drop didn't get under the budget
+    pub fn fits(&self) -> bool {
+        self.tokens_after <= self.budget
+    }
+}
+
+impl std::fmt::Display for Truncation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: dropped {} messages, {} -> {} tokens (budget {})",
+            self.message_id, self.dropped, self.tokens_before, self.tokens_after, self.budget
+        )?;
+        if !self.fits() {
+            write!(f, " STILL OVER BUDGET")?;
+        }
+        Ok(())
+    }
+}
+
+fn omitted_entry(dropped: usize, summary: &str) -> MessagePromptTemplateEntry {
+    let mut value = format!("\t[{dropped} earlier messages omitted]");
+    if !summary.trim().is_empty() {
+        value += &format!("\n\tSummary so far: {}", summary.trim().replace("\n", "\n\t"));
+    }
+    MessagePromptTemplateEntry {
+        author: "System".to_string(),
+        value,
+        id: None,
+    }
+}
+
+/// Renders `data` and, if it comes out over `budget` tokens, replaces the oldest history
+/// with a note saying how much was left out (and the conversation summary if there is one).
+///
+/// The meta block is never touched and nothing at or after `keep_from` in `data.msgs` is
+/// dropped, that's where the current task starts.
+pub fn fit_prompt(
+    data: &mut PromptTemplateData,
+    keep_from: usize,
+    budget: usize,
+    summary: &str,
+    counter: &dyn TokenCounter,
+    render: impl Fn(&PromptTemplateData) -> anyhow::Result<String>,
+    message_id: &str,
+) -> anyhow::Result<(String, Option<Truncation>)> {
+    let full = render(data)?;
+    let tokens_before = counter.count(&full)?;
+    let keep_from = keep_from.min(data.msgs.len());
+    if tokens_before <= budget || keep_from == 0 {
+        return Ok((full, None));
+    }
+
+    let history = data.msgs.clone();
+    let attempt = |dropped: usize| -> anyhow::Result<(PromptTemplateData, String, usize)> {
+        let mut candidate = data.clone();
+        candidate.msgs = std::iter::once(omitted_entry(dropped, summary))
+            .chain(history[dropped..].iter().cloned())
+            .collect();
+        let text = render(&candidate)?;
+        let tokens = counter.count(&text)?;
+        Ok((candidate, text, tokens))
+    };
+
+    // fewer dropped messages always means more tokens, so find the smallest drop that fits
+    let (mut low, mut high) = (1, keep_from);
+    let mut best = attempt(high)?;
+    while low < high {
+        let mid = (low + high) / 2;
+        let candidate = attempt(mid)?;
+        if candidate.2 <= budget {
+            high = mid;
+            best = candidate;
+        } else {
+            low = mid + 1;
+        }
+    }
+    let (candidate, text, tokens_after) = best;
+    *data = candidate;
+    Ok((
+        text,
+        Some(Truncation {
+            message_id: message_id.to_string(),
+            dropped: high,
+            tokens_before,
+            tokens_after,
+            budget,
+        }),
+    ))
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
     #[test]
+    #[ignore = "needs the mistral tokenizer in JAKE_TOKENIZER"]
     fn test_tokenize() {
-        let tokenizer = Tokenizer::from_file(PATH_TO_TOKENIZER).unwrap();
+        let tokenizer = mistral().unwrap();
 
         let encoding = tokenizer.encode("Hey there!", true).unwrap();
         assert_eq!(encoding.get_tokens().len(), 4);
     }
+
+    struct Words;
+    impl TokenCounter for Words {
+        fn count(&self, text: &str) -> anyhow::Result<usize> {
+            Ok(text.split_whitespace().count())
+        }
+    }
+
+    fn render(data: &PromptTemplateData) -> anyhow::Result<String> {
+        let msgs: Vec<String> = data.msgs.iter().map(|m| m.value.clone()).collect();
+        Ok(format!("{} {}", msgs.join(" "), data.response))
+    }
+
+    #[test]
+    fn test_fit_prompt() {
+        let mut data = PromptTemplateData::default();
+        for i in 0..10 {
+            data.msgs.push(MessagePromptTemplateEntry {
+                author: "Zack".into(),
+                value: format!("m{i} a b c"),
+                id: Some(format!("m{i}")),
+            });
+        }
+        data.response = "done".into();
+        // 41 words, the omitted note is 4
+        let (_, truncation) =
+            fit_prompt(&mut data.clone(), 10, 100, "", &Words, render, "x").unwrap();
+        assert!(truncation.is_none());
+
+        let (text, truncation) = fit_prompt(&mut data, 8, 25, "", &Words, render, "x").unwrap();
+        let truncation = truncation.unwrap();
+        assert_eq!(truncation.dropped, 5);
+        assert!(truncation.fits());
+        assert!(text.starts_with("\t[5 earlier messages omitted] m5"));
+        assert_eq!(data.msgs.len(), 6);
+
+        // can't drop into the task context even if it doesn't fit
+        let mut data = data.clone();
+        let (_, truncation) = fit_prompt(&mut data, 2, 5, "", &Words, render, "x").unwrap();
+        let truncation = truncation.unwrap();
+        assert_eq!(truncation.dropped, 2);
+        assert!(!truncation.fits());
+    }
 }
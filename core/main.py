@@ -1,7 +1,13 @@
 from pathlib import Path
 from typing import Union
+import asyncio
+import json
+import queue
+import time
+import uuid
 import uvicorn
 from fastapi import FastAPI, Request, HTTPException
+from fastapi.responses import StreamingResponse
 
 import fire
 import transformers
@@ -31,6 +37,8 @@ status = STATUS_READY
 statusbody = {}
 generated_text = ""
 should_stop =  False
+# pieces of text for /infer and /infer_stream followed by the result
+token_queue = None
 # end statuslock protected
 generate_thread = None
 
@@ -65,24 +73,87 @@ def read_get_generated():
         generated_text = ""
         return {"text":generated_text}
 
+# Generates to the end and returns what the generation came to, see InferResp in
+# backend/src/model_server.rs
 @app.post("/infer")
 async def read_infer(req : Request):
     print("infer")
-    global status, statuslock,generate_thread
+    global status, statuslock,generate_thread, token_queue
     with statuslock:
         if not (status == STATUS_READY or status == STATUS_DONE_GENERATING):
             raise HTTPException(status_code=400, detail="Status was not Ready")
     data = await req.json()
     prompt : str = data["prompt"]
     config : dict = data["config"]
+    results = queue.Queue()
     with statuslock:
         print("Generating started command.")
         status = STATUS_GENERATING
+        token_queue = results
 
-    generation_kwargs = dict(config=Path("./mistralif.yml"),infer_cfg=config, instruction=prompt)
-    generate_thread = Thread(target=run, kwargs=generation_kwargs)
+    generation_kwargs = dict(config=Path("./mistralif.yml"),infer_cfg=config, instruction=prompt, job_id=uuid.uuid4().hex)
+    generate_thread = Thread(target=run_streaming, kwargs=generation_kwargs)
     generate_thread.start()
-    return {}
+    while True:
+        # only the last thing in the queue matters, the text is in the result too
+        result = await asyncio.to_thread(results.get)
+        if isinstance(result, Exception):
+            raise HTTPException(status_code=500, detail=str(result))
+        if isinstance(result, dict):
+            return result
+
+# Same as /infer but the response is server-sent events: a `data: {"text": ...}` for
+# every piece of text as it is generated, then `event: done` with the result or
+# `event: error`
+@app.post("/infer_stream")
+async def read_infer_stream(req : Request):
+    print("infer_stream")
+    global status, statuslock, generate_thread, token_queue
+    with statuslock:
+        if not (status == STATUS_READY or status == STATUS_DONE_GENERATING):
+            raise HTTPException(status_code=400, detail="Status was not Ready")
+    data = await req.json()
+    prompt : str = data["prompt"]
+    config : dict = data["config"]
+    pieces = queue.Queue()
+    with statuslock:
+        status = STATUS_GENERATING
+        token_queue = pieces
+
+    generation_kwargs = dict(config=Path("./mistralif.yml"),infer_cfg=config, instruction=prompt, job_id=uuid.uuid4().hex)
+    generate_thread = Thread(target=run_streaming, kwargs=generation_kwargs)
+    generate_thread.start()
+
+    def events():
+        while True:
+            piece = pieces.get()
+            if isinstance(piece, Exception):
+                yield f"event: error\ndata: {json.dumps({'error': str(piece)})}\n\n"
+                return
+            if isinstance(piece, dict):
+                yield f"event: done\ndata: {json.dumps(piece)}\n\n"
+                return
+            yield f"data: {json.dumps({'text': piece})}\n\n"
+
+    return StreamingResponse(events(), media_type="text/event-stream")
+
+# Runs the generation with token_queue getting each piece of text, then the result
+# dict or the exception
+def run_streaming(**kwargs):
+    global status, statuslock, token_queue
+    try:
+        result = run(**kwargs)
+    except Exception as e:
+        with statuslock:
+            status = STATUS_READY
+            if token_queue is not None:
+                token_queue.put(e)
+                token_queue = None
+        raise
+    with statuslock:
+        if token_queue is not None:
+            token_queue.put(result)
+            token_queue = None
 
 @app.post("/stop")
 def read_cancel():
@@ -104,9 +175,11 @@ def infer(
     cli_args: TrainerCliArgs,
     infer_cfg: dict,
     instruction: str,
-)->str:
+    job_id: str,
+)->dict:
     global model, tokenizer, streamer, statuslock, statusbody, generated_text, status, should_stop
     print("starting inference")
+    started = time.monotonic()
     if model is None or tokenizer is None:
         print("shits wack")
         raise Exception
@@ -117,7 +190,8 @@ def infer(
         generated_text = ""
         should_stop = False
         statusbody = {"text":generated_text}
-        streamer = TextIteratorStreamer(tokenizer)
+        # the prompt isn't part of the completion
+        streamer = TextIteratorStreamer(tokenizer, skip_prompt=True)
 
     print("=" * 80)
     prompt = instruction.strip()
@@ -152,7 +226,9 @@ def infer(
 
         # reset the streamer
         stopping_criteria = UserRequestedStopCriteria()
-        generation = model.generate(inputs=batch["input_ids"].to(cfg.device), streamer=streamer, stopping_criteria=[stopping_criteria], generation_config=generation_config)
+        stop_sequences = [s for s in infer_cfg.get("stop_sequences", []) if s]
+        stop_sequence_criteria = StopSequenceCriteria(stop_sequences, batch["input_ids"].shape[1])
+        generation = model.generate(inputs=batch["input_ids"].to(cfg.device), streamer=streamer, stopping_criteria=[stopping_criteria, stop_sequence_criteria], generation_config=generation_config)
 
     streamerthread.join()
 
@@ -164,17 +240,36 @@ def infer(
         #     generated_text += new_text
         #     print(new_text)
     print("Done infering.")
+    sequences = generation.sequences if hasattr(generation, "sequences") else generation
+    prompt_tokens = batch["input_ids"].shape[1]
+    new_tokens = sequences[0][prompt_tokens:].tolist()
+    # the stop sequence and anything after it isn't part of the completion
+    completion = generated_text
+    cuts = [completion.find(s) for s in stop_sequences if s in completion]
+    if cuts:
+        completion = completion[:min(cuts)]
+    if stopping_criteria.stopped:
+        finish_reason = "stopped"
+    elif stop_sequence_criteria.matched or cuts:
+        finish_reason = "stop_sequence"
+    elif len(new_tokens) >= infer_cfg["max_new_tokens"] and new_tokens[-1] != tokenizer.eos_token_id:
+        finish_reason = "max_new_tokens"
+    else:
+        finish_reason = "eos"
     with statuslock:
         status = STATUS_DONE_GENERATING
         statusbody = {"text": generated_text}
-    # print("=" * 40)
-    # print(generated_text)
-    # result = tokenizer.decode(token_ids=generated["sequences"].cpu().tolist()[0],skip_special_tokens=True)
-    # print(result)
-    return generation
+        return {
+            "job_id": job_id,
+            "completion": completion,
+            "prompt_tokens": prompt_tokens,
+            "completion_tokens": len(new_tokens),
+            "finish_reason": finish_reason,
+            "duration_ms": int((time.monotonic() - started) * 1000),
+        }
 
 def sync_text():
-    global statuslock, generated_text, streamer, statusbody
+    global statuslock, generated_text, streamer, statusbody, token_queue
     if streamer is None:
         print("streamer is none")
         raise Exception
@@ -183,6 +278,8 @@ def sync_text():
             print(new_text)
             generated_text += new_text
             statusbody = {"text": generated_text}
+            if token_queue is not None:
+                token_queue.put(new_text)
 
 def load(config: Path ):
     global model, tokenizer
@@ -217,7 +314,7 @@ def load(config: Path ):
 
     print("finished model loading")
 
-def run(config: Path, infer_cfg: dict, instruction: str)->str:
+def run(config: Path, infer_cfg: dict, instruction: str, job_id: str)->dict:
     parsed_cfg = load_cfg(config)
     parsed_cfg.sample_packing = False
     parser = transformers.HfArgumentParser((TrainerCliArgs))
@@ -225,14 +322,37 @@ def run(config: Path, infer_cfg: dict, instruction: str)->str:
         return_remaining_strings=True
     )
     parsed_cli_args.inference = True
-    return infer(cfg=parsed_cfg, cli_args=parsed_cli_args, infer_cfg=infer_cfg, instruction=instruction)
+    return infer(cfg=parsed_cfg, cli_args=parsed_cli_args, infer_cfg=infer_cfg, instruction=instruction, job_id=job_id)
 
 class UserRequestedStopCriteria(transformers.StoppingCriteria):
+    # whether it was what ended the generation
+    stopped = False
+
     def __call__(self, input_ids: torch.LongTensor, scores: torch.FloatTensor, **kwargs) -> bool:
         global statuslock, should_stop
         with statuslock:
+            if should_stop:
+                self.stopped = True
             return should_stop
 
+# Ends the generation once the text since the prompt has one of the stop sequences in it
+class StopSequenceCriteria(transformers.StoppingCriteria):
+    def __init__(self, stop_sequences: list, prompt_tokens: int):
+        self.stop_sequences = stop_sequences
+        self.prompt_tokens = prompt_tokens
+        self.matched = False
+
+    def __call__(self, input_ids: torch.LongTensor, scores: torch.FloatTensor, **kwargs) -> bool:
+        global tokenizer
+        if not self.stop_sequences:
+            return False
+        # a stop sequence is a few tokens at most, only the end needs decoding
+        new_tokens = input_ids[0][self.prompt_tokens:]
+        tail = tokenizer.decode(new_tokens[-32:], skip_special_tokens=True)
+        if any(s in tail for s in self.stop_sequences):
+            self.matched = True
+        return self.matched
+
 
 if __name__ == "__main__":
     load(config=Path("./mistralif.yml"))